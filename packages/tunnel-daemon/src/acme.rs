@@ -0,0 +1,800 @@
+//! ACME Module
+//!
+//! Gets publicly trusted certificates for domains we own from an ACME CA
+//! (RFC 8555). Let's Encrypt is the default, but any directory URL works, so
+//! a local Pebble instance can stand in for testing. HTTP-01 challenges are
+//! answered by the tunnel's own HTTP listener and DNS-01 challenges by the
+//! built-in DNS server. The account key and certificates are kept on disk,
+//! and certificates are renewed well before they expire.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::client::HttpConnector;
+use hyper::header;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, KeyPair, PKCS_ECDSA_P256_SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tracing::{debug, info, warn};
+
+use crate::cert::{self, IssuedCert};
+use crate::sni::SniResolver;
+
+/// Let's Encrypt production directory
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Path prefix of HTTP-01 validation requests
+pub const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Label of DNS-01 validation records
+const DNS_CHALLENGE_LABEL: &str = "_acme-challenge";
+
+/// ACME certificates are renewed when they have less than this left
+pub const ACME_RENEW_BEFORE: time::Duration = time::Duration::days(30);
+
+/// How often the certificate is checked for renewal (and failed orders retried)
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Delay between polls of pending authorizations and orders
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Polls before giving up on an authorization or order
+const MAX_POLLS: usize = 30;
+
+const JOSE_JSON: &str = "application/jose+json";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+/// How domain ownership is proven to the CA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeType {
+    /// Token served over HTTP on the tunnel's listener
+    Http01,
+    /// TXT record served by the built-in DNS server (also covers `*.domain`)
+    Dns01,
+}
+
+impl ChallengeType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::Dns01 => "dns-01",
+        }
+    }
+}
+
+impl FromStr for ChallengeType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "http-01" | "http" => Ok(ChallengeType::Http01),
+            "dns-01" | "dns" => Ok(ChallengeType::Dns01),
+            _ => Err(format!("Invalid ACME challenge '{}'. Expected http-01 or dns-01", s)),
+        }
+    }
+}
+
+/// ACME settings
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    /// Directory URL of the CA
+    pub directory_url: String,
+
+    /// Contact email for the account
+    pub contact: Option<String>,
+
+    /// Challenge used to prove control of the domain
+    pub challenge: ChallengeType,
+
+    /// Extra CA certificates (PEM) trusted for the ACME server, e.g. Pebble's
+    pub ca_bundle: Option<PathBuf>,
+}
+
+/// Challenge responses currently published, shared with the HTTP listener and DNS server
+#[derive(Debug, Default)]
+pub struct ChallengeResponses {
+    /// Key authorizations by HTTP-01 token
+    http: RwLock<HashMap<String, String>>,
+
+    /// TXT values by DNS-01 record name (lowercase, no trailing dot)
+    dns: RwLock<HashMap<String, Vec<String>>>,
+}
+
+impl ChallengeResponses {
+    /// Key authorization to serve for an HTTP-01 token
+    pub fn http_response(&self, token: &str) -> Option<String> {
+        self.http.read().unwrap().get(token).cloned()
+    }
+
+    /// TXT values to serve for a DNS-01 record name
+    pub fn dns_txt(&self, name: &str) -> Vec<String> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.dns.read().unwrap().get(&name).cloned().unwrap_or_default()
+    }
+
+    fn add_http(&self, token: &str, key_authorization: String) {
+        self.http.write().unwrap().insert(token.to_string(), key_authorization);
+    }
+
+    fn remove_http(&self, token: &str) {
+        self.http.write().unwrap().remove(token);
+    }
+
+    fn add_dns(&self, name: &str, value: String) {
+        self.dns.write().unwrap().entry(name.to_lowercase()).or_default().push(value);
+    }
+
+    fn remove_dns(&self, name: &str, value: &str) {
+        let mut dns = self.dns.write().unwrap();
+        if let Some(values) = dns.get_mut(&name.to_lowercase()) {
+            values.retain(|v| v != value);
+            if values.is_empty() {
+                dns.remove(&name.to_lowercase());
+            }
+        }
+    }
+}
+
+/// Whether an ACME certificate expiring at `not_after` should be renewed
+pub fn is_due(not_after: OffsetDateTime) -> bool {
+    not_after - OffsetDateTime::now_utc() < ACME_RENEW_BEFORE
+}
+
+fn b64(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+/// ES256 account key and its JWK
+struct AccountKey {
+    pair: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+}
+
+impl AccountKey {
+    /// Load the account key from `path` (PKCS#8 PEM), creating it on first use
+    fn load_or_create(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let pkcs8 = if path.exists() {
+            KeyPair::from_pem(&fs::read_to_string(path)?)?.serialize_der()
+        } else {
+            info!("Creating ACME account key {}", path.display());
+            let key = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?;
+            if let Some(dir) = path.parent() {
+                cert::create_private_dir(dir)?;
+            }
+            cert::write_private(path, key.serialize_pem().as_bytes())?;
+            key.serialize_der()
+        };
+
+        let rng = SystemRandom::new();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|_| format!("ACME account key {} is not a P-256 key", path.display()))?;
+
+        // Uncompressed point: 0x04 || x || y
+        let point = pair.public_key().as_ref();
+        let jwk = json!({ "crv": "P-256", "kty": "EC", "x": b64(&point[1..33]), "y": b64(&point[33..65]) });
+        Ok(AccountKey { pair, rng, jwk })
+    }
+
+    /// RFC 7638 thumbprint, used in key authorizations
+    fn thumbprint(&self) -> String {
+        jwk_thumbprint(&self.jwk)
+    }
+
+    /// Flattened JWS of `payload` (empty for POST-as-GET)
+    fn sign(&self, protected: &Value, payload: &str) -> Result<Value, Box<dyn std::error::Error>> {
+        let protected = b64(protected.to_string().as_bytes());
+        let payload = b64(payload.as_bytes());
+        let signature = self
+            .pair
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| "Failed to sign ACME request")?;
+        Ok(json!({ "protected": protected, "payload": payload, "signature": b64(signature.as_ref()) }))
+    }
+}
+
+/// Thumbprint of an EC JWK: required members in lexicographic order, no whitespace
+fn jwk_thumbprint(jwk: &Value) -> String {
+    let member = |name: &str| jwk[name].as_str().unwrap_or_default().to_string();
+    let canonical = format!(
+        r#"{{"crv":"{}","kty":"{}","x":"{}","y":"{}"}}"#,
+        member("crv"),
+        member("kty"),
+        member("x"),
+        member("y")
+    );
+    b64(&Sha256::digest(canonical.as_bytes()))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    identifier: Identifier,
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Problem>,
+}
+
+/// RFC 7807 problem document returned by ACME servers
+#[derive(Debug, Default, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.detail, self.kind)
+    }
+}
+
+/// Successful ACME response
+struct AcmeResponse {
+    location: Option<String>,
+    body: hyper::body::Bytes,
+}
+
+impl AcmeResponse {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// ACME client for one directory and account
+pub struct AcmeClient {
+    config: AcmeConfig,
+    http: Client<HttpsConnector<HttpConnector>>,
+    key: AccountKey,
+
+    /// Directory for the account key and certificates
+    dir: PathBuf,
+
+    /// Published challenge responses
+    responses: Arc<ChallengeResponses>,
+}
+
+impl AcmeClient {
+    pub fn new(config: AcmeConfig, dir: PathBuf, responses: Arc<ChallengeResponses>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut tls = native_tls::TlsConnector::builder();
+        if let Some(bundle) = &config.ca_bundle {
+            let pem = fs::read(bundle).map_err(|e| format!("Cannot read {}: {}", bundle.display(), e))?;
+            for der in rustls_pemfile::certs(&mut pem.as_slice())? {
+                tls.add_root_certificate(native_tls::Certificate::from_der(&der)?);
+            }
+        }
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        let http = Client::builder().build(HttpsConnector::from((connector, tls.build()?.into())));
+
+        // One account per directory, so switching between Pebble and production is safe
+        let directory_id: String = Sha256::digest(config.directory_url.as_bytes())[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let key = AccountKey::load_or_create(&dir.join(format!("account-{}.key", directory_id)))?;
+
+        Ok(AcmeClient { config, http, key, dir, responses })
+    }
+
+    fn paths(&self, domain: &str) -> (PathBuf, PathBuf) {
+        let stem = domain.replace('.', "_");
+        (self.dir.join(format!("{}.pem", stem)), self.dir.join(format!("{}.key", stem)))
+    }
+
+    /// Stored certificate for `domain`, if there is a valid one
+    pub fn load(&self, domain: &str) -> Option<IssuedCert> {
+        let (cert_path, key_path) = self.paths(domain);
+        if !cert_path.exists() {
+            return None;
+        }
+        match cert::load_pem_files(&cert_path, &key_path, domain) {
+            Ok(cert) => Some(cert),
+            Err(e) => {
+                warn!("Ignoring stored ACME certificate for {}: {}", domain, e);
+                None
+            }
+        }
+    }
+
+    /// Order a certificate for `domain` (and `*.domain` with DNS-01) and store it
+    pub async fn order(&self, domain: &str) -> Result<IssuedCert, Box<dyn std::error::Error>> {
+        info!("Requesting certificate for {} from {}", domain, self.config.directory_url);
+        let mut session = Session::open(self).await?;
+
+        let mut names = vec![domain.to_string()];
+        if self.config.challenge == ChallengeType::Dns01 {
+            names.push(format!("*.{}", domain));
+        }
+        let identifiers: Vec<Value> = names.iter().map(|name| json!({ "type": "dns", "value": name })).collect();
+        let new_order = session.directory.new_order.clone();
+        let response = session.post(&new_order, Some(&json!({ "identifiers": identifiers }))).await?;
+        let order_url = response.location.clone().ok_or("ACME server did not return an order URL")?;
+        let order: Order = response.json()?;
+
+        for url in &order.authorizations {
+            self.authorize(&mut session, url).await?;
+        }
+
+        // Finalize with a CSR for a fresh key
+        let mut params = CertificateParams::new(names);
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, domain);
+        let request = Certificate::from_params(params)?;
+        let csr = request.serialize_request_der()?;
+        let mut order: Order = session.post(&order.finalize, Some(&json!({ "csr": b64(&csr) }))).await?.json()?;
+
+        let mut polls = 0;
+        while order.status != "valid" {
+            if order.status == "invalid" || polls == MAX_POLLS {
+                let reason = order.error.map(|e| e.to_string()).unwrap_or_else(|| format!("status {}", order.status));
+                return Err(format!("ACME order for {} failed: {}", domain, reason).into());
+            }
+            polls += 1;
+            tokio::time::sleep(POLL_INTERVAL).await;
+            order = session.post(&order_url, None).await?.json()?;
+        }
+
+        let certificate_url = order.certificate.ok_or("ACME order is valid but has no certificate URL")?;
+        let chain = session.post(&certificate_url, None).await?.body;
+
+        let (cert_path, key_path) = self.paths(domain);
+        cert::create_private_dir(&self.dir)?;
+        cert::write_private(&key_path, request.serialize_private_key_pem().as_bytes())?;
+        fs::write(&cert_path, &chain)?;
+
+        let cert = cert::load_pem_files(&cert_path, &key_path, domain)?;
+        info!("Obtained certificate for {} (valid until {})", domain, cert.not_after);
+        Ok(cert)
+    }
+
+    /// Complete one authorization with the configured challenge
+    async fn authorize(&self, session: &mut Session<'_>, url: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let authz: Authorization = session.post(url, None).await?.json()?;
+        if authz.status == "valid" {
+            return Ok(());
+        }
+
+        let kind = self.config.challenge.as_str();
+        let domain = authz.identifier.value.clone();
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.kind == kind)
+            .ok_or_else(|| format!("ACME server offers no {} challenge for {}", kind, domain))?;
+        let key_authorization = format!("{}.{}", challenge.token, self.key.thumbprint());
+
+        let record_name = format!("{}.{}", DNS_CHALLENGE_LABEL, domain);
+        let record_value = b64(&Sha256::digest(key_authorization.as_bytes()));
+        match self.config.challenge {
+            ChallengeType::Http01 => self.responses.add_http(&challenge.token, key_authorization),
+            ChallengeType::Dns01 => self.responses.add_dns(&record_name, record_value.clone()),
+        }
+        debug!("Published {} response for {}", kind, domain);
+
+        let result = self.validate(session, url, &challenge.url, &domain).await;
+
+        match self.config.challenge {
+            ChallengeType::Http01 => self.responses.remove_http(&challenge.token),
+            ChallengeType::Dns01 => self.responses.remove_dns(&record_name, &record_value),
+        }
+        result
+    }
+
+    /// Tell the CA the challenge is ready and wait for the authorization to settle
+    async fn validate(
+        &self,
+        session: &mut Session<'_>,
+        authz_url: &str,
+        challenge_url: &str,
+        domain: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        session.post(challenge_url, Some(&json!({}))).await?;
+
+        let kind = self.config.challenge.as_str();
+        for _ in 0..MAX_POLLS {
+            let authz: Authorization = session.post(authz_url, None).await?.json()?;
+            match authz.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => {
+                    let reason = authz
+                        .challenges
+                        .into_iter()
+                        .find(|c| c.kind == kind)
+                        .and_then(|c| c.error)
+                        .map(|e| e.to_string())
+                        .unwrap_or_else(|| format!("status {}", status));
+                    return Err(format!("ACME {} validation for {} failed: {}", kind, domain, reason).into());
+                }
+            }
+        }
+        Err(format!("ACME {} validation for {} timed out", kind, domain).into())
+    }
+
+    /// Keep `resolver`'s certificate for `domain` current, ordering a new one
+    /// when none is held yet (`not_after` is `None`) or it is due for renewal
+    pub fn start_renewal_task(
+        client: Arc<AcmeClient>,
+        resolver: Arc<SniResolver>,
+        domain: String,
+        mut not_after: Option<OffsetDateTime>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RENEWAL_CHECK_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                if not_after.is_some_and(|t| !is_due(t)) {
+                    continue;
+                }
+
+                let result = client.order(&domain).await.map_err(|e| e.to_string());
+                match result.and_then(|cert| {
+                    let expires = cert.not_after;
+                    resolver.set_primary(cert).map(|_| expires).map_err(|e| e.to_string())
+                }) {
+                    Ok(expires) => not_after = Some(expires),
+                    Err(e) => warn!("Could not get ACME certificate for {}: {} (retrying in an hour)", domain, e),
+                }
+            }
+        })
+    }
+}
+
+/// Account session: directory, current nonce and account URL
+struct Session<'a> {
+    client: &'a AcmeClient,
+    directory: Directory,
+    nonce: Option<String>,
+    kid: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    /// Fetch the directory and register (or look up) the account
+    async fn open(client: &'a AcmeClient) -> Result<Session<'a>, Box<dyn std::error::Error>> {
+        let response = client.http.get(client.config.directory_url.parse()?).await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let directory: Directory = serde_json::from_slice(&body)
+            .map_err(|e| format!("Invalid ACME directory at {}: {}", client.config.directory_url, e))?;
+
+        let mut session = Session { client, directory, nonce: None, kid: None };
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &client.config.contact {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account = session.directory.new_account.clone();
+        let response = session.post(&new_account, Some(&account)).await?;
+        session.kid = Some(response.location.ok_or("ACME server did not return an account URL")?);
+        debug!("Using ACME account {:?}", session.kid);
+
+        Ok(session)
+    }
+
+    async fn new_nonce(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let request = Request::builder().method(Method::HEAD).uri(&self.directory.new_nonce).body(Body::empty())?;
+        let response = self.client.http.request(request).await?;
+        replay_nonce(response.headers()).ok_or_else(|| "ACME server did not return a nonce".into())
+    }
+
+    /// Signed POST (`None` payload for POST-as-GET), retrying once on a stale nonce
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse, Box<dyn std::error::Error>> {
+        let payload = payload.map(Value::to_string).unwrap_or_default();
+
+        for attempt in 0..2 {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.client.key.jwk.clone(),
+            }
+            let body = self.client.key.sign(&protected, &payload)?;
+
+            let request = Request::post(url)
+                .header(header::CONTENT_TYPE, JOSE_JSON)
+                .body(Body::from(body.to_string()))?;
+            let response = self.client.http.request(request).await?;
+            let status = response.status();
+            self.nonce = replay_nonce(response.headers());
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|h| h.to_str().ok())
+                .map(String::from);
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+
+            if status.is_success() {
+                return Ok(AcmeResponse { location, body });
+            }
+            let problem: Problem = serde_json::from_slice(&body).unwrap_or_default();
+            if problem.kind == BAD_NONCE && attempt == 0 {
+                debug!("Retrying ACME request to {} with a fresh nonce", url);
+                continue;
+            }
+            return Err(format!("ACME request to {} failed with {}: {}", url, status, problem).into());
+        }
+        Err(format!("ACME server at {} keeps rejecting nonces", url).into())
+    }
+}
+
+fn replay_nonce(headers: &header::HeaderMap) -> Option<String> {
+    headers.get("replay-nonce").and_then(|h| h.to_str().ok()).map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_server::{self, Answer};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use rcgen::{BasicConstraints, CertificateSigningRequest, IsCa};
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+
+    fn decode(value: &Value) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(value.as_str().unwrap()).unwrap()
+    }
+
+    /// In-process ACME server: checks JWS signatures, validates challenges
+    /// against the client's published responses and signs CSRs with its own CA
+    struct MockCa {
+        base: String,
+        challenge: ChallengeType,
+        responses: Arc<ChallengeResponses>,
+        ca: Certificate,
+        jwk: Mutex<Option<Value>>,
+        nonces: Mutex<u64>,
+        identifiers: Mutex<Vec<String>>,
+        validated: Mutex<Vec<bool>>,
+        chain: Mutex<Option<String>>,
+    }
+
+    impl MockCa {
+        fn new(base: String, challenge: ChallengeType, responses: Arc<ChallengeResponses>) -> Self {
+            let mut params = CertificateParams::new(Vec::new());
+            params.distinguished_name.push(DnType::CommonName, "Mock ACME CA");
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            MockCa {
+                base,
+                challenge,
+                responses,
+                ca: Certificate::from_params(params).unwrap(),
+                jwk: Mutex::new(None),
+                nonces: Mutex::new(0),
+                identifiers: Mutex::new(Vec::new()),
+                validated: Mutex::new(Vec::new()),
+                chain: Mutex::new(None),
+            }
+        }
+
+        async fn handle(&self, req: hyper::Request<Body>) -> Response<Body> {
+            let (method, path) = (req.method().clone(), req.uri().path().to_string());
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let nonce = {
+                let mut nonces = self.nonces.lock().unwrap();
+                *nonces += 1;
+                format!("nonce-{}", *nonces)
+            };
+            let reply = |status: StatusCode, body: Value| {
+                Response::builder()
+                    .status(status)
+                    .header("replay-nonce", &nonce)
+                    .header(header::LOCATION, format!("{}{}/1", self.base, path))
+                    .body(Body::from(body.to_string()))
+                    .unwrap()
+            };
+
+            if method == Method::GET {
+                let url = |p: &str| format!("{}/{}", self.base, p);
+                return reply(StatusCode::OK, json!({ "newNonce": url("nonce"), "newAccount": url("account"), "newOrder": url("order") }));
+            }
+            if method == Method::HEAD {
+                return reply(StatusCode::OK, Value::Null);
+            }
+
+            // The first nonce handed out is treated as stale to exercise the retry
+            let (protected, payload) = self.verify(&path, &body);
+            if protected["nonce"] == "nonce-1" {
+                return reply(StatusCode::BAD_REQUEST, json!({ "type": BAD_NONCE, "detail": "stale nonce" }));
+            }
+
+            let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+            match segments.as_slice() {
+                ["account"] => reply(StatusCode::CREATED, json!({ "status": "valid" })),
+                ["order"] => {
+                    let names: Vec<String> =
+                        payload["identifiers"].as_array().unwrap().iter().map(|i| i["value"].as_str().unwrap().to_string()).collect();
+                    *self.validated.lock().unwrap() = vec![false; names.len()];
+                    *self.identifiers.lock().unwrap() = names;
+                    reply(StatusCode::CREATED, self.order())
+                }
+                ["order", _] => reply(StatusCode::OK, self.order()),
+                ["authz", i] => reply(StatusCode::OK, self.authorization(i.parse().unwrap())),
+                ["chall", i] => {
+                    let i: usize = i.parse().unwrap();
+                    let valid = self.check_challenge(i);
+                    self.validated.lock().unwrap()[i] = valid;
+                    reply(StatusCode::OK, json!({ "status": if valid { "valid" } else { "invalid" } }))
+                }
+                ["finalize"] => {
+                    let csr = CertificateSigningRequest::from_der(&decode(&payload["csr"])).unwrap();
+                    let chain = csr.serialize_pem_with_signer(&self.ca).unwrap() + &self.ca.serialize_pem().unwrap();
+                    *self.chain.lock().unwrap() = Some(chain);
+                    reply(StatusCode::OK, self.order())
+                }
+                ["cert"] => Response::builder()
+                    .header("replay-nonce", &nonce)
+                    .body(Body::from(self.chain.lock().unwrap().clone().unwrap()))
+                    .unwrap(),
+                _ => reply(StatusCode::NOT_FOUND, json!({ "type": "urn:ietf:params:acme:error:malformed" })),
+            }
+        }
+
+        /// Check the JWS and return its protected header and payload
+        fn verify(&self, path: &str, body: &[u8]) -> (Value, Value) {
+            let jws: Value = serde_json::from_slice(body).unwrap();
+            let protected: Value = serde_json::from_slice(&decode(&jws["protected"])).unwrap();
+            assert_eq!(protected["url"], format!("{}{}", self.base, path));
+
+            let jwk = match protected.get("jwk") {
+                Some(jwk) => {
+                    *self.jwk.lock().unwrap() = Some(jwk.clone());
+                    jwk.clone()
+                }
+                None => {
+                    assert_eq!(protected["kid"], format!("{}/account/1", self.base));
+                    self.jwk.lock().unwrap().clone().unwrap()
+                }
+            };
+            let public_key = [vec![4], decode(&jwk["x"]), decode(&jwk["y"])].concat();
+            let input = format!("{}.{}", jws["protected"].as_str().unwrap(), jws["payload"].as_str().unwrap());
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+                .verify(input.as_bytes(), &decode(&jws["signature"]))
+                .expect("invalid JWS signature");
+
+            let payload = decode(&jws["payload"]);
+            let payload = if payload.is_empty() { Value::Null } else { serde_json::from_slice(&payload).unwrap() };
+            (protected, payload)
+        }
+
+        fn order(&self) -> Value {
+            let count = self.identifiers.lock().unwrap().len();
+            let issued = self.chain.lock().unwrap().is_some();
+            let ready = self.validated.lock().unwrap().iter().all(|v| *v);
+            json!({
+                "status": if issued { "valid" } else if ready { "ready" } else { "pending" },
+                "authorizations": (0..count).map(|i| format!("{}/authz/{}", self.base, i)).collect::<Vec<_>>(),
+                "finalize": format!("{}/finalize", self.base),
+                "certificate": if issued { Some(format!("{}/cert", self.base)) } else { None },
+            })
+        }
+
+        fn authorization(&self, i: usize) -> Value {
+            let name = self.identifiers.lock().unwrap()[i].clone();
+            let valid = self.validated.lock().unwrap()[i];
+            let challenge = |kind: &str| json!({ "type": kind, "url": format!("{}/chall/{}", self.base, i), "token": format!("token{}", i) });
+            json!({
+                "identifier": { "type": "dns", "value": name.trim_start_matches("*.") },
+                "status": if valid { "valid" } else { "pending" },
+                "challenges": [challenge("http-01"), challenge("dns-01")],
+            })
+        }
+
+        /// Look for the expected response the way a real CA would
+        fn check_challenge(&self, i: usize) -> bool {
+            let jwk = self.jwk.lock().unwrap().clone().unwrap();
+            let key_authorization = format!("token{}.{}", i, jwk_thumbprint(&jwk));
+            let name = self.identifiers.lock().unwrap()[i].trim_start_matches("*.").to_string();
+
+            match self.challenge {
+                ChallengeType::Http01 => self.responses.http_response(&format!("token{}", i)) == Some(key_authorization),
+                ChallengeType::Dns01 => {
+                    let record = Name::from_ascii(format!("_acme-challenge.{}.", name)).unwrap();
+                    let expected = b64(&Sha256::digest(key_authorization.as_bytes()));
+                    match dns_server::challenge_answer(&self.responses, &record, RecordType::TXT) {
+                        Some(Answer::Records(records)) => records.iter().any(|r| match r.data() {
+                            Some(RData::TXT(txt)) => txt.to_string() == expected,
+                            _ => false,
+                        }),
+                        _ => false,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn order_from_mock(challenge: ChallengeType) -> (tempfile::TempDir, Arc<ChallengeResponses>, IssuedCert) {
+        let responses = Arc::new(ChallengeResponses::default());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let mock = Arc::new(MockCa::new(base.clone(), challenge, Arc::clone(&responses)));
+        let server = Server::from_tcp(listener).unwrap().serve(make_service_fn(move |_| {
+            let mock = Arc::clone(&mock);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let mock = Arc::clone(&mock);
+                    async move { Ok::<_, Infallible>(mock.handle(req).await) }
+                }))
+            }
+        }));
+        tokio::spawn(server);
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = AcmeConfig {
+            directory_url: format!("{}/dir", base),
+            contact: Some("ops@myapp.test".to_string()),
+            challenge,
+            ca_bundle: None,
+        };
+        let client = AcmeClient::new(config, dir.path().to_path_buf(), Arc::clone(&responses)).unwrap();
+        let cert = client.order("myapp.test").await.unwrap();
+
+        // Stored for the next start
+        assert!(client.load("myapp.test").is_some());
+        (dir, responses, cert)
+    }
+
+    #[tokio::test]
+    async fn test_order_with_http01_and_dns01() {
+        let (_dir, responses, cert) = order_from_mock(ChallengeType::Http01).await;
+        assert_eq!(cert.chain.len(), 2);
+        assert!(!is_due(cert.not_after));
+        assert!(responses.http.read().unwrap().is_empty());
+
+        // DNS-01 orders also cover the wildcard
+        let (dir, responses, _) = order_from_mock(ChallengeType::Dns01).await;
+        let (cert_path, key_path) = (dir.path().join("myapp_test.pem"), dir.path().join("myapp_test.key"));
+        assert!(cert::load_pem_files(&cert_path, &key_path, "tenant1.myapp.test").is_ok());
+        assert!(responses.dns.read().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+//! Access Control Module
+//!
+//! Basic auth, Bearer tokens and CIDR allow/deny lists checked before a
+//! request is routed. A verified TLS client certificate also authenticates
+//! the client. Secrets are stored as SHA-256 digests and compared in
+//! constant time. The IP lists always apply; contexts listed as bypassed
+//! (local browsers by default, and then only from loopback) skip the
+//! credential checks. Onion traffic always arrives as external access, so it
+//! is never treated as local.
+
+use base64::Engine;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+use crate::context::AccessContext;
+use crate::mtls::ClientCert;
+
+/// Realm announced in Basic auth challenges
+const AUTH_REALM: &str = "Beam";
+
+/// Outcome of an access check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// Let the request through
+    Allow,
+    /// Let the request through; it authenticated as the given identity
+    Authenticated(String),
+    /// Client address is not allowed (403)
+    Forbidden,
+    /// Credentials missing or wrong (401)
+    Unauthorized,
+}
+
+/// Contexts that skip access control, parsed from a comma list or `none`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BypassContexts(pub HashSet<AccessContext>);
+
+impl Default for BypassContexts {
+    fn default() -> Self {
+        BypassContexts(HashSet::from([AccessContext::LocalBrowser]))
+    }
+}
+
+impl FromStr for BypassContexts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("none") {
+            return Ok(BypassContexts(HashSet::new()));
+        }
+
+        s.split(',')
+            .map(|c| c.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(BypassContexts)
+    }
+}
+
+/// Access control settings
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    /// SHA-256 digests of accepted `user:pass` pairs
+    basic_credentials: Vec<[u8; 32]>,
+
+    /// SHA-256 digests of accepted Bearer tokens
+    tokens: Vec<[u8; 32]>,
+
+    /// Networks allowed to connect (any if empty)
+    allow: Vec<IpNet>,
+
+    /// Networks always refused
+    deny: Vec<IpNet>,
+
+    /// Contexts that skip the credential checks
+    bypass: BypassContexts,
+}
+
+impl AuthConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept Basic auth with a `user:pass` pair
+    pub fn add_basic_credentials(&mut self, user_pass: &str) -> Result<(), String> {
+        match user_pass.split_once(':') {
+            Some((user, _)) if !user.is_empty() => {
+                self.basic_credentials.push(digest(user_pass.as_bytes()));
+                Ok(())
+            }
+            _ => Err("Invalid --auth value. Expected user:pass".to_string()),
+        }
+    }
+
+    /// Accept a Bearer token
+    pub fn add_token(&mut self, token: &str) -> Result<(), String> {
+        if token.is_empty() {
+            return Err("Bearer token must not be empty".to_string());
+        }
+        self.tokens.push(digest(token.as_bytes()));
+        Ok(())
+    }
+
+    /// Allow a comma-separated list of IPs or CIDR ranges
+    pub fn add_allowed(&mut self, list: &str) -> Result<(), String> {
+        self.allow.extend(parse_networks(list)?);
+        Ok(())
+    }
+
+    /// Deny a comma-separated list of IPs or CIDR ranges
+    pub fn add_denied(&mut self, list: &str) -> Result<(), String> {
+        self.deny.extend(parse_networks(list)?);
+        Ok(())
+    }
+
+    pub fn set_bypass(&mut self, bypass: BypassContexts) {
+        self.bypass = bypass;
+    }
+
+    /// Whether any access control is configured
+    pub fn is_enabled(&self) -> bool {
+        self.requires_credentials() || !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    fn requires_credentials(&self) -> bool {
+        !self.basic_credentials.is_empty() || !self.tokens.is_empty()
+    }
+
+    /// Check a request from `client_ip` in `context`, made on a connection
+    /// with `client_cert` if the client presented a verified one
+    pub fn check(
+        &self,
+        context: AccessContext,
+        client_ip: IpAddr,
+        headers: &HeaderMap,
+        client_cert: Option<&ClientCert>,
+    ) -> AuthDecision {
+        let client_ip = client_ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&client_ip))
+            || (!self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(&client_ip)))
+        {
+            return AuthDecision::Forbidden;
+        }
+
+        // A browser User-Agent is client-controlled, so only loopback peers
+        // earn the local-browser bypass
+        if self.bypass.0.contains(&context) && (context != AccessContext::LocalBrowser || client_ip.is_loopback()) {
+            return AuthDecision::Allow;
+        }
+
+        // The TLS handshake already proved who the client is
+        if let Some(cert) = client_cert {
+            return AuthDecision::Authenticated(cert.identity());
+        }
+
+        if !self.requires_credentials() {
+            return AuthDecision::Allow;
+        }
+
+        let authorization = headers.get(header::AUTHORIZATION).and_then(|h| h.to_str().ok());
+        let identity = match authorization.and_then(|v| v.split_once(' ')) {
+            Some((scheme, value)) if scheme.eq_ignore_ascii_case("basic") => base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()
+                .filter(|decoded| matches_any(&self.basic_credentials, decoded))
+                .map(|decoded| {
+                    let user = decoded.split(|b| *b == b':').next().unwrap_or_default();
+                    format!("user:{}", String::from_utf8_lossy(user))
+                }),
+            Some((scheme, value)) if scheme.eq_ignore_ascii_case("bearer") => {
+                let token = value.trim().as_bytes();
+                matches_any(&self.tokens, token).then(|| token_identity(token))
+            }
+            _ => None,
+        };
+
+        identity.map(AuthDecision::Authenticated).unwrap_or(AuthDecision::Unauthorized)
+    }
+
+    /// Response for a refused request
+    pub fn reject(&self, decision: &AuthDecision) -> Response<Body> {
+        let mut builder = Response::builder().header(header::CONTENT_TYPE, "application/json");
+
+        if *decision == AuthDecision::Forbidden {
+            return builder
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from(r#"{"error": "Access denied"}"#))
+                .unwrap();
+        }
+
+        if !self.basic_credentials.is_empty() {
+            builder = builder.header(header::WWW_AUTHENTICATE, format!("Basic realm=\"{}\", charset=\"UTF-8\"", AUTH_REALM));
+        }
+        if !self.tokens.is_empty() {
+            builder = builder.header(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+
+        builder
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::from(r#"{"error": "Authentication required"}"#))
+            .unwrap()
+    }
+}
+
+fn digest(secret: &[u8]) -> [u8; 32] {
+    Sha256::digest(secret).into()
+}
+
+/// Non-secret identity for a token: a short prefix of its digest
+fn token_identity(token: &[u8]) -> String {
+    let prefix: String = digest(token)[..4].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("token:{}", prefix)
+}
+
+/// Compare against every digest without short-circuiting
+fn matches_any(digests: &[[u8; 32]], candidate: &[u8]) -> bool {
+    let candidate = digest(candidate);
+    digests
+        .iter()
+        .fold(subtle::Choice::from(0), |found, expected| found | expected.ct_eq(&candidate))
+        .into()
+}
+
+/// Parse `10.0.0.0/8,192.168.1.100,::1` into networks (bare IPs become /32 or /128)
+fn parse_networks(list: &str) -> Result<Vec<IpNet>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .map(|net| net.trunc())
+                .map_err(|_| format!("Invalid IP address or CIDR range '{}'", s))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_basic_and_bearer() {
+        let mut auth = AuthConfig::new();
+        auth.add_basic_credentials("admin:secret123").unwrap();
+        auth.add_token("my-secret-token").unwrap();
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+        let ctx = AccessContext::ExternalAccess;
+
+        // admin:secret123
+        assert_eq!(
+            auth.check(ctx, ip, &headers("Basic YWRtaW46c2VjcmV0MTIz"), None),
+            AuthDecision::Authenticated("user:admin".to_string())
+        );
+        assert_eq!(auth.check(ctx, ip, &headers("basic YWRtaW46d3Jvbmc="), None), AuthDecision::Unauthorized);
+        assert_eq!(
+            auth.check(ctx, ip, &headers("Bearer my-secret-token"), None),
+            AuthDecision::Authenticated(token_identity(b"my-secret-token"))
+        );
+        assert_eq!(auth.check(ctx, ip, &headers("Bearer my-secret"), None), AuthDecision::Unauthorized);
+        assert_eq!(auth.check(ctx, ip, &HeaderMap::new(), None), AuthDecision::Unauthorized);
+
+        let cert = ClientCert { subject: "CN=alice, O=Team".to_string(), fingerprint: String::new() };
+        assert_eq!(
+            auth.check(ctx, ip, &HeaderMap::new(), Some(&cert)),
+            AuthDecision::Authenticated("cert:CN=alice, O=Team".to_string())
+        );
+
+        let challenge = auth.reject(&AuthDecision::Unauthorized);
+        assert_eq!(challenge.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(challenge.headers().get_all(header::WWW_AUTHENTICATE).iter().count(), 2);
+
+        assert!(auth.add_basic_credentials("nopassword").is_err());
+    }
+
+    #[test]
+    fn test_cidr_allow_and_deny() {
+        let mut auth = AuthConfig::new();
+        auth.set_bypass("none".parse().unwrap());
+        auth.add_allowed("10.0.0.0/8, 192.168.1.100, fd00::/8").unwrap();
+        auth.add_denied("10.0.0.13").unwrap();
+        let check = |ip: &str| auth.check(AccessContext::APIClient, ip.parse().unwrap(), &HeaderMap::new(), None);
+
+        assert_eq!(check("10.1.2.3"), AuthDecision::Allow);
+        assert_eq!(check("::ffff:10.1.2.3"), AuthDecision::Allow);
+        assert_eq!(check("192.168.1.100"), AuthDecision::Allow);
+        assert_eq!(check("fd12::1"), AuthDecision::Allow);
+        assert_eq!(check("10.0.0.13"), AuthDecision::Forbidden);
+        assert_eq!(check("192.168.1.101"), AuthDecision::Forbidden);
+        assert_eq!(check("2001:db8::1"), AuthDecision::Forbidden);
+
+        assert!(auth.add_allowed("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn test_bypass_contexts() {
+        let mut auth = AuthConfig::new();
+        auth.add_token("t").unwrap();
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(auth.check(AccessContext::LocalBrowser, local, &HeaderMap::new(), None), AuthDecision::Allow);
+        assert_eq!(auth.check(AccessContext::ExternalAccess, local, &HeaderMap::new(), None), AuthDecision::Unauthorized);
+        assert_eq!(auth.check(AccessContext::WebhookService, local, &HeaderMap::new(), None), AuthDecision::Unauthorized);
+
+        // A LAN host sending a browser User-Agent is not a local browser
+        let lan: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(auth.check(AccessContext::LocalBrowser, lan, &HeaderMap::new(), None), AuthDecision::Unauthorized);
+
+        // The IP lists apply to bypassed contexts too
+        auth.add_denied("127.0.0.1").unwrap();
+        assert_eq!(auth.check(AccessContext::LocalBrowser, local, &HeaderMap::new(), None), AuthDecision::Forbidden);
+
+        auth.set_bypass("webhook,api".parse().unwrap());
+        assert_eq!(auth.check(AccessContext::WebhookService, lan, &HeaderMap::new(), None), AuthDecision::Allow);
+        assert_eq!(auth.check(AccessContext::LocalBrowser, lan, &HeaderMap::new(), None), AuthDecision::Unauthorized);
+    }
+}
@@ -2,7 +2,12 @@
 //!
 //! Provides in-memory caching for static assets to reduce latency
 //! and bandwidth usage, especially helpful in Tor modes.
+//!
+//! The cache is shared between every client, so responses to requests with
+//! credentials are only stored when marked `public`, and responses are keyed
+//! by the request headers their `Vary` header names.
 
+use hyper::header::{self, HeaderMap};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -114,6 +119,9 @@ pub struct ResponseCache {
 
     /// Whether caching is enabled
     enabled: bool,
+
+    /// Request headers responses for a URL vary on, by base key
+    vary: Arc<RwLock<HashMap<String, Vec<String>>>>,
 }
 
 impl ResponseCache {
@@ -158,6 +166,7 @@ impl ResponseCache {
                 "application/font-woff2".to_string(),
             ],
             enabled,
+            vary: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -196,6 +205,52 @@ impl ResponseCache {
         }
     }
 
+    /// Key for a request: the base key plus the values of the request
+    /// headers the last stored response for it varied on
+    pub async fn variant_key(&self, base: &str, request_headers: &HeaderMap) -> String {
+        match self.vary.read().await.get(base) {
+            Some(names) => vary_key(base, names, request_headers),
+            None => base.to_string(),
+        }
+    }
+
+    /// Key to store a response under, remembering what it varies on for
+    /// later lookups; `None` when the response must not be shared
+    pub async fn store_key(&self, base: &str, request_headers: &HeaderMap, response_headers: &HeaderMap) -> Option<String> {
+        let public = response_headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| has_directive(v, "public"));
+        let credentials = request_headers.contains_key(header::AUTHORIZATION) || request_headers.contains_key(header::COOKIE);
+        if credentials && !public {
+            return None;
+        }
+
+        let mut names: Vec<String> = response_headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if names.iter().any(|name| name == "*") {
+            return None;
+        }
+        names.sort();
+        names.dedup();
+
+        let key = vary_key(base, &names, request_headers);
+        let mut vary = self.vary.write().await;
+        if names.is_empty() {
+            vary.remove(base);
+        } else {
+            vary.insert(base.to_string(), names);
+        }
+        Some(key)
+    }
+
     /// Get an entry from cache
     pub async fn get(&self, key: &str) -> Option<CacheEntry> {
         if !self.enabled {
@@ -345,6 +400,7 @@ impl ResponseCache {
         let mut stats = self.stats.write().await;
 
         entries.clear();
+        self.vary.write().await.clear();
         stats.current_size = 0;
         stats.entry_count = 0;
 
@@ -369,6 +425,26 @@ impl ResponseCache {
     }
 }
 
+/// Base key extended with the request's values for the varied headers
+fn vary_key(base: &str, names: &[String], request_headers: &HeaderMap) -> String {
+    let mut key = base.to_string();
+    for name in names {
+        let values: Vec<&str> = request_headers.get_all(name.as_str()).iter().filter_map(|v| v.to_str().ok()).collect();
+        key.push_str(&format!("\n{}: {}", name, values.join(", ")));
+    }
+    key
+}
+
+/// Whether a Cache-Control value contains a directive, with or without an
+/// argument (`private` matches `private="Set-Cookie"`)
+pub fn has_directive(header_value: &str, name: &str) -> bool {
+    header_value.split(',').any(|directive| {
+        let directive = directive.trim();
+        let directive = directive.split_once('=').map_or(directive, |(name, _)| name.trim_end());
+        directive.eq_ignore_ascii_case(name)
+    })
+}
+
 /// Parse Cache-Control header to determine TTL
 pub fn parse_cache_control(header_value: &str) -> Option<Duration> {
     for directive in header_value.split(',') {
@@ -432,4 +508,62 @@ mod tests {
         assert!(!cache.should_cache("/api/data", None));
         assert!(cache.should_cache("/api/data", Some("application/json")));
     }
+
+    #[tokio::test]
+    async fn test_put_and_get() {
+        let cache = ResponseCache::new(1, 300, true);
+        let key = ResponseCache::cache_key("GET", "/app.js", None);
+
+        assert!(cache.get(&key).await.is_none());
+
+        cache.put(
+            key.clone(),
+            b"console.log(1)".to_vec(),
+            "application/javascript".to_string(),
+            200,
+            vec![("etag".to_string(), "\"abc\"".to_string())],
+            None,
+        ).await;
+
+        let entry = cache.get(&key).await.expect("entry should be cached");
+        assert_eq!(entry.body, b"console.log(1)");
+        assert_eq!(entry.status_code, 200);
+        assert_eq!(entry.hit_count, 1);
+
+        let stats = cache.get_stats().await;
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.entry_count, 1);
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_store_key_respects_credentials_and_vary() {
+        let cache = ResponseCache::new(1, 300, true);
+        let base = ResponseCache::cache_key("GET", "/app.js", None);
+        let anonymous = HeaderMap::new();
+
+        // Responses to requests with credentials are private unless marked public
+        let cookie = headers(&[("cookie", "session=1")]);
+        let bearer = headers(&[("authorization", "Bearer t")]);
+        assert!(cache.store_key(&base, &cookie, &HeaderMap::new()).await.is_none());
+        assert!(cache.store_key(&base, &bearer, &headers(&[("cache-control", "max-age=60")])).await.is_none());
+        assert!(cache.store_key(&base, &cookie, &headers(&[("cache-control", "Public, max-age=60")])).await.is_some());
+
+        assert!(cache.store_key(&base, &anonymous, &headers(&[("vary", "*")])).await.is_none());
+
+        // Varied request headers become part of the key for stores and lookups
+        let gzip = headers(&[("accept-encoding", "gzip")]);
+        let br = headers(&[("accept-encoding", "br")]);
+        let key = cache.store_key(&base, &gzip, &headers(&[("vary", "Accept-Encoding")])).await.unwrap();
+        assert_eq!(cache.variant_key(&base, &gzip).await, key);
+        assert_ne!(cache.variant_key(&base, &br).await, key);
+
+        // A response without Vary resets the key to the URL alone
+        assert_eq!(cache.store_key(&base, &gzip, &HeaderMap::new()).await.unwrap(), base);
+        assert_eq!(cache.variant_key(&base, &br).await, base);
+    }
 }
@@ -0,0 +1,512 @@
+//! Request Capture Module
+//!
+//! Records request/response metadata, headers and bodies (up to a size limit)
+//! into a bounded in-memory ring buffer for the inspector. Bodies are teed
+//! while they stream, so capturing never buffers a whole response.
+//! Credential headers are recorded with a placeholder instead of their value,
+//! so exports and replays never carry them.
+
+use base64::Engine;
+use futures_util::stream::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::HeaderMap;
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::context::AccessContext;
+use crate::tunnel::ConnectionInfo;
+
+/// Default number of exchanges kept in memory
+pub const DEFAULT_CAPTURE_CAPACITY: usize = 500;
+
+/// Default maximum captured body size per request/response (1MB)
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Request headers whose values are credentials
+pub const CREDENTIAL_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+
+/// Value recorded in place of a credential
+pub const REDACTED: &str = "[redacted]";
+
+/// A single header line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedHeader {
+    pub name: String,
+    pub value: String,
+}
+
+/// How captured body bytes are encoded in `CapturedBody::data`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+/// Captured (possibly truncated) body
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedBody {
+    /// Captured bytes as UTF-8 text or base64
+    pub data: String,
+
+    /// Encoding of `data`
+    pub encoding: BodyEncoding,
+
+    /// Total body size seen, including bytes past the capture limit
+    pub size: u64,
+
+    /// Whether `data` holds less than the full body
+    pub truncated: bool,
+}
+
+impl CapturedBody {
+    /// Decode the captured bytes
+    pub fn bytes(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        match self.encoding {
+            BodyEncoding::Utf8 => Ok(self.data.as_bytes().to_vec()),
+            BodyEncoding::Base64 => base64::engine::general_purpose::STANDARD.decode(&self.data),
+        }
+    }
+
+    /// Encode raw bytes, using text when they are valid UTF-8
+    pub fn from_bytes(bytes: &[u8], size: u64) -> Self {
+        let (data, encoding) = match std::str::from_utf8(bytes) {
+            Ok(text) => (text.to_string(), BodyEncoding::Utf8),
+            // Text cut mid-character by the capture limit; a complete body
+            // ending in a partial character is not text
+            Err(e) if e.error_len().is_none() && (bytes.len() as u64) < size => {
+                (String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned(), BodyEncoding::Utf8)
+            }
+            Err(_) => (base64::engine::general_purpose::STANDARD.encode(bytes), BodyEncoding::Base64),
+        };
+
+        CapturedBody {
+            data,
+            encoding,
+            size,
+            truncated: (bytes.len() as u64) < size,
+        }
+    }
+}
+
+/// A captured request/response exchange
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedExchange {
+    /// Capture ID (monotonic)
+    pub id: u64,
+
+    /// Wall-clock start time in milliseconds since the Unix epoch
+    pub started_at_ms: u64,
+
+    /// ID of the exchange this one replayed, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_of: Option<u64>,
+
+    /// Request method
+    pub method: String,
+
+    /// Scheme the client used (`http` or `https`)
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+
+    /// Request path and query as sent by the client
+    pub uri: String,
+
+    /// Host the client addressed
+    pub host: Option<String>,
+
+    /// HTTP version of the request, e.g. `HTTP/1.1`
+    pub http_version: String,
+
+    /// Client address
+    pub client_addr: String,
+
+    /// Detected access context
+    pub context: String,
+
+    /// Request headers as received
+    pub request_headers: Vec<CapturedHeader>,
+
+    /// Request body
+    pub request_body: CapturedBody,
+
+    /// Response status code
+    pub status: u16,
+
+    /// Response headers as sent to the client
+    pub response_headers: Vec<CapturedHeader>,
+
+    /// Response body
+    pub response_body: CapturedBody,
+
+    /// Time until response headers were ready, in milliseconds
+    pub wait_ms: f64,
+
+    /// Time until the response body finished, in milliseconds
+    pub duration_ms: f64,
+}
+
+/// Bounded ring buffer of captured exchanges with a live event feed
+pub struct CaptureStore {
+    /// Captured exchanges, oldest first
+    entries: Mutex<VecDeque<CapturedExchange>>,
+
+    /// Maximum number of exchanges kept
+    capacity: usize,
+
+    /// Maximum bytes captured per body
+    max_body_size: usize,
+
+    /// Next capture ID
+    next_id: AtomicU64,
+
+    /// Live feed of completed exchanges
+    events: broadcast::Sender<CapturedExchange>,
+}
+
+impl CaptureStore {
+    pub fn new(capacity: usize, max_body_size: usize) -> Self {
+        let (events, _) = broadcast::channel(256);
+
+        CaptureStore {
+            entries: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            capacity: capacity.max(1),
+            max_body_size,
+            next_id: AtomicU64::new(1),
+            events,
+        }
+    }
+
+    /// Start capturing a request; the returned handle tees its body
+    pub fn begin(self: &Arc<Self>, req: &Request<Body>, conn: &ConnectionInfo, context: AccessContext) -> CaptureHandle {
+        let host = req
+            .headers()
+            .get(hyper::header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .map(|s| s.to_string())
+            .or_else(|| req.uri().authority().map(|a| a.to_string()));
+
+        let exchange = CapturedExchange {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            started_at_ms: unix_millis(SystemTime::now()),
+            replay_of: None,
+            method: req.method().to_string(),
+            scheme: conn.scheme().to_string(),
+            uri: req.uri().path_and_query().map(|pq| pq.to_string()).unwrap_or_else(|| "/".to_string()),
+            host,
+            http_version: format!("{:?}", req.version()),
+            client_addr: conn.remote_addr.to_string(),
+            context: format!("{:?}", context),
+            request_headers: capture_headers(req.headers()),
+            request_body: CapturedBody::default(),
+            status: 0,
+            response_headers: Vec::new(),
+            response_body: CapturedBody::default(),
+            wait_ms: 0.0,
+            duration_ms: 0.0,
+        };
+
+        CaptureHandle {
+            pending: Arc::new(Mutex::new(PendingCapture {
+                exchange,
+                request_bytes: Vec::new(),
+                request_size: 0,
+                response_bytes: Vec::new(),
+                response_size: 0,
+                started: Instant::now(),
+                max_body_size: self.max_body_size,
+                store: Arc::clone(self),
+            })),
+        }
+    }
+
+    /// Add a finished exchange, evicting the oldest past capacity
+    pub fn insert(&self, exchange: CapturedExchange) {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            while entries.len() >= self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(exchange.clone());
+        }
+
+        debug!("Captured #{} {} {} → {}", exchange.id, exchange.method, exchange.uri, exchange.status);
+        let _ = self.events.send(exchange);
+    }
+
+    /// Store an exchange recorded elsewhere (e.g. imported), assigning it a new ID
+    pub fn import(&self, mut exchange: CapturedExchange) -> u64 {
+        exchange.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let id = exchange.id;
+        self.insert(exchange);
+        id
+    }
+
+    /// All captured exchanges, newest first
+    pub fn list(&self) -> Vec<CapturedExchange> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Look up a captured exchange by ID
+    pub fn get(&self, id: u64) -> Option<CapturedExchange> {
+        self.entries.lock().unwrap().iter().find(|e| e.id == id).cloned()
+    }
+
+    /// Remove all captured exchanges
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Subscribe to completed exchanges
+    pub fn subscribe(&self) -> broadcast::Receiver<CapturedExchange> {
+        self.events.subscribe()
+    }
+}
+
+/// Exchange being captured; committed to the store once both bodies are done
+struct PendingCapture {
+    exchange: CapturedExchange,
+    request_bytes: Vec<u8>,
+    request_size: u64,
+    response_bytes: Vec<u8>,
+    response_size: u64,
+    started: Instant,
+    max_body_size: usize,
+    store: Arc<CaptureStore>,
+}
+
+impl PendingCapture {
+    fn record(&mut self, side: BodySide, chunk: &[u8]) {
+        let max = self.max_body_size;
+        let (buf, size) = match side {
+            BodySide::Request => (&mut self.request_bytes, &mut self.request_size),
+            BodySide::Response => (&mut self.response_bytes, &mut self.response_size),
+        };
+
+        *size += chunk.len() as u64;
+        let room = max.saturating_sub(buf.len());
+        buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+}
+
+impl Drop for PendingCapture {
+    fn drop(&mut self) {
+        let mut exchange = std::mem::replace(&mut self.exchange, empty_exchange());
+        exchange.request_body = CapturedBody::from_bytes(&self.request_bytes, self.request_size);
+        exchange.response_body = CapturedBody::from_bytes(&self.response_bytes, self.response_size);
+        exchange.duration_ms = millis(self.started.elapsed());
+        self.store.insert(exchange);
+    }
+}
+
+/// Which body a chunk belongs to
+#[derive(Clone, Copy)]
+enum BodySide {
+    Request,
+    Response,
+}
+
+/// Handle used by the proxy to feed an in-flight capture
+pub struct CaptureHandle {
+    pending: Arc<Mutex<PendingCapture>>,
+}
+
+impl CaptureHandle {
+    /// ID the exchange will be stored under
+    pub fn id(&self) -> u64 {
+        self.pending.lock().unwrap().exchange.id
+    }
+
+    /// Mark the exchange as a replay of an earlier capture
+    pub fn set_replay_of(&self, id: u64) {
+        self.pending.lock().unwrap().exchange.replay_of = Some(id);
+    }
+
+    /// Record a request body that is already fully in memory
+    pub fn set_request_body(&self, body: &[u8]) {
+        self.pending.lock().unwrap().record(BodySide::Request, body);
+    }
+
+    /// Tee the request body into the capture
+    pub fn tee_request(&self, req: Request<Body>) -> Request<Body> {
+        let (parts, body) = req.into_parts();
+        Request::from_parts(parts, self.tee(body, BodySide::Request))
+    }
+
+    /// Record response metadata and tee the response body into the capture
+    pub fn finish(self, response: Response<Body>, wait: Duration) -> Response<Body> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.exchange.status = response.status().as_u16();
+            pending.exchange.response_headers = capture_headers(response.headers());
+            pending.exchange.wait_ms = millis(wait);
+        }
+
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, self.tee(body, BodySide::Response))
+    }
+
+    fn tee(&self, body: Body, side: BodySide) -> Body {
+        // Leave empty bodies alone so they keep their exact (zero) length
+        if body.is_end_stream() {
+            return body;
+        }
+
+        Body::wrap_stream(TeeBody {
+            inner: body,
+            side,
+            pending: Arc::clone(&self.pending),
+        })
+    }
+}
+
+/// Body stream that copies chunks into a pending capture as they pass
+struct TeeBody {
+    inner: Body,
+    side: BodySide,
+    pending: Arc<Mutex<PendingCapture>>,
+}
+
+impl Stream for TeeBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(ref chunk))) = poll {
+            self.pending.lock().unwrap().record(self.side, chunk);
+        }
+        poll
+    }
+}
+
+/// Convert headers into capture records, redacting credentials
+pub fn capture_headers(headers: &HeaderMap) -> Vec<CapturedHeader> {
+    headers
+        .iter()
+        .map(|(name, value)| CapturedHeader {
+            name: name.as_str().to_string(),
+            value: if is_credential_header(name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            },
+        })
+        .collect()
+}
+
+/// Whether a header carries credentials
+pub fn is_credential_header(name: &str) -> bool {
+    CREDENTIAL_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Milliseconds since the Unix epoch
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn default_scheme() -> String {
+    "http".to_string()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn empty_exchange() -> CapturedExchange {
+    CapturedExchange {
+        id: 0,
+        started_at_ms: 0,
+        replay_of: None,
+        method: String::new(),
+        scheme: default_scheme(),
+        uri: String::new(),
+        host: None,
+        http_version: String::new(),
+        client_addr: String::new(),
+        context: String::new(),
+        request_headers: Vec::new(),
+        request_body: CapturedBody::default(),
+        status: 0,
+        response_headers: Vec::new(),
+        response_body: CapturedBody::default(),
+        wait_ms: 0.0,
+        duration_ms: 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_capture_tees_bodies_and_truncates() {
+        let store = Arc::new(CaptureStore::new(10, 4));
+
+        let req = Request::post("/submit")
+            .header("host", "myapp.local")
+            .header("authorization", "Bearer secret")
+            .header("cookie", "session=1")
+            .body(Body::from("hello world"))
+            .unwrap();
+        let conn = ConnectionInfo { remote_addr: ([127, 0, 0, 1], 5000).into(), secure: true, client_cert: None, via_onion: false };
+        let handle = store.begin(&req, &conn, AccessContext::APIClient);
+        let req = handle.tee_request(req);
+        let sent = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(&sent[..], b"hello world");
+
+        let response = handle.finish(Response::new(Body::from(vec![0xffu8, 0xfe])), Duration::from_millis(3));
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let captured = store.list();
+        assert_eq!(captured.len(), 1);
+        let exchange = &captured[0];
+        assert_eq!(exchange.method, "POST");
+        assert_eq!(exchange.scheme, "https");
+        assert_eq!(exchange.context, "APIClient");
+        assert_eq!(exchange.host.as_deref(), Some("myapp.local"));
+        assert_eq!(exchange.request_headers[1], CapturedHeader { name: "authorization".to_string(), value: REDACTED.to_string() });
+        assert_eq!(exchange.request_headers[2], CapturedHeader { name: "cookie".to_string(), value: REDACTED.to_string() });
+        assert_eq!(exchange.request_body.data, "hell");
+        assert_eq!(exchange.request_body.size, 11);
+        assert!(exchange.request_body.truncated);
+        assert_eq!(exchange.response_body.encoding, BodyEncoding::Base64);
+        assert_eq!(exchange.response_body.bytes().unwrap(), vec![0xff, 0xfe]);
+        assert_eq!(exchange.status, 200);
+    }
+
+    #[test]
+    fn test_body_encoding_keeps_every_byte() {
+        // Cut inside "€" by the capture limit: keep the text before it
+        let body = CapturedBody::from_bytes(&[b'a', 0xe2, 0x82], 4);
+        assert_eq!((body.data.as_str(), body.encoding, body.truncated), ("a", BodyEncoding::Utf8, true));
+
+        // A complete body that happens to end in a lead byte is binary
+        let body = CapturedBody::from_bytes(&[b'a', 0xe2], 2);
+        assert_eq!(body.encoding, BodyEncoding::Base64);
+        assert!(!body.truncated);
+        assert_eq!(body.bytes().unwrap(), vec![b'a', 0xe2]);
+    }
+
+    #[test]
+    fn test_ring_buffer_evicts_oldest() {
+        let store = CaptureStore::new(2, 1024);
+        for id in 1..=3 {
+            let mut exchange = empty_exchange();
+            exchange.id = id;
+            store.insert(exchange);
+        }
+
+        let ids: Vec<u64> = store.list().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![3, 2]);
+        assert!(store.get(1).is_none());
+    }
+}
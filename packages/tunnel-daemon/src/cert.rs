@@ -1,82 +1,645 @@
-use rcgen::{Certificate, CertificateParams, KeyPair, PKCS_ECDSA_P256_SHA256};
-use rustls::{Certificate as RustlsCertificate, PrivateKey};
-use std::fs;
+//! Certificate Module
+//!
+//! Local certificate authority for HTTPS. Beam creates one root CA per user,
+//! kept in its data directory with the key readable only by the owner, and
+//! signs short-lived leaf certificates for each tunnel domain and its
+//! subdomains. Trusting the CA once (see `beam-tunnel-daemon ca install`)
+//! makes every Beam certificate valid in browsers. Teams with their own CA
+//! can instead provide a PEM certificate chain and key, which are checked
+//! against the tunnel domain before use.
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose, SanType, PKCS_ECDSA_P256_SHA256,
+};
+use rustls::sign;
+use rustls::{Certificate as RustlsCertificate, PrivateKey, SignatureScheme};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
 use tracing::{info, warn};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
 
-/// Generate a self-signed certificate for a domain
-pub fn generate_self_signed_cert(domain: &str) -> Result<(Vec<u8>, Vec<u8>), Box<dyn std::error::Error>> {
-    let mut params = CertificateParams::new(vec![domain.to_string()]);
-    
-    // Add localhost and 127.0.0.1 as alternative names
-    params.subject_alt_names = vec![
-        rcgen::SanType::DnsName("localhost".to_string()),
-        rcgen::SanType::IpAddress(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))),
-        rcgen::SanType::DnsName(domain.to_string()),
-    ];
-    
-    // Generate key pair
-    let key_pair = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?;
-    params.key_pair = Some(key_pair);
-    
-    // Generate certificate
-    let cert = Certificate::from_params(params)?;
-    
-    // Get certificate and private key in DER format
-    let cert_der = cert.serialize_der()?;
-    let key_der = cert.serialize_private_key_der();
-    
-    Ok((cert_der, key_der))
-}
-
-/// Load or generate certificate for a domain
-pub fn get_or_create_cert(domain: &str, cert_dir: Option<&Path>) -> Result<(Vec<RustlsCertificate>, PrivateKey), Box<dyn std::error::Error>> {
-    let cert_path = if let Some(dir) = cert_dir {
-        dir.join(format!("{}.cert", domain.replace(".", "_")))
-    } else {
-        PathBuf::from(format!("{}.cert", domain.replace(".", "_")))
-    };
-    
-    let key_path = if let Some(dir) = cert_dir {
-        dir.join(format!("{}.key", domain.replace(".", "_")))
-    } else {
-        PathBuf::from(format!("{}.key", domain.replace(".", "_")))
-    };
-    
-    // Try to load existing certificate
-    if cert_path.exists() && key_path.exists() {
-        match (fs::read(&cert_path), fs::read(&key_path)) {
-            (Ok(cert_bytes), Ok(key_bytes)) => {
-                info!("Loading existing certificate for {}", domain);
-                let cert = RustlsCertificate(cert_bytes);
-                let key = PrivateKey(key_bytes);
-                return Ok((vec![cert], key));
+/// CA certificate file name (PEM)
+const CA_CERT_FILE: &str = "beam-ca.pem";
+
+/// CA private key file name (PEM, mode 0600)
+const CA_KEY_FILE: &str = "beam-ca.key";
+
+/// Name of the CA in trust stores
+pub const CA_NICKNAME: &str = "Beam Local CA";
+
+/// Lifetime of the root CA
+const CA_VALIDITY: Duration = Duration::days(3650);
+
+/// Lifetime of leaf certificates
+pub const LEAF_VALIDITY: Duration = Duration::days(7);
+
+/// Leaf certificates are replaced when they have less than this left
+pub const RENEW_BEFORE: Duration = Duration::days(2);
+
+/// Debian-style directory of extra trusted CAs (picked up by `update-ca-certificates`)
+pub const SYSTEM_CA_DIR: &str = "/usr/local/share/ca-certificates";
+
+/// Schemes tried when checking a provided key against its certificate
+const KEY_CHECK_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+/// Where the CA lives unless overridden
+pub fn default_ca_dir() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("beam")
+        .join("ca")
+}
+
+/// Trust store to install the CA certificate into
+#[derive(Debug, Clone)]
+pub enum TrustStore {
+    /// NSS database directory (Firefox and Chrome profiles, `~/.pki/nssdb`)
+    Nss(PathBuf),
+    /// Directory of PEM `.crt` files (`/usr/local/share/ca-certificates`)
+    Directory(PathBuf),
+}
+
+/// Local root CA that signs Beam's leaf certificates
+pub struct CertificateAuthority {
+    /// Signing certificate rebuilt from the stored CA
+    cert: Certificate,
+
+    /// The CA certificate exactly as stored (and as trusted by users)
+    cert_pem: String,
+
+    /// Directory holding the CA files
+    dir: PathBuf,
+}
+
+impl CertificateAuthority {
+    /// Load the CA from `dir`, creating it on first use
+    pub fn load_or_create(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+
+        if cert_path.exists() && key_path.exists() {
+            let cert_pem = fs::read_to_string(&cert_path)?;
+            let key_pair = KeyPair::from_pem(&fs::read_to_string(&key_path)?)?;
+            let cert = Certificate::from_params(CertificateParams::from_ca_cert_pem(&cert_pem, key_pair)?)?;
+            return Ok(CertificateAuthority { cert, cert_pem, dir: dir.to_path_buf() });
+        }
+
+        info!("Creating local certificate authority in {}", dir.display());
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.key_pair = Some(KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?);
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, format!("{} ({})", CA_NICKNAME, owner()));
+        params.distinguished_name.push(DnType::OrganizationName, "Beam");
+        params.serial_number = Some(rand::random::<u64>().into());
+        params.not_before = OffsetDateTime::now_utc() - Duration::hours(1);
+        params.not_after = OffsetDateTime::now_utc() + CA_VALIDITY;
+        let cert = Certificate::from_params(params)?;
+        let cert_pem = cert.serialize_pem()?;
+
+        create_private_dir(dir)?;
+        write_private(&key_path, cert.serialize_private_key_pem().as_bytes())?;
+        fs::write(&cert_path, &cert_pem)?;
+
+        Ok(CertificateAuthority { cert, cert_pem, dir: dir.to_path_buf() })
+    }
+
+    /// Path of the CA certificate (PEM)
+    pub fn cert_path(&self) -> PathBuf {
+        self.dir.join(CA_CERT_FILE)
+    }
+
+    /// CA certificate in PEM format
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// CA certificate in DER format
+    pub fn cert_der(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        rustls_pemfile::certs(&mut self.cert_pem.as_bytes())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| "CA certificate file holds no certificate".into())
+    }
+
+    /// Hex SHA-256 of the CA certificate
+    pub fn fingerprint(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(Sha256::digest(self.cert_der()?).iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Sign a leaf certificate for `domain`, `*.domain` and localhost
+    pub fn issue(&self, domain: &str) -> Result<IssuedCert, Box<dyn std::error::Error>> {
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.key_pair = Some(KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, domain);
+        params.subject_alt_names = vec![
+            SanType::DnsName(domain.to_string()),
+            SanType::DnsName(format!("*.{}", domain)),
+            SanType::DnsName("localhost".to_string()),
+            SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            SanType::IpAddress(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        ];
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        params.serial_number = Some(rand::random::<u64>().into());
+        // Whole seconds, as stored in the certificate
+        let not_after = (OffsetDateTime::now_utc() + LEAF_VALIDITY).replace_nanosecond(0)?;
+        params.not_before = OffsetDateTime::now_utc() - Duration::hours(1);
+        params.not_after = not_after;
+
+        let leaf = Certificate::from_params(params)?;
+        let leaf_der = leaf.serialize_der_with_signer(&self.cert)?;
+
+        Ok(IssuedCert {
+            chain: vec![RustlsCertificate(leaf_der), RustlsCertificate(self.cert_der()?)],
+            key: PrivateKey(leaf.serialize_private_key_der()),
+            not_after,
+        })
+    }
+
+    /// Add the CA certificate to a trust store
+    pub fn install(&self, store: &TrustStore) -> Result<(), Box<dyn std::error::Error>> {
+        match store {
+            TrustStore::Nss(db) => {
+                let db = if db.to_string_lossy().contains(':') {
+                    db.to_string_lossy().into_owned()
+                } else {
+                    format!("sql:{}", db.display())
+                };
+                let status = Command::new("certutil")
+                    .args(["-d", &db, "-A", "-t", "C,,", "-n", CA_NICKNAME, "-i"])
+                    .arg(self.cert_path())
+                    .status()
+                    .map_err(|e| format!("Could not run certutil (install libnss3-tools or nss-tools): {}", e))?;
+                if !status.success() {
+                    return Err(format!("certutil failed to add the CA to {} ({})", db, status).into());
+                }
+                info!("Added {} to NSS database {}", CA_NICKNAME, db);
             }
-            _ => {
-                warn!("Failed to read existing certificate, generating new one");
+            TrustStore::Directory(dir) => {
+                fs::create_dir_all(dir)?;
+                let path = dir.join("beam-local-ca.crt");
+                fs::write(&path, &self.cert_pem)?;
+                info!("Wrote {}", path.display());
+
+                if dir == Path::new(SYSTEM_CA_DIR) {
+                    match Command::new("update-ca-certificates").status() {
+                        Ok(status) if status.success() => info!("Updated the system trust store"),
+                        Ok(status) => warn!("update-ca-certificates failed ({})", status),
+                        Err(e) => warn!("Could not run update-ca-certificates: {}", e),
+                    }
+                }
             }
         }
+        Ok(())
+    }
+}
+
+/// `user@host`, so CAs from different machines can be told apart in trust stores
+fn owner() -> String {
+    let user = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| "beam".to_string());
+    let host = fs::read_to_string("/etc/hostname")
+        .ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty());
+    match host {
+        Some(host) => format!("{}@{}", user, host),
+        None => user,
+    }
+}
+
+/// Create a directory only the owner can access (0700 on Unix); directories
+/// that already exist keep the permissions their owner gave them
+pub fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+/// Write a file only the owner can read (0600 on Unix), also when it already
+/// existed with a wider mode
+pub fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+
+    // Restrict the file itself before emptying and refilling it
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.set_len(0)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Leaf certificate with its key and expiry
+pub struct IssuedCert {
+    /// Leaf first, then the CA
+    pub chain: Vec<RustlsCertificate>,
+    pub key: PrivateKey,
+    pub not_after: OffsetDateTime,
+}
+
+impl IssuedCert {
+    /// Whether the certificate is expired or close enough to expiry to replace
+    pub fn needs_renewal(&self) -> bool {
+        self.not_after - OffsetDateTime::now_utc() < RENEW_BEFORE
     }
-    
-    // Generate new certificate
-    info!("Generating new self-signed certificate for {}", domain);
-    let (cert_der, key_der) = generate_self_signed_cert(domain)?;
-    
-    // Save certificate if directory is provided
-    if let Some(dir) = cert_dir {
-        if !dir.exists() {
-            fs::create_dir_all(dir)?;
+}
+
+/// Metadata stored next to each certificate
+#[derive(Debug, Serialize, Deserialize)]
+struct CertMetadata {
+    domain: String,
+    /// RFC 3339 expiry
+    not_after: String,
+    /// SHA-256 of the issuing CA certificate, so certs from a replaced CA are not reused
+    issuer_sha256: String,
+}
+
+/// Where issued certificates are kept unless overridden
+pub fn default_cert_dir() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("beam")
+        .join("certs")
+}
+
+/// Issued certificates persisted on disk, reissued when close to expiry
+pub struct CertStore {
+    ca: Arc<CertificateAuthority>,
+    dir: PathBuf,
+}
+
+impl CertStore {
+    pub fn new(ca: Arc<CertificateAuthority>, dir: PathBuf) -> Self {
+        CertStore { ca, dir }
+    }
+
+    fn paths(&self, domain: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let stem = domain.replace('.', "_");
+        (
+            self.dir.join(format!("{}.cert", stem)),
+            self.dir.join(format!("{}.key", stem)),
+            self.dir.join(format!("{}.json", stem)),
+        )
+    }
+
+    /// Load the stored certificate for `domain`, issuing a new one if missing or due for renewal
+    pub fn get_or_issue(&self, domain: &str) -> Result<IssuedCert, Box<dyn std::error::Error>> {
+        match self.load(domain) {
+            Ok(Some(cert)) if !cert.needs_renewal() => {
+                info!("Loaded certificate for {} (valid until {})", domain, cert.not_after);
+                return Ok(cert);
+            }
+            Ok(Some(_)) => info!("Certificate for {} is due for renewal", domain),
+            Ok(None) => {}
+            Err(e) => warn!("Ignoring unreadable certificate for {}: {}", domain, e),
         }
-        fs::write(&cert_path, &cert_der)?;
-        fs::write(&key_path, &key_der)?;
+        self.renew(domain)
+    }
+
+    /// Issue and store a new certificate for `domain`
+    pub fn renew(&self, domain: &str) -> Result<IssuedCert, Box<dyn std::error::Error>> {
+        info!("Issuing certificate for {} from the local CA", domain);
+        let cert = self.ca.issue(domain)?;
+
+        let (cert_path, key_path, meta_path) = self.paths(domain);
+        let metadata = CertMetadata {
+            domain: domain.to_string(),
+            not_after: cert.not_after.format(&Rfc3339)?,
+            issuer_sha256: self.ca.fingerprint()?,
+        };
+        create_private_dir(&self.dir)?;
+        write_private(&key_path, &cert.key.0)?;
+        fs::write(&cert_path, &cert.chain[0].0)?;
+        fs::write(&meta_path, serde_json::to_vec_pretty(&metadata)?)?;
         info!("Saved certificate to {:?}", cert_path);
+
+        Ok(cert)
+    }
+
+    /// Stored certificate, if there is one from the current CA
+    fn load(&self, domain: &str) -> Result<Option<IssuedCert>, Box<dyn std::error::Error>> {
+        let (cert_path, key_path, meta_path) = self.paths(domain);
+        if !(cert_path.exists() && key_path.exists() && meta_path.exists()) {
+            return Ok(None);
+        }
+
+        let metadata: CertMetadata = serde_json::from_slice(&fs::read(&meta_path)?)?;
+        if metadata.issuer_sha256 != self.ca.fingerprint()? {
+            info!("Stored certificate for {} is from another CA", domain);
+            return Ok(None);
+        }
+
+        Ok(Some(IssuedCert {
+            chain: vec![RustlsCertificate(fs::read(&cert_path)?), RustlsCertificate(self.ca.cert_der()?)],
+            key: PrivateKey(fs::read(&key_path)?),
+            not_after: OffsetDateTime::parse(&metadata.not_after, &Rfc3339)?,
+        }))
+    }
+}
+
+/// Load a PEM certificate chain and private key (PKCS#8, RSA or EC), checking
+/// that the key matches, every certificate is valid now, each one is signed by
+/// the next, and the leaf covers `domain`
+pub fn load_pem_files(cert_path: &Path, key_path: &Path, domain: &str) -> Result<IssuedCert, Box<dyn std::error::Error>> {
+    let file = File::open(cert_path).map_err(|e| format!("Cannot read {}: {}", cert_path.display(), e))?;
+    let chain: Vec<RustlsCertificate> = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("Invalid PEM in {}: {}", cert_path.display(), e))?
+        .into_iter()
+        .map(RustlsCertificate)
+        .collect();
+    if chain.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path.display()).into());
+    }
+    let key = read_pem_key(key_path)?;
+
+    let parsed = chain
+        .iter()
+        .map(|cert| x509_parser::parse_x509_certificate(&cert.0).map(|(_, parsed)| parsed))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate in {}: {}", cert_path.display(), e))?;
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    for cert in &parsed {
+        let validity = cert.validity();
+        if now > validity.not_after.timestamp() {
+            return Err(format!("Certificate {} in {} expired on {}", cert.subject(), cert_path.display(), validity.not_after).into());
+        }
+        if now < validity.not_before.timestamp() {
+            return Err(format!("Certificate {} in {} is not valid until {}", cert.subject(), cert_path.display(), validity.not_before).into());
+        }
+    }
+    for pair in parsed.windows(2) {
+        let (cert, issuer) = (&pair[0], &pair[1]);
+        if cert.issuer() != issuer.subject() || cert.verify_signature(Some(issuer.public_key())).is_err() {
+            return Err(format!(
+                "Certificate chain in {} is broken: {} is not signed by {}",
+                cert_path.display(),
+                cert.subject(),
+                issuer.subject()
+            )
+            .into());
+        }
+    }
+
+    let leaf = webpki::EndEntityCert::try_from(chain[0].0.as_slice())
+        .map_err(|e| format!("Unsupported certificate in {}: {:?}", cert_path.display(), e))?;
+    let name = webpki::DnsNameRef::try_from_ascii_str(domain).map_err(|_| format!("Invalid domain name: {}", domain))?;
+    if leaf.verify_is_valid_for_subject_name(webpki::SubjectNameRef::DnsName(name)).is_err() {
+        return Err(format!(
+            "Certificate in {} is not valid for {} (it covers: {})",
+            cert_path.display(),
+            domain,
+            dns_names(&parsed[0]).join(", ")
+        )
+        .into());
+    }
+    check_key_matches(&leaf, &key).map_err(|e| {
+        format!("Private key in {} does not match the certificate in {}: {}", key_path.display(), cert_path.display(), e)
+    })?;
+
+    let not_after = OffsetDateTime::from_unix_timestamp(parsed[0].validity().not_after.timestamp())?;
+    info!("Loaded certificate for {} from {:?} (valid until {})", domain, cert_path, not_after);
+    Ok(IssuedCert { chain, key, not_after })
+}
+
+/// First private key in a PEM file
+fn read_pem_key(path: &Path) -> Result<PrivateKey, Box<dyn std::error::Error>> {
+    let file = File::open(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| format!("Invalid PEM in {}: {}", path.display(), e))?;
+    for item in items {
+        if let Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) = item {
+            return Ok(PrivateKey(der));
+        }
+    }
+    Err(format!("No private key found in {} (expected PKCS#8, RSA or EC PEM)", path.display()).into())
+}
+
+/// Sign a probe with the key and verify it with the certificate's public key
+fn check_key_matches(leaf: &webpki::EndEntityCert, key: &PrivateKey) -> Result<(), Box<dyn std::error::Error>> {
+    let signing_key = sign::any_supported_type(key).map_err(|_| "unsupported key type")?;
+    let signer = signing_key.choose_scheme(KEY_CHECK_SCHEMES).ok_or("unsupported key type")?;
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+
+    let probe = b"beam certificate key check";
+    let signature = signer.sign(probe)?;
+    leaf.verify_signature(algorithm, probe, &signature).map_err(|_| "public keys differ".into())
+}
+
+/// DNS names a certificate was issued for, for error messages
+fn dns_names(cert: &X509Certificate) -> Vec<String> {
+    match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(dns.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => cert.subject().iter_common_name().filter_map(|cn| cn.as_str().ok()).map(String::from).collect(),
     }
-    
-    let cert = RustlsCertificate(cert_der);
-    let key = PrivateKey(key_der);
-    
-    Ok((vec![cert], key))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn test_ca_persists_and_signs_leaves() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca_dir = dir.path().join("ca");
 
+        let ca = CertificateAuthority::load_or_create(&ca_dir).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(ca_dir.join(CA_KEY_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Reloading keeps the same CA certificate
+        let reloaded = CertificateAuthority::load_or_create(&ca_dir).unwrap();
+        assert_eq!(reloaded.cert_pem(), ca.cert_pem());
+
+        // Leaves from the reloaded CA chain up to the stored root
+        let chain = reloaded.issue("myapp.local").unwrap().chain;
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[1].0, ca.cert_der().unwrap());
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&chain[1]).unwrap();
+        let verifier = rustls::client::WebPkiVerifier::new(roots, None);
+        for name in ["myapp.local", "tenant1.myapp.local"] {
+            rustls::client::ServerCertVerifier::verify_server_cert(
+                &verifier,
+                &chain[0],
+                &[],
+                &rustls::ServerName::try_from(name).unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+            .unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_permissions_only_touch_what_we_create() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // An existing directory chosen by the user keeps its mode
+        let existing = dir.path().join("shared");
+        fs::create_dir(&existing).unwrap();
+        fs::set_permissions(&existing, fs::Permissions::from_mode(0o755)).unwrap();
+        create_private_dir(&existing.join("certs")).unwrap();
+        assert_eq!(mode(&existing), 0o755);
+        assert_eq!(mode(&existing.join("certs")), 0o700);
+
+        // A key written over a world-readable file ends up private
+        let key = existing.join("key.pem");
+        fs::write(&key, "a much longer old key").unwrap();
+        fs::set_permissions(&key, fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&key, b"secret").unwrap();
+        assert_eq!(mode(&key), 0o600);
+        assert_eq!(fs::read(&key).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_cert_store_reuses_until_renewal() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = Arc::new(CertificateAuthority::load_or_create(&dir.path().join("ca")).unwrap());
+        let store = CertStore::new(Arc::clone(&ca), dir.path().join("certs"));
+
+        let first = store.get_or_issue("myapp.local").unwrap();
+        assert!(!first.needs_renewal());
+        let again = store.get_or_issue("myapp.local").unwrap();
+        assert_eq!(again.chain[0].0, first.chain[0].0);
+        assert_eq!(again.not_after, first.not_after);
+
+        // A certificate close to expiry is replaced
+        let (_, _, meta_path) = store.paths("myapp.local");
+        let mut metadata: CertMetadata = serde_json::from_slice(&fs::read(&meta_path).unwrap()).unwrap();
+        metadata.not_after = (OffsetDateTime::now_utc() + Duration::hours(1)).format(&Rfc3339).unwrap();
+        fs::write(&meta_path, serde_json::to_vec(&metadata).unwrap()).unwrap();
+        let renewed = store.get_or_issue("myapp.local").unwrap();
+        assert_ne!(renewed.chain[0].0, first.chain[0].0);
+        assert!(!renewed.needs_renewal());
+    }
+
+    /// Self-signed CA for provided-certificate tests
+    fn test_ca(name: &str) -> Certificate {
+        let mut params = CertificateParams::new(Vec::new());
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Certificate::from_params(params).unwrap()
+    }
+
+    /// Write a leaf signed by `ca` (followed by `chain_ca`) and its key as PEM
+    fn write_pem(dir: &Path, ca: &Certificate, chain_ca: &Certificate, not_after: OffsetDateTime) -> (PathBuf, PathBuf) {
+        let mut params = CertificateParams::new(vec!["myapp.local".to_string(), "*.myapp.local".to_string()]);
+        params.not_after = not_after;
+        let leaf = Certificate::from_params(params).unwrap();
+
+        let cert_path = dir.join("tls.crt");
+        let key_path = dir.join("tls.key");
+        let chain = leaf.serialize_pem_with_signer(ca).unwrap() + &chain_ca.serialize_pem().unwrap();
+        fs::write(&cert_path, chain).unwrap();
+        fs::write(&key_path, leaf.serialize_private_key_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn load_error(cert_path: &Path, key_path: &Path, domain: &str) -> String {
+        match load_pem_files(cert_path, key_path, domain) {
+            Ok(_) => panic!("{} loaded for {}", cert_path.display(), domain),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_load_pem_files_checks_chain_and_domain() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = test_ca("Team CA");
+        let next_month = OffsetDateTime::now_utc() + Duration::days(30);
+        let (cert_path, key_path) = write_pem(dir.path(), &ca, &ca, next_month);
+
+        let provided = load_pem_files(&cert_path, &key_path, "myapp.local").unwrap();
+        assert_eq!(provided.chain.len(), 2);
+        assert!(!provided.needs_renewal());
+        assert!(load_pem_files(&cert_path, &key_path, "tenant1.myapp.local").is_ok());
+
+        let err = load_error(&cert_path, &key_path, "other.local");
+        assert!(err.contains("not valid for other.local"), "{}", err);
+        assert!(err.contains("*.myapp.local"), "{}", err);
+
+        // The CA that follows the leaf must be the one that signed it
+        let (cert_path, key_path) = write_pem(dir.path(), &ca, &test_ca("Other CA"), next_month);
+        let err = load_error(&cert_path, &key_path, "myapp.local");
+        assert!(err.contains("chain"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_pem_files_rejects_mismatch_and_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = test_ca("Team CA");
+        let now = OffsetDateTime::now_utc();
+
+        let (cert_path, _) = write_pem(dir.path(), &ca, &ca, now + Duration::days(30));
+        let other_key = dir.path().join("other.key");
+        fs::write(&other_key, test_ca("Unrelated").serialize_private_key_pem()).unwrap();
+        let err = load_error(&cert_path, &other_key, "myapp.local");
+        assert!(err.contains("does not match"), "{}", err);
+
+        let (cert_path, key_path) = write_pem(dir.path(), &ca, &ca, now - Duration::days(1));
+        let err = load_error(&cert_path, &key_path, "myapp.local");
+        assert!(err.contains("expired on"), "{}", err);
+
+        let err = load_error(&cert_path, &cert_path, "myapp.local");
+        assert!(err.contains("No private key"), "{}", err);
+    }
+}
@@ -1,7 +1,7 @@
 use std::net::IpAddr;
 use tracing::debug;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum AccessContext {
     LocalBrowser,
     WebhookService,
@@ -9,6 +9,23 @@ pub enum AccessContext {
     ExternalAccess,
 }
 
+impl std::str::FromStr for AccessContext {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "local-browser" | "local" | "browser" => Ok(AccessContext::LocalBrowser),
+            "webhook" | "webhook-service" => Ok(AccessContext::WebhookService),
+            "api-client" | "api" => Ok(AccessContext::APIClient),
+            "external" | "external-access" => Ok(AccessContext::ExternalAccess),
+            _ => Err(format!(
+                "Invalid access context '{}'. Use: local-browser, webhook, api-client, or external",
+                s
+            )),
+        }
+    }
+}
+
 pub struct ContextDetector;
 
 impl ContextDetector {
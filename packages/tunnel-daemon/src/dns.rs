@@ -1,11 +1,17 @@
+use ipnet::IpNet;
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::str::FromStr;
 use tracing::{info, warn};
 
+use crate::hosts::HostsFile;
+
 #[derive(Clone)]
 pub struct DualDNSResolver {
     local_domain_map: HashMap<String, IpAddr>,
     tor_domain_map: HashMap<String, String>, // domain -> onion address
+    hosts_file: HostsFile,
+    context_subnets: Vec<(IpNet, ResolutionContext)>,
 }
 
 
@@ -14,104 +20,215 @@ impl DualDNSResolver {
         DualDNSResolver {
             local_domain_map: HashMap::new(),
             tor_domain_map: HashMap::new(),
+            hosts_file: HostsFile::system(),
+            context_subnets: Vec::new(),
         }
     }
 
     pub async fn configure_dual_resolution(&mut self, domain: &str, onion_address: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Map domain to localhost for local resolution
+        // Map domain to localhost for local resolution (the DNS server gives
+        // LAN queriers the host's LAN address instead)
         let localhost: IpAddr = "127.0.0.1".parse()?;
-        self.local_domain_map.insert(domain.to_string(), localhost);
+        self.local_domain_map.insert(domain.to_lowercase(), localhost);
 
         // Map domain to onion address for external resolution
-        self.tor_domain_map.insert(domain.to_string(), onion_address.to_string());
+        self.tor_domain_map.insert(domain.to_lowercase(), onion_address.to_string());
 
 
         info!("Configured dual resolution for {}: local=127.0.0.1, tor={}", domain, onion_address);
         Ok(())
     }
 
+    /// Map a domain to a local address without an onion counterpart
+    pub fn add_local_domain(&mut self, domain: &str, ip: IpAddr) {
+        self.local_domain_map.insert(domain.to_lowercase(), ip);
+    }
+
+    /// Use a different hosts file than the system one
+    pub fn set_hosts_file(&mut self, hosts_file: HostsFile) {
+        self.hosts_file = hosts_file;
+    }
+
     pub async fn setup_local_dns_override(&self, domain: &str) -> Result<(), Box<dyn std::error::Error>> {
         // Modify hosts file for local resolution
-        self.add_to_hosts_file(domain, "127.0.0.1").await?;
-        info!("Added {} → 127.0.0.1 to hosts file", domain);
+        // Note: This requires elevated privileges on most systems
+        match self.hosts_file.add_entry(IpAddr::from([127, 0, 0, 1]), domain).await {
+            Ok(true) => info!("Added {} → 127.0.0.1 to {}", domain, self.hosts_file.path().display()),
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Failed to write to hosts file (requires admin privileges): {}", e);
+                warn!("Local DNS resolution may not work properly");
+            }
+        }
         Ok(())
     }
 
+    /// Remove the hosts-file entries added by this process
+    pub async fn remove_local_dns_override(&self) {
+        match self.hosts_file.remove_entries().await {
+            Ok(true) => info!("Removed Beam entries from {}", self.hosts_file.path().display()),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to remove Beam entries from hosts file: {}", e),
+        }
+    }
+
+    /// Domains with a local mapping
+    pub fn local_domains(&self) -> impl Iterator<Item = &String> {
+        self.local_domain_map.keys()
+    }
+
+    /// Address for a domain or any of its subdomains (`*.domain`)
     pub fn resolve_local(&self, domain: &str) -> Option<&IpAddr> {
-        self.local_domain_map.get(domain)
+        lookup_wildcard(&self.local_domain_map, domain)
     }
 
+    /// Onion address for a domain or any of its subdomains (`*.domain`)
     pub fn resolve_tor(&self, domain: &str) -> Option<&String> {
-        self.tor_domain_map.get(domain)
-    }
-
-    async fn add_to_hosts_file(&self, domain: &str, ip: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let hosts_path = if cfg!(target_os = "windows") {
-            "C:\\Windows\\System32\\drivers\\etc\\hosts"
-        } else {
-            "/etc/hosts"
-        };
-
-        // Read current hosts file
-        let content = match tokio::fs::read_to_string(hosts_path).await {
-            Ok(content) => content,
-            Err(_) => String::new(),
-        };
-
-        // Check if entry already exists
-        let entry = format!("{} {}", ip, domain);
-        if content.contains(&entry) {
-            return Ok(());
-        }
+        lookup_wildcard(&self.tor_domain_map, domain)
+    }
 
-        // Remove any existing entries for this domain
-        let lines: Vec<String> = content
-            .lines()
-            .filter(|line| !line.contains(domain))
-            .map(|s| s.to_string())
-            .collect();
+    /// Give queriers from `net` the answers for `context` (most specific subnet wins)
+    pub fn add_context_subnet(&mut self, net: IpNet, context: ResolutionContext) {
+        self.context_subnets.push((net, context));
+    }
 
-        let mut new_content = lines.join("\n");
+    /// View for a querier: configured subnets first, then LAN clients are local and the rest remote
+    pub fn context_for(&self, querier: IpAddr) -> ResolutionContext {
+        let querier = querier.to_canonical();
+        self.context_subnets
+            .iter()
+            .filter(|(net, _)| net.contains(&querier))
+            .max_by_key(|(net, _)| net.prefix_len())
+            .map(|(_, context)| *context)
+            .unwrap_or(if is_lan_address(querier) { ResolutionContext::Local } else { ResolutionContext::Tor })
+    }
 
-        // Add Beam section if it doesn't exist
-        if !new_content.contains("# Beam local domains") {
-            new_content.push_str("\n# Beam local domains\n");
+    pub fn resolve_with_context(&self, domain: &str, context: ResolutionContext) -> Option<String> {
+        match context {
+            ResolutionContext::Local => {
+                self.resolve_local(domain).map(|ip| ip.to_string())
+            }
+            ResolutionContext::Tor => {
+                self.resolve_tor(domain).cloned()
+            }
         }
+    }
+}
 
-        // Add the entry
-        new_content.push_str(&entry);
-        new_content.push_str("\n");
+/// Look up a domain, falling back to each parent domain so `*.domain` matches too
+fn lookup_wildcard<'a, V>(map: &'a HashMap<String, V>, domain: &str) -> Option<&'a V> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let mut name = domain.as_str();
 
-        // Write back to hosts file
-        // Note: This requires elevated privileges on most systems
-        match tokio::fs::write(hosts_path, new_content).await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                warn!("Failed to write to hosts file (requires admin privileges): {}", e);
-                warn!("Local DNS resolution may not work properly");
-                Ok(()) // Don't fail, just warn
-            }
+    loop {
+        if let Some(value) = map.get(name) {
+            return Some(value);
         }
+        name = name.split_once('.')?.1;
+    }
+}
+
+/// Subdomain part of `host` (port allowed) if it is strictly below `domain`, e.g. `tenant1`
+pub fn subdomain_of<'a>(host: &'a str, domain: &str) -> Option<&'a str> {
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let host = host.trim_end_matches('.');
+
+    let suffix_start = host.len().checked_sub(domain.len())?;
+    if !host.is_char_boundary(suffix_start) || !host[suffix_start..].eq_ignore_ascii_case(domain) {
+        return None;
     }
+    host[..suffix_start].strip_suffix('.').filter(|s| !s.is_empty())
 }
 
-#[derive(Debug, Clone)]
+/// Which answer a querier gets for a Beam domain (split-horizon DNS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResolutionContext {
+    /// Loopback and LAN clients: the local address
     Local,
+    /// Tor-facing and remote clients: the onion name
     Tor,
 }
 
-impl DualDNSResolver {
-    pub fn resolve_with_context(&self, domain: &str, context: ResolutionContext) -> Option<String> {
-        match context {
-            ResolutionContext::Local => {
-                self.resolve_local(domain).map(|ip| ip.to_string())
-            }
-            ResolutionContext::Tor => {
-                self.resolve_tor(domain).cloned()
-            }
+impl FromStr for ResolutionContext {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "local" | "lan" => Ok(ResolutionContext::Local),
+            "tor" | "onion" | "remote" => Ok(ResolutionContext::Tor),
+            _ => Err(format!("Invalid resolution context '{}'. Use: local or tor", s)),
+        }
+    }
+}
+
+/// Parse a `CIDR=local|tor` rule, e.g. `100.64.0.0/10=tor`
+pub fn parse_view_rule(rule: &str) -> Result<(IpNet, ResolutionContext), String> {
+    let (net, context) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid DNS view rule '{}'. Expected CIDR=local|tor", rule))?;
+    let net = net.trim();
+    let net = net
+        .parse::<IpNet>()
+        .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("Invalid IP address or CIDR range '{}'", net))?;
+
+    Ok((net.trunc(), context.parse()?))
+}
+
+/// Loopback, private and link-local addresses
+fn is_lan_address(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || v6.segments()[0] & 0xfe00 == 0xfc00 // unique local fc00::/7
+                || v6.segments()[0] & 0xffc0 == 0xfe80 // link-local fe80::/10
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_subdomains() {
+        let mut resolver = DualDNSResolver::new();
+        resolver.add_local_domain("MyApp.local", IpAddr::from([127, 0, 0, 1]));
+
+        assert!(resolver.resolve_local("myapp.local").is_some());
+        assert!(resolver.resolve_local("tenant1.myapp.local.").is_some());
+        assert!(resolver.resolve_local("a.b.MYAPP.local").is_some());
+        assert!(resolver.resolve_local("notmyapp.local").is_none());
+        assert!(resolver.resolve_local("local").is_none());
+
+        assert_eq!(subdomain_of("tenant1.myapp.local:8080", "myapp.local"), Some("tenant1"));
+        assert_eq!(subdomain_of("a.b.myapp.local", "myapp.local"), Some("a.b"));
+        assert_eq!(subdomain_of("myapp.local", "myapp.local"), None);
+        assert_eq!(subdomain_of("xmyapp.local", "myapp.local"), None);
+    }
 
+    #[test]
+    fn test_context_by_subnet() {
+        let mut resolver = DualDNSResolver::new();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert_eq!(resolver.context_for(ip("127.0.0.1")), ResolutionContext::Local);
+        assert_eq!(resolver.context_for(ip("192.168.1.20")), ResolutionContext::Local);
+        assert_eq!(resolver.context_for(ip("fe80::1")), ResolutionContext::Local);
+        assert_eq!(resolver.context_for(ip("203.0.113.5")), ResolutionContext::Tor);
+
+        let (net, context) = parse_view_rule("192.168.0.0/16=tor").unwrap();
+        resolver.add_context_subnet(net, context);
+        let (net, context) = parse_view_rule("192.168.1.0/24 = local").unwrap();
+        resolver.add_context_subnet(net, context);
+        assert_eq!(resolver.context_for(ip("192.168.2.9")), ResolutionContext::Tor);
+        assert_eq!(resolver.context_for(ip("::ffff:192.168.1.9")), ResolutionContext::Local);
+
+        assert!(parse_view_rule("10.0.0.0/8").is_err());
+        assert!(parse_view_rule("10.0.0.0/8=elsewhere").is_err());
+    }
+}
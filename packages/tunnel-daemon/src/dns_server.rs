@@ -0,0 +1,388 @@
+//! DNS Server Module
+//!
+//! Authoritative DNS server for Beam domains on `--dns-port`. Answers A/AAAA
+//! from the resolver's local domain map and TXT with the onion address from
+//! its Tor domain map. Answers are split-horizon: loopback mappings are given
+//! to LAN queriers as the host's LAN address, and queriers in the Tor view
+//! get a CNAME to the onion name instead of the local address. Other names
+//! get NXDOMAIN, or are forwarded to an upstream resolver when one is
+//! configured. Pending ACME DNS-01 challenges are answered with their TXT
+//! records.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{debug, error, info, warn};
+use trust_dns_proto::op::{Header, MessageType, OpCode, ResponseCode};
+use trust_dns_proto::rr::rdata::TXT;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+use trust_dns_server::authority::MessageResponseBuilder;
+use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+use trust_dns_server::ServerFuture;
+
+use crate::acme::ChallengeResponses;
+use crate::dns::{DualDNSResolver, ResolutionContext};
+use crate::mdns::LanAddresses;
+
+/// TTL of records served for Beam domains (short, since tunnels come and go)
+const RECORD_TTL: u32 = 60;
+
+/// Idle timeout for DNS-over-TCP connections
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Result of looking a name up in the Beam zone
+#[derive(Debug, PartialEq)]
+pub enum Answer {
+    /// The name is a Beam domain; these are its records of the requested type
+    Records(Vec<Record>),
+    /// The name is a Beam domain but has no records of the requested type
+    NoData,
+    /// The name is not a Beam domain
+    NotOurs,
+}
+
+/// Look up a name and record type among the resolver's Beam domains, as seen
+/// from `context`. `lan` is set for queriers off this host, which are given
+/// these addresses instead of a loopback mapping.
+pub fn authoritative_answer(
+    resolver: &DualDNSResolver,
+    name: &Name,
+    record_type: RecordType,
+    context: ResolutionContext,
+    lan: Option<&LanAddresses>,
+) -> Answer {
+    let domain = name.to_ascii().trim_end_matches('.').to_lowercase();
+    let local = resolver.resolve_local(&domain).copied();
+    let onion = resolver.resolve_tor(&domain);
+    if local.is_none() && onion.is_none() {
+        return Answer::NotOurs;
+    }
+
+    let wants = |t: RecordType| record_type == t || record_type == RecordType::ANY;
+    let mut records = Vec::new();
+
+    // Remote queriers can't reach the local address; point them at the onion name
+    if context == ResolutionContext::Tor {
+        if let Some(onion) = resolver.resolve_with_context(&domain, context) {
+            return onion_answer(name, &onion, record_type);
+        }
+    }
+
+    let (v4, v6) = match (local, lan) {
+        // Another host's loopback is not ours; point LAN queriers at our interfaces
+        (Some(ip), Some(lan)) if ip.is_loopback() => (lan.v4, lan.v6),
+        // Loopback mappings are reachable over IPv6 loopback too
+        (Some(IpAddr::V4(ip)), _) => (Some(ip), ip.is_loopback().then_some(Ipv6Addr::LOCALHOST)),
+        (Some(IpAddr::V6(ip)), _) => (None, Some(ip)),
+        (None, _) => (None, None),
+    };
+    if let Some(ip) = v4.filter(|_| wants(RecordType::A)) {
+        records.push(Record::from_rdata(name.clone(), RECORD_TTL, RData::A(ip)));
+    }
+    if let Some(ip) = v6.filter(|_| wants(RecordType::AAAA)) {
+        records.push(Record::from_rdata(name.clone(), RECORD_TTL, RData::AAAA(ip)));
+    }
+
+    if let Some(onion) = onion {
+        if wants(RecordType::TXT) {
+            let txt = TXT::new(vec![format!("onion={}", onion)]);
+            records.push(Record::from_rdata(name.clone(), RECORD_TTL, RData::TXT(txt)));
+        }
+    }
+
+    if records.is_empty() { Answer::NoData } else { Answer::Records(records) }
+}
+
+/// Tor view: CNAME to the onion name for address queries, TXT with the onion
+/// address. A CNAME owner can hold no other data, so ANY gets the CNAME alone.
+fn onion_answer(name: &Name, onion: &str, record_type: RecordType) -> Answer {
+    let mut records = Vec::new();
+
+    if matches!(record_type, RecordType::A | RecordType::AAAA | RecordType::CNAME | RecordType::ANY) {
+        if let Ok(target) = Name::from_ascii(format!("{}.", onion)) {
+            records.push(Record::from_rdata(name.clone(), RECORD_TTL, RData::CNAME(target)));
+        }
+    }
+    if record_type == RecordType::TXT {
+        let txt = TXT::new(vec![format!("onion={}", onion)]);
+        records.push(Record::from_rdata(name.clone(), RECORD_TTL, RData::TXT(txt)));
+    }
+
+    if records.is_empty() { Answer::NoData } else { Answer::Records(records) }
+}
+
+/// TXT records of pending ACME DNS-01 challenges for `name`, if any
+pub fn challenge_answer(challenges: &ChallengeResponses, name: &Name, record_type: RecordType) -> Option<Answer> {
+    let values = challenges.dns_txt(&name.to_ascii());
+    if values.is_empty() {
+        return None;
+    }
+    if !matches!(record_type, RecordType::TXT | RecordType::ANY) {
+        return Some(Answer::NoData);
+    }
+
+    // TTL 0 so validators never see a stale token
+    let records = values
+        .into_iter()
+        .map(|value| Record::from_rdata(name.clone(), 0, RData::TXT(TXT::new(vec![value]))))
+        .collect();
+    Some(Answer::Records(records))
+}
+
+/// Request handler serving the Beam zone
+pub struct BeamDnsHandler {
+    /// Beam domain mappings
+    resolver: Arc<DualDNSResolver>,
+
+    /// Resolver for names outside the Beam zone (NXDOMAIN if `None`)
+    upstream: Option<TokioAsyncResolver>,
+
+    /// Pending ACME DNS-01 challenges
+    acme: Option<Arc<ChallengeResponses>>,
+
+    /// This host's LAN addresses, given to queriers on other hosts
+    lan_addresses: LanAddresses,
+}
+
+impl BeamDnsHandler {
+    pub fn new(resolver: Arc<DualDNSResolver>) -> Self {
+        BeamDnsHandler { resolver, upstream: None, acme: None, lan_addresses: LanAddresses::default() }
+    }
+
+    /// Answer queriers on other hosts with these addresses for loopback mappings
+    pub fn set_lan_addresses(&mut self, addresses: LanAddresses) {
+        self.lan_addresses = addresses;
+    }
+
+    /// Answer ACME DNS-01 challenges from these responses
+    pub fn set_acme_challenges(&mut self, challenges: Arc<ChallengeResponses>) {
+        self.acme = Some(challenges);
+    }
+
+    /// Forward queries for other names to the given DNS server
+    pub fn set_upstream(&mut self, upstream: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+        let servers = NameServerConfigGroup::from_ips_clear(&[upstream.ip()], upstream.port(), true);
+        let config = ResolverConfig::from_parts(None, vec![], servers);
+        self.upstream = Some(TokioAsyncResolver::tokio(config, ResolverOpts::default())?);
+        Ok(())
+    }
+
+    /// Resolve a name outside the Beam zone through the upstream
+    async fn forward(&self, name: &Name, record_type: RecordType) -> Result<Vec<Record>, ResponseCode> {
+        let upstream = match self.upstream {
+            Some(ref upstream) => upstream,
+            None => return Err(ResponseCode::NXDomain),
+        };
+
+        match upstream.lookup(name.clone(), record_type).await {
+            Ok(lookup) => Ok(lookup.records().to_vec()),
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { response_code, .. } if *response_code == ResponseCode::NoError => {
+                    Ok(Vec::new())
+                }
+                ResolveErrorKind::NoRecordsFound { response_code, .. } => Err(*response_code),
+                _ => {
+                    debug!("Upstream lookup of {} {} failed: {}", name, record_type, e);
+                    Err(ResponseCode::ServFail)
+                }
+            },
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for BeamDnsHandler {
+    async fn handle_request<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) -> ResponseInfo {
+        let builder = MessageResponseBuilder::from_message_request(request);
+
+        if request.op_code() != OpCode::Query || request.message_type() != MessageType::Query {
+            let response = builder.error_msg(request.header(), ResponseCode::NotImp);
+            return send(&mut response_handle, response).await;
+        }
+
+        let query = request.query();
+        let name = Name::from(query.name().clone());
+        let record_type = query.query_type();
+        let context = self.resolver.context_for(request.src().ip());
+        let lan = (!request.src().ip().to_canonical().is_loopback()).then_some(&self.lan_addresses);
+        debug!("DNS query from {} ({:?} view): {} {}", request.src(), context, name, record_type);
+
+        let mut header = Header::response_from_request(request.header());
+        let answer = self
+            .acme
+            .as_ref()
+            .and_then(|challenges| challenge_answer(challenges, &name, record_type))
+            .unwrap_or_else(|| authoritative_answer(&self.resolver, &name, record_type, context, lan));
+        let records = match answer {
+            Answer::Records(records) => {
+                header.set_authoritative(true);
+                records
+            }
+            Answer::NoData => {
+                header.set_authoritative(true);
+                Vec::new()
+            }
+            Answer::NotOurs => match self.forward(&name, record_type).await {
+                Ok(records) => {
+                    header.set_recursion_available(true);
+                    records
+                }
+                Err(code) => {
+                    let response = builder.error_msg(request.header(), code);
+                    return send(&mut response_handle, response).await;
+                }
+            },
+        };
+
+        let response = builder.build(header, records.iter(), None, None, None);
+        send(&mut response_handle, response).await
+    }
+}
+
+async fn send<'a, R: ResponseHandler>(
+    response_handle: &mut R,
+    response: trust_dns_server::authority::MessageResponse<
+        '_,
+        'a,
+        impl Iterator<Item = &'a Record> + Send + 'a,
+        impl Iterator<Item = &'a Record> + Send + 'a,
+        impl Iterator<Item = &'a Record> + Send + 'a,
+        impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+) -> ResponseInfo {
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Failed to send DNS response: {}", e);
+            let mut header = Header::new();
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        }
+    }
+}
+
+/// Bind UDP and TCP on `addr` and serve the Beam zone in the background
+pub async fn start(addr: SocketAddr, handler: BeamDnsHandler) -> Result<tokio::task::JoinHandle<()>, Box<dyn std::error::Error>> {
+    let mut server = ServerFuture::new(handler);
+    server.register_socket(bind_udp(addr)?);
+    server.register_listener(TcpListener::bind(addr).await?, TCP_TIMEOUT);
+
+    info!("DNS server listening on {} (udp/tcp)", addr);
+    Ok(tokio::spawn(async move {
+        if let Err(e) = server.block_until_done().await {
+            warn!("DNS server stopped: {}", e);
+        }
+    }))
+}
+
+/// Bind a UDP socket that can share its port with mDNS responders (5353 is the mDNS port)
+fn bind_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: ResolutionContext = ResolutionContext::Local;
+
+    async fn resolver() -> DualDNSResolver {
+        let mut resolver = DualDNSResolver::new();
+        resolver
+            .configure_dual_resolution("myapp.local", "abcdefghijklmnop.onion")
+            .await
+            .unwrap();
+        resolver
+    }
+
+    fn name(s: &str) -> Name {
+        Name::from_ascii(s).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_beam_domain_records() {
+        let resolver = resolver().await;
+
+        match authoritative_answer(&resolver, &name("MyApp.local."), RecordType::A, LOCAL, None) {
+            Answer::Records(records) => assert_eq!(records[0].data(), Some(&RData::A([127, 0, 0, 1].into()))),
+            other => panic!("expected A record, got {:?}", other),
+        }
+
+        match authoritative_answer(&resolver, &name("myapp.local."), RecordType::AAAA, LOCAL, None) {
+            Answer::Records(records) => assert_eq!(records[0].data(), Some(&RData::AAAA(Ipv6Addr::LOCALHOST))),
+            other => panic!("expected AAAA record, got {:?}", other),
+        }
+
+        match authoritative_answer(&resolver, &name("myapp.local."), RecordType::TXT, LOCAL, None) {
+            Answer::Records(records) => match records[0].data() {
+                Some(RData::TXT(txt)) => assert_eq!(&*txt.txt_data()[0], b"onion=abcdefghijklmnop.onion"),
+                other => panic!("expected TXT data, got {:?}", other),
+            },
+            other => panic!("expected TXT record, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nodata_and_other_names() {
+        let resolver = resolver().await;
+        assert!(matches!(authoritative_answer(&resolver, &name("tenant1.myapp.local."), RecordType::A, LOCAL, None), Answer::Records(_)));
+        assert_eq!(authoritative_answer(&resolver, &name("myapp.local."), RecordType::MX, LOCAL, None), Answer::NoData);
+        assert_eq!(authoritative_answer(&resolver, &name("other.local."), RecordType::A, LOCAL, None), Answer::NotOurs);
+    }
+
+    #[tokio::test]
+    async fn test_tor_view_gets_onion_cname() {
+        let resolver = resolver().await;
+
+        match authoritative_answer(&resolver, &name("myapp.local."), RecordType::A, ResolutionContext::Tor, None) {
+            Answer::Records(records) => {
+                assert_eq!(records.len(), 1);
+                assert_eq!(records[0].data(), Some(&RData::CNAME(name("abcdefghijklmnop.onion."))));
+            }
+            other => panic!("expected CNAME record, got {:?}", other),
+        }
+        assert_eq!(authoritative_answer(&resolver, &name("myapp.local."), RecordType::MX, ResolutionContext::Tor, None), Answer::NoData);
+
+        // Without an onion mapping every view gets the local address
+        let mut local_only = DualDNSResolver::new();
+        local_only.add_local_domain("fast.local", [127, 0, 0, 1].into());
+        assert!(matches!(
+            authoritative_answer(&local_only, &name("fast.local."), RecordType::A, ResolutionContext::Tor, None),
+            Answer::Records(_)
+        ));
+
+        // A CNAME owner holds nothing else, so ANY gets the CNAME alone
+        match authoritative_answer(&resolver, &name("myapp.local."), RecordType::ANY, ResolutionContext::Tor, None) {
+            Answer::Records(records) => {
+                assert_eq!(records.len(), 1);
+                assert_eq!(records[0].record_type(), RecordType::CNAME);
+            }
+            other => panic!("expected CNAME record, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lan_view_gets_lan_address() {
+        let resolver = resolver().await;
+        let lan = LanAddresses { v4: Some([192, 168, 1, 10].into()), v6: None };
+
+        match authoritative_answer(&resolver, &name("myapp.local."), RecordType::A, LOCAL, Some(&lan)) {
+            Answer::Records(records) => {
+                assert_eq!(records.len(), 1);
+                assert_eq!(records[0].data(), Some(&RData::A([192, 168, 1, 10].into())));
+            }
+            other => panic!("expected A record, got {:?}", other),
+        }
+
+        // Never the host's loopback, even with no LAN address of that family
+        assert_eq!(authoritative_answer(&resolver, &name("myapp.local."), RecordType::AAAA, LOCAL, Some(&lan)), Answer::NoData);
+    }
+}
@@ -0,0 +1,237 @@
+//! Forwarding Headers Module
+//!
+//! Adds RFC 7239 `Forwarded` and the de-facto `X-Forwarded-*` headers to
+//! proxied requests so the local application can see the real client,
+//! scheme and host. Which headers are added is chosen per access context.
+
+use hyper::header::{HeaderMap, HeaderValue};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::context::AccessContext;
+use crate::mode::TunnelMode;
+
+const FORWARDED: &str = "forwarded";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Set of forwarding headers to add to a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForwardHeaderSet {
+    /// RFC 7239 `Forwarded`
+    pub forwarded: bool,
+
+    /// `X-Forwarded-For`
+    pub x_forwarded_for: bool,
+
+    /// `X-Forwarded-Proto`
+    pub x_forwarded_proto: bool,
+
+    /// `X-Forwarded-Host`
+    pub x_forwarded_host: bool,
+}
+
+impl ForwardHeaderSet {
+    /// Every forwarding header
+    pub const ALL: ForwardHeaderSet = ForwardHeaderSet {
+        forwarded: true,
+        x_forwarded_for: true,
+        x_forwarded_proto: true,
+        x_forwarded_host: true,
+    };
+
+    /// No forwarding headers
+    pub const NONE: ForwardHeaderSet = ForwardHeaderSet {
+        forwarded: false,
+        x_forwarded_for: false,
+        x_forwarded_proto: false,
+        x_forwarded_host: false,
+    };
+}
+
+impl FromStr for ForwardHeaderSet {
+    type Err = String;
+
+    /// Parse a comma-separated header list, or `all` / `none`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut set = ForwardHeaderSet::NONE;
+
+        for name in s.split(',').map(|n| n.trim().to_lowercase()) {
+            match name.as_str() {
+                "all" => set = ForwardHeaderSet::ALL,
+                "none" | "" => {}
+                FORWARDED => set.forwarded = true,
+                X_FORWARDED_FOR => set.x_forwarded_for = true,
+                X_FORWARDED_PROTO => set.x_forwarded_proto = true,
+                X_FORWARDED_HOST => set.x_forwarded_host = true,
+                _ => {
+                    return Err(format!(
+                        "Invalid forwarding header '{}'. Use: forwarded, x-forwarded-for, x-forwarded-proto, x-forwarded-host, all, or none",
+                        name
+                    ))
+                }
+            }
+        }
+
+        Ok(set)
+    }
+}
+
+/// Forwarding behaviour of the proxy
+#[derive(Debug, Clone)]
+pub struct ForwardingConfig {
+    /// Keep the client's original `Host` header instead of rewriting it to the upstream
+    pub preserve_host: bool,
+
+    /// Headers added when no per-context rule applies
+    default_headers: ForwardHeaderSet,
+
+    /// Per-context overrides
+    context_headers: HashMap<AccessContext, ForwardHeaderSet>,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        ForwardingConfig {
+            preserve_host: false,
+            default_headers: ForwardHeaderSet::ALL,
+            context_headers: HashMap::new(),
+        }
+    }
+}
+
+impl ForwardingConfig {
+    /// Create config for a specific mode
+    pub fn for_mode(mode: TunnelMode) -> Self {
+        match mode {
+            // Don't leak client addresses or hostnames in private mode
+            TunnelMode::Private => ForwardingConfig {
+                default_headers: ForwardHeaderSet::NONE,
+                ..Default::default()
+            },
+            TunnelMode::Fast | TunnelMode::Balanced => ForwardingConfig::default(),
+        }
+    }
+
+    /// Override the headers added for one access context
+    pub fn set_context_headers(&mut self, context: AccessContext, headers: ForwardHeaderSet) {
+        self.context_headers.insert(context, headers);
+    }
+
+    /// Headers to add for a request in the given context
+    pub fn headers_for(&self, context: AccessContext) -> ForwardHeaderSet {
+        self.context_headers
+            .get(&context)
+            .copied()
+            .unwrap_or(self.default_headers)
+    }
+}
+
+/// Parse a `<context>=<headers>` rule, e.g. `external=x-forwarded-proto,x-forwarded-host`
+pub fn parse_context_rule(rule: &str) -> Result<(AccessContext, ForwardHeaderSet), String> {
+    let (context, headers) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid forwarding rule '{}'. Expected <context>=<headers>", rule))?;
+
+    Ok((context.trim().parse()?, headers.parse()?))
+}
+
+/// Add the selected forwarding headers, appending to any set by upstream proxies
+pub fn apply_forwarding_headers(
+    headers: &mut HeaderMap,
+    set: ForwardHeaderSet,
+    client_ip: IpAddr,
+    proto: &str,
+    host: Option<&str>,
+) {
+    if set.forwarded {
+        let mut element = format!("for={};proto={}", forwarded_node(client_ip), proto);
+        if let Some(host) = host {
+            element.push_str(&format!(";host=\"{}\"", host));
+        }
+        append_list(headers, FORWARDED, &element);
+    }
+
+    if set.x_forwarded_for {
+        append_list(headers, X_FORWARDED_FOR, &client_ip.to_string());
+    }
+
+    if set.x_forwarded_proto {
+        if let Ok(value) = HeaderValue::from_str(proto) {
+            headers.insert(X_FORWARDED_PROTO, value);
+        }
+    }
+
+    if set.x_forwarded_host {
+        if let Some(value) = host.and_then(|h| HeaderValue::from_str(h).ok()) {
+            headers.insert(X_FORWARDED_HOST, value);
+        }
+    }
+}
+
+/// Format a node for the `Forwarded` header (IPv6 must be quoted and bracketed)
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("\"[{}]\"", v6),
+    }
+}
+
+/// Append an element to a comma-separated list header
+fn append_list(headers: &mut HeaderMap, name: &'static str, element: &str) {
+    let value = match headers.get(name).and_then(|h| h.to_str().ok()) {
+        Some(existing) if !existing.is_empty() => format!("{}, {}", existing, element),
+        _ => element.to_string(),
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_all_headers() {
+        let mut headers = HeaderMap::new();
+        apply_forwarding_headers(&mut headers, ForwardHeaderSet::ALL, "1.2.3.4".parse().unwrap(), "https", Some("myapp.local"));
+
+        assert_eq!(headers[FORWARDED], "for=1.2.3.4;proto=https;host=\"myapp.local\"");
+        assert_eq!(headers[X_FORWARDED_FOR], "1.2.3.4");
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[X_FORWARDED_HOST], "myapp.local");
+    }
+
+    #[test]
+    fn test_appends_to_existing_chain() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.1"));
+        apply_forwarding_headers(&mut headers, ForwardHeaderSet::ALL, "::1".parse().unwrap(), "http", None);
+
+        assert_eq!(headers[X_FORWARDED_FOR], "10.0.0.1, ::1");
+        assert_eq!(headers[FORWARDED], "for=\"[::1]\";proto=http");
+        assert!(!headers.contains_key(X_FORWARDED_HOST));
+    }
+
+    #[test]
+    fn test_context_rules() {
+        let (context, set) = parse_context_rule("external=x-forwarded-proto,x-forwarded-host").unwrap();
+        assert_eq!(context, AccessContext::ExternalAccess);
+        assert!(set.x_forwarded_proto && set.x_forwarded_host);
+        assert!(!set.forwarded && !set.x_forwarded_for);
+
+        let mut config = ForwardingConfig::for_mode(TunnelMode::Private);
+        assert_eq!(config.headers_for(AccessContext::LocalBrowser), ForwardHeaderSet::NONE);
+
+        config.set_context_headers(AccessContext::LocalBrowser, ForwardHeaderSet::ALL);
+        assert_eq!(config.headers_for(AccessContext::LocalBrowser), ForwardHeaderSet::ALL);
+
+        assert!(parse_context_rule("external").is_err());
+        assert!(parse_context_rule("nowhere=all").is_err());
+        assert!(parse_context_rule("external=x-real-ip").is_err());
+    }
+}
@@ -0,0 +1,457 @@
+//! HAR Module
+//!
+//! Converts captured exchanges to and from HTTP Archive (HAR 1.2) files so
+//! tunnel traffic can be attached to bug reports, opened in browser devtools
+//! and loaded back into the inspector for replay.
+
+use hyper::{StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::capture::{self, BodyEncoding, CapturedBody, CapturedExchange, CapturedHeader};
+
+/// HAR format version written by the exporter
+const HAR_VERSION: &str = "1.2";
+
+/// Client address recorded for imported entries that don't carry one
+const IMPORTED_CLIENT_ADDR: &str = "127.0.0.1:0";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Har {
+    pub log: HarLog,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HarLog {
+    pub version: String,
+    pub creator: HarCreator,
+    pub entries: Vec<HarEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HarCreator {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarEntry {
+    pub started_date_time: String,
+    pub time: f64,
+    pub request: HarRequest,
+    pub response: HarResponse,
+    #[serde(default)]
+    pub cache: serde_json::Value,
+    pub timings: HarTimings,
+
+    /// Address of the client that sent the request (Beam extension)
+    #[serde(rename = "_clientAddress", default, skip_serializing_if = "Option::is_none")]
+    pub client_address: Option<String>,
+
+    /// Detected access context (Beam extension)
+    #[serde(rename = "_context", default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<HarNameValue>,
+    #[serde(default)]
+    pub headers: Vec<HarNameValue>,
+    #[serde(default)]
+    pub query_string: Vec<HarNameValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<HarPostData>,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarPostData {
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub text: String,
+
+    /// `base64` for binary bodies (Beam extension; HAR 1.2 postData is text only)
+    #[serde(rename = "_encoding", default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub status_text: String,
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<HarNameValue>,
+    #[serde(default)]
+    pub headers: Vec<HarNameValue>,
+    pub content: HarContent,
+    #[serde(default, rename = "redirectURL")]
+    pub redirect_url: String,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarContent {
+    pub size: i64,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarNameValue {
+    pub name: String,
+    pub value: String,
+}
+
+/// Timings in milliseconds; -1 marks phases that were not measured
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HarTimings {
+    #[serde(default = "not_measured")]
+    pub blocked: f64,
+    #[serde(default = "not_measured")]
+    pub dns: f64,
+    #[serde(default = "not_measured")]
+    pub connect: f64,
+    pub send: f64,
+    pub wait: f64,
+    pub receive: f64,
+    #[serde(default = "not_measured")]
+    pub ssl: f64,
+}
+
+fn unknown_size() -> i64 {
+    -1
+}
+
+fn not_measured() -> f64 {
+    -1.0
+}
+
+/// Build a HAR log from captured exchanges (oldest first)
+pub fn export(exchanges: &[CapturedExchange]) -> Har {
+    Har {
+        log: HarLog {
+            version: HAR_VERSION.to_string(),
+            creator: HarCreator {
+                name: "beam".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            entries: exchanges.iter().map(export_entry).collect(),
+        },
+    }
+}
+
+fn export_entry(exchange: &CapturedExchange) -> HarEntry {
+    let host = exchange.host.as_deref().unwrap_or("localhost");
+    let url = format!("{}://{}{}", exchange.scheme, host, exchange.uri);
+
+    let query_string = exchange
+        .uri
+        .split_once('?')
+        .map(|(_, query)| {
+            form_urlencoded::parse(query.as_bytes())
+                .map(|(name, value)| HarNameValue { name: name.into_owned(), value: value.into_owned() })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let post_data = (exchange.request_body.size > 0).then(|| HarPostData {
+        mime_type: header_value(&exchange.request_headers, "content-type").unwrap_or_default(),
+        text: exchange.request_body.data.clone(),
+        encoding: (exchange.request_body.encoding == BodyEncoding::Base64).then(|| "base64".to_string()),
+    });
+
+    // Headers were ready after `wait`; the rest of the exchange was spent streaming the body
+    let wait = exchange.wait_ms;
+    let receive = (exchange.duration_ms - wait).max(0.0);
+
+    HarEntry {
+        started_date_time: format_timestamp(exchange.started_at_ms),
+        time: wait + receive,
+        request: HarRequest {
+            method: exchange.method.clone(),
+            url,
+            http_version: exchange.http_version.clone(),
+            cookies: Vec::new(),
+            headers: export_headers(&exchange.request_headers),
+            query_string,
+            post_data,
+            headers_size: -1,
+            body_size: exchange.request_body.size as i64,
+        },
+        response: HarResponse {
+            status: exchange.status,
+            status_text: StatusCode::from_u16(exchange.status)
+                .ok()
+                .and_then(|s| s.canonical_reason())
+                .unwrap_or_default()
+                .to_string(),
+            http_version: exchange.http_version.clone(),
+            cookies: Vec::new(),
+            headers: export_headers(&exchange.response_headers),
+            content: HarContent {
+                size: exchange.response_body.size as i64,
+                mime_type: header_value(&exchange.response_headers, "content-type").unwrap_or_default(),
+                text: Some(exchange.response_body.data.clone()),
+                encoding: (exchange.response_body.encoding == BodyEncoding::Base64).then(|| "base64".to_string()),
+            },
+            redirect_url: header_value(&exchange.response_headers, "location").unwrap_or_default(),
+            headers_size: -1,
+            body_size: exchange.response_body.size as i64,
+        },
+        cache: serde_json::json!({}),
+        timings: HarTimings {
+            blocked: -1.0,
+            dns: -1.0,
+            connect: -1.0,
+            send: 0.0,
+            wait,
+            receive,
+            ssl: -1.0,
+        },
+        client_address: Some(exchange.client_addr.clone()),
+        context: Some(exchange.context.clone()),
+    }
+}
+
+/// Convert HAR entries into exchanges ready for `CaptureStore::import`
+pub fn import(har: &Har) -> Result<Vec<CapturedExchange>, Box<dyn std::error::Error>> {
+    har.log.entries.iter().map(import_entry).collect()
+}
+
+fn import_entry(entry: &HarEntry) -> Result<CapturedExchange, Box<dyn std::error::Error>> {
+    let url: Uri = entry
+        .request
+        .url
+        .parse()
+        .map_err(|e| format!("Invalid HAR request URL '{}': {}", entry.request.url, e))?;
+
+    let request_headers = import_headers(&entry.request.headers);
+    let request_body = match entry.request.post_data {
+        Some(ref post) => {
+            let encoding = match post.encoding.as_deref() {
+                Some("base64") => BodyEncoding::Base64,
+                _ => BodyEncoding::Utf8,
+            };
+            import_body(&post.text, encoding, entry.request.body_size)?
+        }
+        None => CapturedBody::default(),
+    };
+
+    let content = &entry.response.content;
+    let response_encoding = match content.encoding.as_deref() {
+        Some("base64") => BodyEncoding::Base64,
+        _ => BodyEncoding::Utf8,
+    };
+    let response_body = import_body(content.text.as_deref().unwrap_or_default(), response_encoding, content.size)?;
+
+    let started_at_ms = OffsetDateTime::parse(&entry.started_date_time, &Rfc3339)
+        .map(|t| (t.unix_timestamp_nanos() / 1_000_000) as u64)
+        .map_err(|e| format!("Invalid HAR startedDateTime '{}': {}", entry.started_date_time, e))?;
+
+    let wait_ms = entry.timings.wait.max(0.0);
+    let duration_ms = [entry.timings.send, entry.timings.wait, entry.timings.receive]
+        .iter()
+        .filter(|t| **t > 0.0)
+        .sum::<f64>()
+        .max(entry.time);
+
+    Ok(CapturedExchange {
+        id: 0,
+        started_at_ms,
+        replay_of: None,
+        method: entry.request.method.clone(),
+        scheme: url.scheme_str().unwrap_or("http").to_string(),
+        uri: url.path_and_query().map(|pq| pq.to_string()).unwrap_or_else(|| "/".to_string()),
+        host: header_value(&request_headers, "host").or_else(|| url.authority().map(|a| a.to_string())),
+        http_version: entry.request.http_version.clone(),
+        client_addr: entry.client_address.clone().unwrap_or_else(|| IMPORTED_CLIENT_ADDR.to_string()),
+        context: entry.context.clone().unwrap_or_else(|| "Imported".to_string()),
+        request_headers,
+        request_body,
+        status: entry.response.status,
+        response_headers: import_headers(&entry.response.headers),
+        response_body,
+        wait_ms,
+        duration_ms,
+    })
+}
+
+fn import_body(text: &str, encoding: BodyEncoding, size: i64) -> Result<CapturedBody, Box<dyn std::error::Error>> {
+    let mut body = CapturedBody { data: text.to_string(), encoding, ..Default::default() };
+    let captured = body.bytes()?.len() as u64;
+
+    // A declared size beyond the text means the exporter truncated the body
+    body.size = if size > captured as i64 { size as u64 } else { captured };
+    body.truncated = body.size > captured;
+    Ok(body)
+}
+
+/// Credentials are redacted here too, since imported exchanges may carry them
+fn export_headers(headers: &[CapturedHeader]) -> Vec<HarNameValue> {
+    headers
+        .iter()
+        .map(|h| HarNameValue {
+            name: h.name.clone(),
+            value: if capture::is_credential_header(&h.name) { capture::REDACTED.to_string() } else { h.value.clone() },
+        })
+        .collect()
+}
+
+fn import_headers(headers: &[HarNameValue]) -> Vec<CapturedHeader> {
+    headers
+        .iter()
+        // HTTP/2 pseudo-headers (from browser exports) are not real headers
+        .filter(|h| !h.name.starts_with(':'))
+        .map(|h| CapturedHeader { name: h.name.to_lowercase(), value: h.value.clone() })
+        .collect()
+}
+
+fn header_value(headers: &[CapturedHeader], name: &str) -> Option<String> {
+    headers.iter().find(|h| h.name.eq_ignore_ascii_case(name)).map(|h| h.value.clone())
+}
+
+fn format_timestamp(unix_ms: u64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(unix_ms as i128 * 1_000_000)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange() -> CapturedExchange {
+        CapturedExchange {
+            id: 7,
+            started_at_ms: 1_700_000_000_123,
+            replay_of: None,
+            method: "POST".to_string(),
+            scheme: "https".to_string(),
+            uri: "/hook?event=push&x=a%20b".to_string(),
+            host: Some("myapp.local".to_string()),
+            http_version: "HTTP/1.1".to_string(),
+            client_addr: "10.0.0.2:5000".to_string(),
+            context: "WebhookService".to_string(),
+            request_headers: vec![
+                CapturedHeader { name: "host".to_string(), value: "myapp.local".to_string() },
+                CapturedHeader { name: "content-type".to_string(), value: "application/json".to_string() },
+            ],
+            request_body: CapturedBody::from_bytes(b"{\"a\":1}", 7),
+            status: 201,
+            response_headers: vec![CapturedHeader { name: "content-type".to_string(), value: "image/png".to_string() }],
+            response_body: CapturedBody::from_bytes(&[0x89, 0x50, 0xff], 300),
+            wait_ms: 12.5,
+            duration_ms: 20.0,
+        }
+    }
+
+    #[test]
+    fn test_export_entry() {
+        // An imported exchange may still carry credentials
+        let mut with_credentials = exchange();
+        with_credentials.request_headers.push(CapturedHeader { name: "Cookie".to_string(), value: "session=1".to_string() });
+        let har = export(&[with_credentials]);
+        assert_eq!(har.log.version, "1.2");
+
+        let entry = &har.log.entries[0];
+        assert_eq!(entry.request.headers[2].value, capture::REDACTED);
+        assert_eq!(entry.started_date_time, "2023-11-14T22:13:20.123Z");
+        assert_eq!(entry.request.url, "https://myapp.local/hook?event=push&x=a%20b");
+        assert_eq!(entry.request.query_string[1].value, "a b");
+        assert_eq!(entry.request.post_data.as_ref().unwrap().mime_type, "application/json");
+        assert_eq!(entry.response.status_text, "Created");
+        assert_eq!(entry.response.content.encoding.as_deref(), Some("base64"));
+        assert_eq!(entry.timings.wait, 12.5);
+        assert_eq!(entry.timings.receive, 7.5);
+        assert_eq!(entry.time, 20.0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = serde_json::to_string(&export(&[exchange()])).unwrap();
+        let har: Har = serde_json::from_str(&json).unwrap();
+        let imported = import(&har).unwrap().remove(0);
+        let original = exchange();
+
+        assert_eq!(imported.started_at_ms, original.started_at_ms);
+        assert_eq!(imported.scheme, "https");
+        assert_eq!(imported.uri, original.uri);
+        assert_eq!(imported.host, original.host);
+        assert_eq!(imported.client_addr, original.client_addr);
+        assert_eq!(imported.request_headers, original.request_headers);
+        assert_eq!(imported.request_body, original.request_body);
+        assert_eq!(imported.response_body, original.response_body);
+        assert!(imported.response_body.truncated);
+        assert_eq!(imported.duration_ms, 20.0);
+    }
+
+    #[test]
+    fn test_import_browser_entry() {
+        let har: Har = serde_json::from_value(serde_json::json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": "WebInspector", "version": "537.36" },
+                "entries": [{
+                    "startedDateTime": "2024-05-01T10:00:00.000+02:00",
+                    "time": 31.2,
+                    "request": {
+                        "method": "GET",
+                        "url": "http://localhost:3000/api/users",
+                        "httpVersion": "HTTP/1.1",
+                        "headers": [{ "name": ":authority", "value": "localhost:3000" }, { "name": "Accept", "value": "*/*" }],
+                        "queryString": [],
+                        "cookies": [],
+                        "headersSize": -1,
+                        "bodySize": 0
+                    },
+                    "response": {
+                        "status": 200,
+                        "statusText": "OK",
+                        "httpVersion": "HTTP/1.1",
+                        "headers": [],
+                        "cookies": [],
+                        "content": { "size": 2, "mimeType": "application/json", "text": "[]" },
+                        "redirectURL": "",
+                        "headersSize": -1,
+                        "bodySize": 2
+                    },
+                    "cache": {},
+                    "timings": { "send": 0.1, "wait": 30.0, "receive": 1.1 }
+                }]
+            }
+        }))
+        .unwrap();
+
+        let imported = import(&har).unwrap().remove(0);
+        assert_eq!(imported.host.as_deref(), Some("localhost:3000"));
+        assert_eq!(imported.request_headers, vec![CapturedHeader { name: "accept".to_string(), value: "*/*".to_string() }]);
+        assert_eq!(imported.client_addr, IMPORTED_CLIENT_ADDR);
+        assert_eq!(imported.response_body.data, "[]");
+        assert_eq!(imported.started_at_ms, 1_714_550_400_000);
+    }
+}
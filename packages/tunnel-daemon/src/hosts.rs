@@ -0,0 +1,571 @@
+//! Hosts File Module
+//!
+//! Reversible edits to the system hosts file. Beam entries live in a block
+//! between begin/end markers tagged with the owning process ID, so they can be
+//! removed on shutdown and swept on the next start if the process crashed.
+//! While its block exists the owner holds a lock on a `<hosts>.beam-<pid>.lock`
+//! file next to the hosts file; a block whose lock can be taken is stale, so a
+//! reused process ID never keeps a crashed daemon's entries alive. Bytes
+//! outside the blocks are never touched, down to blank lines and the final
+//! newline. Every write makes a backup, then writes a temp file and renames it
+//! over the original.
+
+use std::fs::File;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
+
+/// Marker opening a managed block (followed by `(pid N)`)
+const BEGIN_MARKER: &str = "# BEGIN Beam";
+
+/// Marker closing a managed block
+const END_MARKER: &str = "# END Beam";
+
+/// Section header written by older versions, which appended entries without an end marker
+const LEGACY_HEADER: &str = "# Beam local domains";
+
+/// Beam entries added by one daemon process
+#[derive(Debug, Clone, PartialEq)]
+struct ManagedBlock {
+    pid: u32,
+    entries: Vec<(IpAddr, String)>,
+}
+
+/// Part of a hosts file: the user's text, byte for byte, or a Beam block
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Block(ManagedBlock),
+}
+
+/// Hosts file split into untouched text and Beam blocks
+#[derive(Debug, Clone, PartialEq)]
+struct HostsContent {
+    segments: Vec<Segment>,
+}
+
+impl HostsContent {
+    fn parse(content: &str) -> Self {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut current: Option<ManagedBlock> = None;
+
+        for line in content.split_inclusive('\n') {
+            let trimmed = line.trim();
+
+            if let Some(block) = current.as_mut() {
+                if trimmed.starts_with(END_MARKER) {
+                    segments.extend(current.take().map(Segment::Block));
+                } else if trimmed.is_empty() {
+                    // Unterminated block (hand-edited file): it ends at the first blank line
+                    segments.extend(current.take().map(Segment::Block));
+                    text.push_str(line);
+                } else if let Some(entry) = parse_entry(trimmed) {
+                    block.entries.push(entry);
+                }
+                continue;
+            }
+
+            match trimmed.strip_prefix(BEGIN_MARKER).and_then(parse_pid) {
+                Some(pid) => {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                    current = Some(ManagedBlock { pid, entries: Vec::new() });
+                }
+                None => text.push_str(line),
+            }
+        }
+        segments.extend(current.map(Segment::Block));
+        segments.push(Segment::Text(text));
+
+        // Segments alternate text and block, starting and ending with text.
+        // Blocks at the end of the file were appended after a line break of
+        // their own (see `render`); that break is not part of the user's text
+        let mut index = segments.len() - 1;
+        while index >= 2 && matches!(&segments[index], Segment::Text(t) if t.is_empty()) {
+            let before = match &mut segments[index - 2] {
+                Segment::Text(before) => before,
+                Segment::Block(_) => break,
+            };
+            if before.ends_with('\n') {
+                before.pop();
+            }
+            index -= 2;
+        }
+
+        let mut content = HostsContent { segments };
+        content.merge_text();
+        content
+    }
+
+    /// Join neighbouring text segments and drop empty ones
+    fn merge_text(&mut self) {
+        let mut merged: Vec<Segment> = Vec::new();
+        for segment in self.segments.drain(..) {
+            match (merged.last_mut(), segment) {
+                (_, Segment::Text(text)) if text.is_empty() => {}
+                (Some(Segment::Text(last)), Segment::Text(text)) => last.push_str(&text),
+                (_, segment) => merged.push(segment),
+            }
+        }
+        self.segments = merged;
+    }
+
+    fn render(&self) -> String {
+        // Blocks after the last of the user's text go at the end of the file
+        let tail_start = self
+            .segments
+            .iter()
+            .rposition(|s| matches!(s, Segment::Text(_)))
+            .map_or(0, |i| i + 1);
+
+        let mut out = String::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Text(text) => out.push_str(text),
+                Segment::Block(block) if block.entries.is_empty() => {}
+                Segment::Block(block) => {
+                    // Set the tail apart from the user's lines; `parse` drops this break again
+                    if index >= tail_start && !out.is_empty() {
+                        out.push('\n');
+                    }
+                    out.push_str(&format!("{} (pid {}) - managed by beam-tunnel-daemon, do not edit\n", BEGIN_MARKER, block.pid));
+                    for (ip, hostname) in &block.entries {
+                        out.push_str(&format!("{} {}\n", ip, hostname));
+                    }
+                    out.push_str(&format!("{} (pid {})\n", END_MARKER, block.pid));
+                }
+            }
+        }
+        out
+    }
+
+    /// Drop the blocks `keep` rejects, with the segments they leave empty
+    fn retain_blocks(&mut self, mut keep: impl FnMut(&ManagedBlock) -> bool) {
+        self.segments.retain(|s| match s {
+            Segment::Block(block) => keep(block),
+            Segment::Text(_) => true,
+        });
+        self.merge_text();
+    }
+
+    /// Address the user's own lines map `hostname` to (exact, case-insensitive match)
+    fn user_mapping(&self, hostname: &str) -> Option<IpAddr> {
+        self.segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Text(text) => Some(text.lines()),
+                Segment::Block(_) => None,
+            })
+            .flatten()
+            .filter_map(line_hostnames)
+            .find(|(_, names)| names.iter().any(|n| n.eq_ignore_ascii_case(hostname)))
+            .map(|(ip, _)| ip)
+    }
+
+    fn block_mut(&mut self, pid: u32) -> &mut ManagedBlock {
+        let index = match self.segments.iter().position(|s| matches!(s, Segment::Block(b) if b.pid == pid)) {
+            Some(index) => index,
+            None => {
+                self.segments.push(Segment::Block(ManagedBlock { pid, entries: Vec::new() }));
+                self.segments.len() - 1
+            }
+        };
+        match &mut self.segments[index] {
+            Segment::Block(block) => block,
+            Segment::Text(_) => unreachable!(),
+        }
+    }
+
+    /// Drop the `127.0.0.1 <name>` entries older versions wrote for `names`
+    /// under the legacy section header, and the header once nothing is left
+    /// under it. Anything else the user put there stays.
+    fn remove_legacy_section(&mut self, names: &[&str]) -> usize {
+        let ours = |line: &str| {
+            matches!(line_hostnames(line), Some((ip, ref hosts))
+                if ip == IpAddr::from([127, 0, 0, 1])
+                    && hosts.len() == 1
+                    && names.iter().any(|n| n.eq_ignore_ascii_case(hosts[0])))
+        };
+
+        for segment in &mut self.segments {
+            let text = match segment {
+                Segment::Text(text) => text,
+                Segment::Block(_) => continue,
+            };
+            let lines: Vec<&str> = text.split_inclusive('\n').collect();
+            let start = match lines.iter().position(|l| l.trim() == LEGACY_HEADER) {
+                Some(start) => start,
+                None => continue,
+            };
+
+            let end = start + 1 + lines[start + 1..]
+                .iter()
+                .take_while(|l| matches!(line_hostnames(l), Some((_, ref names)) if names.len() == 1))
+                .count();
+            let kept: Vec<&str> = lines[start + 1..end].iter().copied().filter(|l| !ours(l)).collect();
+            let removed = end - start - 1 - kept.len();
+            if removed == 0 {
+                return 0;
+            }
+
+            let mut edited: Vec<&str> = Vec::with_capacity(lines.len());
+            if kept.is_empty() {
+                // Older versions also wrote the blank line before the header
+                let from = match start.checked_sub(1) {
+                    Some(blank) if lines[blank].trim().is_empty() => blank,
+                    _ => start,
+                };
+                edited.extend(&lines[..from]);
+            } else {
+                edited.extend(&lines[..=start]);
+                edited.extend(kept);
+            }
+            edited.extend(&lines[end..]);
+            *text = edited.concat();
+            return removed;
+        }
+        0
+    }
+}
+
+/// Parse the pid out of `(pid N) ...` following a begin marker
+fn parse_pid(rest: &str) -> Option<u32> {
+    rest.trim().strip_prefix("(pid ")?.split(')').next()?.trim().parse().ok()
+}
+
+fn parse_entry(line: &str) -> Option<(IpAddr, String)> {
+    let (ip, names) = line_hostnames(line)?;
+    names.first().map(|name| (ip, name.to_string()))
+}
+
+/// Address and hostnames of a hosts-file line, ignoring comments
+fn line_hostnames(line: &str) -> Option<(IpAddr, Vec<&str>)> {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut fields = line.split_whitespace();
+    let ip = fields.next()?.parse().ok()?;
+    let names: Vec<&str> = fields.collect();
+    (!names.is_empty()).then_some((ip, names))
+}
+
+/// A hosts file Beam may add entries to
+#[derive(Debug, Clone)]
+pub struct HostsFile {
+    path: PathBuf,
+
+    /// Lock file this process holds while its block is in the file
+    owner_lock: Arc<Mutex<Option<File>>>,
+}
+
+impl Default for HostsFile {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+impl HostsFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        HostsFile { path: path.into(), owner_lock: Arc::default() }
+    }
+
+    /// The platform's hosts file
+    pub fn system() -> Self {
+        if cfg!(target_os = "windows") {
+            Self::new("C:\\Windows\\System32\\drivers\\etc\\hosts")
+        } else {
+            Self::new("/etc/hosts")
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Map `hostname` to `ip` in this process's block; returns whether the file changed
+    pub async fn add_entry(&self, ip: IpAddr, hostname: &str) -> io::Result<bool> {
+        let pid = std::process::id();
+        let path = self.path.display();
+        // Claim the block before it exists, so a sweep never sees it unowned
+        self.lock_ownership(pid)?;
+        self.update(|hosts| {
+            match hosts.user_mapping(hostname) {
+                Some(existing) if existing == ip => {
+                    debug!("{} already maps {} to {}", path, hostname, ip);
+                    return;
+                }
+                Some(existing) => {
+                    warn!("{} maps {} to {}, which takes precedence over Beam's entry", path, hostname, existing);
+                }
+                None => {}
+            }
+
+            let block = hosts.block_mut(pid);
+            block.entries.retain(|(_, name)| !name.eq_ignore_ascii_case(hostname));
+            block.entries.push((ip, hostname.to_string()));
+        })
+        .await
+    }
+
+    /// Remove every entry this process added; returns whether the file changed
+    pub async fn remove_entries(&self) -> io::Result<bool> {
+        let pid = std::process::id();
+        let changed = self.update(|hosts| hosts.retain_blocks(|b| b.pid != pid)).await?;
+        if self.owner_lock.lock().unwrap().take().is_some() {
+            self.remove_lock_file(pid);
+        }
+        Ok(changed)
+    }
+
+    /// Remove blocks whose owner no longer holds its lock, and the legacy
+    /// entries older versions wrote for `legacy_names`
+    pub async fn sweep_stale(&self, legacy_names: &[&str]) -> io::Result<usize> {
+        let mut removed = 0;
+        let mut stale = Vec::new();
+        self.update(|hosts| {
+            removed += hosts.remove_legacy_section(legacy_names);
+            hosts.retain_blocks(|block| {
+                let alive = self.owner_alive(block.pid);
+                if !alive {
+                    removed += block.entries.len();
+                    stale.push(block.pid);
+                }
+                alive
+            });
+        })
+        .await?;
+        for pid in stale {
+            self.remove_lock_file(pid);
+        }
+
+        if removed > 0 {
+            info!("Removed {} stale Beam entries from {}", removed, self.path.display());
+        }
+        Ok(removed)
+    }
+
+    /// Take the lock marking this process's block as owned, if not held yet
+    fn lock_ownership(&self, pid: u32) -> io::Result<()> {
+        let mut owner_lock = self.owner_lock.lock().unwrap();
+        if owner_lock.is_some() {
+            return Ok(());
+        }
+
+        let path = self.lock_path(pid);
+        let file = File::options().write(true).create(true).truncate(false).open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                return Err(io::Error::other(format!("{} is held by another process", path.display())));
+            }
+            Err(std::fs::TryLockError::Error(e)) => return Err(e),
+        }
+        *owner_lock = Some(file);
+        Ok(())
+    }
+
+    /// Whether the process that wrote the block for `pid` still holds its lock;
+    /// the lock dies with the process, so a reused ID doesn't count
+    fn owner_alive(&self, pid: u32) -> bool {
+        let file = match File::open(self.lock_path(pid)) {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+        match file.try_lock() {
+            Ok(()) => false,
+            Err(std::fs::TryLockError::WouldBlock) => true,
+            Err(std::fs::TryLockError::Error(e)) => {
+                warn!("Cannot check owner of Beam entries for pid {}: {}", pid, e);
+                true
+            }
+        }
+    }
+
+    fn remove_lock_file(&self, pid: u32) {
+        if let Err(e) = std::fs::remove_file(self.lock_path(pid)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                debug!("Cannot remove {}: {}", self.lock_path(pid).display(), e);
+            }
+        }
+    }
+
+    fn lock_path(&self, pid: u32) -> PathBuf {
+        self.sibling(&format!("beam-{}.lock", pid))
+    }
+
+    /// Apply an edit and write the result back if anything changed
+    async fn update(&self, edit: impl FnOnce(&mut HostsContent)) -> io::Result<bool> {
+        let original = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let parsed = HostsContent::parse(&original);
+        let mut edited = parsed.clone();
+        edit(&mut edited);
+        if edited == parsed {
+            return Ok(false);
+        }
+
+        self.write_atomically(&original, &edited.render()).await?;
+        Ok(true)
+    }
+
+    /// Back up the current content, then replace the file via a temp file and rename
+    async fn write_atomically(&self, original: &str, content: &str) -> io::Result<()> {
+        if !original.is_empty() {
+            tokio::fs::write(self.sibling("beam-backup"), original).await?;
+        }
+
+        // Unique per writer so concurrent daemons never share a temp file
+        let temp = self.sibling(&format!("beam-tmp-{}-{}", std::process::id(), uuid::Uuid::new_v4().simple()));
+        {
+            let mut file = tokio::fs::File::create(&temp).await?;
+            tokio::io::AsyncWriteExt::write_all(&mut file, content.as_bytes()).await?;
+            file.sync_all().await?;
+        }
+        if let Ok(metadata) = tokio::fs::metadata(&self.path).await {
+            tokio::fs::set_permissions(&temp, metadata.permissions()).await?;
+        }
+
+        if let Err(e) = tokio::fs::rename(&temp, &self.path).await {
+            // Bind-mounted hosts files (containers) can't be replaced, only rewritten
+            let _ = tokio::fs::remove_file(&temp).await;
+            if e.kind() != io::ErrorKind::ResourceBusy {
+                return Err(e);
+            }
+            debug!("{} is busy, rewriting in place", self.path.display());
+            tokio::fs::write(&self.path, content).await?;
+        }
+        Ok(())
+    }
+
+    /// `<hosts>.<suffix>` next to the hosts file (same filesystem, so rename is atomic)
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_CONTENT: &str = "127.0.0.1 localhost\n10.0.0.5 notmyapp.local myapp.local.example # keep\n";
+
+    fn localhost() -> IpAddr {
+        IpAddr::from([127, 0, 0, 1])
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::write(&path, USER_CONTENT).unwrap();
+        let hosts = HostsFile::new(&path);
+
+        assert!(hosts.add_entry(localhost(), "myapp.local").await.unwrap());
+        assert!(!hosts.add_entry(localhost(), "myapp.local").await.unwrap());
+        assert!(!hosts.add_entry(localhost(), "localhost").await.unwrap());
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with(USER_CONTENT));
+        assert!(content.contains(&format!("{} (pid {})", BEGIN_MARKER, std::process::id())));
+        assert!(content.contains("\n127.0.0.1 myapp.local\n"));
+        assert_eq!(std::fs::read_to_string(dir.path().join("hosts.beam-backup")).unwrap(), USER_CONTENT);
+        assert!(!std::fs::read_dir(dir.path()).unwrap().any(|e| e.unwrap().file_name().to_string_lossy().contains("beam-tmp")));
+
+        assert!(hosts.remove_entries().await.unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), USER_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_sweep_stale_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        let live = std::process::id();
+        std::fs::write(
+            &path,
+            format!(
+                "{USER_CONTENT}\n# Beam local domains\n127.0.0.1 old.local\n\
+                 {BEGIN_MARKER} (pid {dead})\n127.0.0.1 crashed.local\n{END_MARKER} (pid {dead})\n\
+                 {BEGIN_MARKER} (pid {live})\n127.0.0.1 running.local\n{END_MARKER} (pid {live})\n",
+                dead = i32::MAX,
+            ),
+        )
+        .unwrap();
+        let hosts = HostsFile::new(&path);
+        hosts.lock_ownership(live).unwrap();
+
+        // A lock file left by the dead process proves nothing once it's gone
+        std::fs::write(hosts.lock_path(i32::MAX as u32), "").unwrap();
+
+        assert_eq!(hosts.sweep_stale(&["old.local"]).await.unwrap(), 2);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with(USER_CONTENT));
+        assert!(content.contains("running.local"));
+        assert!(!content.contains("crashed.local"));
+        assert!(!content.contains("old.local"));
+        assert!(!hosts.lock_path(i32::MAX as u32).exists());
+        assert_eq!(hosts.sweep_stale(&["old.local"]).await.unwrap(), 0);
+
+        // Another handle on the file sees the block as owned until it is removed
+        assert!(HostsFile::new(&path).owner_alive(live));
+        assert!(hosts.remove_entries().await.unwrap());
+        assert!(!hosts.lock_path(live).exists());
+        assert!(!HostsFile::new(&path).owner_alive(live));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_section_keeps_user_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::write(
+            &path,
+            format!("{USER_CONTENT}\n# Beam local domains\n127.0.0.1 myapp.local\n10.0.0.5 nas.lan\n127.0.0.1 other.local\n"),
+        )
+        .unwrap();
+        let hosts = HostsFile::new(&path);
+
+        assert_eq!(hosts.sweep_stale(&["MyApp.local"]).await.unwrap(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{USER_CONTENT}\n# Beam local domains\n10.0.0.5 nas.lan\n127.0.0.1 other.local\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bytes_outside_blocks_are_preserved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        let hosts = HostsFile::new(&path);
+
+        for original in ["", "127.0.0.1 localhost", "127.0.0.1 localhost\n\n\n", "# comment\r\n::1 localhost\r\n\r\n"] {
+            std::fs::write(&path, original).unwrap();
+            assert!(hosts.add_entry(localhost(), "myapp.local").await.unwrap());
+            assert!(hosts.add_entry(localhost(), "api.myapp.local").await.unwrap());
+            assert!(std::fs::read_to_string(&path).unwrap().starts_with(original));
+
+            assert!(hosts.remove_entries().await.unwrap());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), original, "{:?}", original);
+        }
+    }
+
+    #[test]
+    fn test_render_round_trips() {
+        let block = |pid| {
+            format!("{BEGIN_MARKER} (pid {pid}) - managed by beam-tunnel-daemon, do not edit\n127.0.0.1 a.local\n{END_MARKER} (pid {pid})\n")
+        };
+        for content in [
+            format!("127.0.0.1 localhost\n\n{}", block(1)),
+            format!("127.0.0.1 localhost\n{}\n{}", block(1), block(2)),
+            // A block the user moved above their own lines stays in place
+            format!("127.0.0.1 localhost\n{}::1 localhost", block(1)),
+        ] {
+            assert_eq!(HostsContent::parse(&content).render(), content);
+        }
+    }
+}
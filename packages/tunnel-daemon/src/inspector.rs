@@ -0,0 +1,432 @@
+//! Request Inspector Module
+//!
+//! Local web dashboard for captured traffic. Serves a JSON API over the
+//! capture store, a live Server-Sent Events feed and a minimal HTML UI on
+//! localhost (port 4040 by default). Requests must address the inspector by
+//! its loopback host and come from its own origin, so other sites cannot
+//! reach it through DNS rebinding or cross-site requests, and requests that
+//! change state must be sent as JSON, which browsers only allow cross-site
+//! after a preflight.
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode, header};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info};
+
+use crate::capture::CaptureStore;
+use crate::har::{self, Har};
+use crate::replay::{self, ReplayDiff, ReplayOverrides, ReplayResult};
+use crate::tunnel::TunnelDaemon;
+
+/// Default inspector port
+pub const DEFAULT_INSPECT_PORT: u16 = 4040;
+
+/// Interval between SSE keep-alive comments
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Snapshot of tunnel statistics for `/api/stats`
+#[derive(Debug, Serialize)]
+struct StatsSnapshot {
+    total_requests: u64,
+    successful_requests: u64,
+    failed_requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    rate_limited_requests: u64,
+    overloaded_requests: u64,
+    uptime_secs: u64,
+    captured: usize,
+}
+
+/// Serve the inspector on localhost until the server fails
+pub async fn serve(port: u16, daemon: Arc<TunnelDaemon>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+    let make_svc = make_service_fn(move |_| {
+        let daemon = Arc::clone(&daemon);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let daemon = Arc::clone(&daemon);
+                async move { Ok::<_, Infallible>(handle(req, port, daemon).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    info!("Request inspector listening on http://{}", addr);
+
+    server.await?;
+    Ok(())
+}
+
+async fn handle(req: Request<Body>, port: u16, daemon: Arc<TunnelDaemon>) -> Response<Body> {
+    if let Some(rejection) = reject_foreign(&req, port) {
+        return rejection;
+    }
+
+    let store = match daemon.capture_store() {
+        Some(store) => Arc::clone(store),
+        None => return json_error(StatusCode::SERVICE_UNAVAILABLE, "Request capture is disabled"),
+    };
+
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    debug!("Inspector: {} {}", req.method(), path);
+
+    match (req.method(), path.as_str()) {
+        (&Method::GET, "") | (&Method::GET, "/index.html") => Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(INDEX_HTML))
+            .unwrap(),
+        (&Method::GET, "/api/requests") => json_response(StatusCode::OK, &store.list()),
+        (&Method::DELETE, "/api/requests") => {
+            store.clear();
+            Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap()
+        }
+        (&Method::GET, "/api/har") => {
+            let mut exchanges = store.list();
+            exchanges.reverse();
+            let mut response = json_response(StatusCode::OK, &har::export(&exchanges));
+            response.headers_mut().insert(
+                header::CONTENT_DISPOSITION,
+                header::HeaderValue::from_static("attachment; filename=\"beam.har\""),
+            );
+            response
+        }
+        (&Method::POST, "/api/har") => import_har(req, &store).await,
+        (&Method::GET, "/api/events") => event_stream(store.subscribe()),
+        (&Method::GET, "/api/stats") => {
+            let (total, ok, failed, bytes_in, bytes_out) = daemon.get_stats();
+            let stats = daemon.stats();
+            json_response(StatusCode::OK, &StatsSnapshot {
+                total_requests: total,
+                successful_requests: ok,
+                failed_requests: failed,
+                bytes_in,
+                bytes_out,
+                rate_limited_requests: stats.rate_limited_requests.load(Ordering::Relaxed),
+                overloaded_requests: stats.overloaded_requests.load(Ordering::Relaxed),
+                uptime_secs: daemon.uptime_secs(),
+                captured: store.list().len(),
+            })
+        }
+        (&Method::POST, p) if p.starts_with("/api/requests/") && p.ends_with("/replay") => {
+            let id = &p["/api/requests/".len()..p.len() - "/replay".len()];
+            match id.parse::<u64>() {
+                Ok(id) => replay_capture(req, id, &store, &daemon).await,
+                Err(_) => json_error(StatusCode::NOT_FOUND, "No captured request with that ID"),
+            }
+        }
+        (&Method::GET, p) if p.starts_with("/api/requests/") => {
+            match p["/api/requests/".len()..].parse::<u64>().ok().and_then(|id| store.get(id)) {
+                Some(exchange) => json_response(StatusCode::OK, &exchange),
+                None => json_error(StatusCode::NOT_FOUND, "No captured request with that ID"),
+            }
+        }
+        _ => json_error(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// Refuse requests not made by the inspector UI itself
+fn reject_foreign(req: &Request<Body>, port: u16) -> Option<Response<Body>> {
+    let is_own_host = |host: &str| {
+        host.eq_ignore_ascii_case(&format!("localhost:{}", port)) || host == format!("127.0.0.1:{}", port)
+    };
+
+    let host = req.headers().get(header::HOST).and_then(|h| h.to_str().ok());
+    if !host.is_some_and(is_own_host) {
+        debug!("Inspector: refusing request for host {:?}", host);
+        return Some(json_error(StatusCode::FORBIDDEN, "Unknown host"));
+    }
+
+    if let Some(origin) = req.headers().get(header::ORIGIN) {
+        let own = origin.to_str().ok().and_then(|o| o.strip_prefix("http://")).is_some_and(is_own_host);
+        if !own {
+            debug!("Inspector: refusing request from origin {:?}", origin);
+            return Some(json_error(StatusCode::FORBIDDEN, "Cross-origin requests are not allowed"));
+        }
+    }
+
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"));
+    if mutating && !json {
+        return Some(json_error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected Content-Type: application/json"));
+    }
+
+    None
+}
+
+/// Replay a captured request with optional overrides and diff the responses
+async fn replay_capture(req: Request<Body>, id: u64, store: &CaptureStore, daemon: &TunnelDaemon) -> Response<Body> {
+    let original = match store.get(id) {
+        Some(original) => original,
+        None => return json_error(StatusCode::NOT_FOUND, "No captured request with that ID"),
+    };
+
+    let overrides: ReplayOverrides = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.iter().all(u8::is_ascii_whitespace) => ReplayOverrides::default(),
+        Ok(body) => match serde_json::from_slice(&body) {
+            Ok(overrides) => overrides,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, &format!("Invalid replay overrides: {}", e)),
+        },
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+
+    let (request, body) = match replay::build_request(&original, &overrides) {
+        Ok(built) => built,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+
+    match daemon.replay(&original, request, body).await {
+        Ok(replay) => json_response(StatusCode::OK, &ReplayResult {
+            original_id: original.id,
+            diff: ReplayDiff::between(&original, &replay),
+            replay,
+        }),
+        Err(e) => json_error(StatusCode::BAD_GATEWAY, &format!("Replay failed: {}", e)),
+    }
+}
+
+/// Load the entries of an uploaded HAR file into the capture store
+async fn import_har(req: Request<Body>, store: &CaptureStore) -> Response<Body> {
+    let har: Har = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => match serde_json::from_slice(&body) {
+            Ok(har) => har,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, &format!("Invalid HAR file: {}", e)),
+        },
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+
+    let exchanges = match har::import(&har) {
+        Ok(exchanges) => exchanges,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+
+    let ids: Vec<u64> = exchanges.into_iter().map(|exchange| store.import(exchange)).collect();
+    info!("Imported {} requests from HAR", ids.len());
+    json_response(StatusCode::OK, &serde_json::json!({ "imported": ids.len(), "ids": ids }))
+}
+
+/// Stream completed exchanges as Server-Sent Events
+fn event_stream(mut events: tokio::sync::broadcast::Receiver<crate::capture::CapturedExchange>) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut keepalive = tokio::time::interval(SSE_KEEPALIVE);
+        keepalive.tick().await;
+
+        loop {
+            let chunk = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(exchange) => match serde_json::to_string(&exchange) {
+                        Ok(json) => format!("event: request\nid: {}\ndata: {}\n\n", exchange.id, json),
+                        Err(e) => {
+                            error!("Failed to serialize captured request: {}", e);
+                            continue;
+                        }
+                    },
+                    Err(RecvError::Lagged(skipped)) => format!("event: lagged\ndata: {}\n\n", skipped),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keepalive.tick() => ": keep-alive\n\n".to_string(),
+            };
+
+            if sender.send_data(chunk.into()).await.is_err() {
+                debug!("Inspector event stream closed");
+                break;
+            }
+        }
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+fn json_error(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::json!({ "error": message }).to_string()))
+        .unwrap()
+}
+
+/// Minimal single-page inspector UI
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Beam Inspector</title>
+<style>
+  body { font-family: -apple-system, system-ui, sans-serif; margin: 0; display: flex; height: 100vh; color: #222; }
+  #list { width: 45%; overflow-y: auto; border-right: 1px solid #ddd; }
+  #detail { flex: 1; overflow-y: auto; padding: 0 16px; }
+  header { padding: 8px 12px; background: #111; color: #fff; display: flex; justify-content: space-between; }
+  table { width: 100%; border-collapse: collapse; font-size: 13px; }
+  td, th { padding: 4px 8px; text-align: left; border-bottom: 1px solid #eee; }
+  tr.row { cursor: pointer; }
+  tr.row:hover, tr.selected { background: #eef4ff; }
+  .err { color: #c00; }
+  pre { background: #f6f6f6; padding: 8px; white-space: pre-wrap; word-break: break-all; font-size: 12px; }
+</style>
+</head>
+<body>
+<div id="list">
+  <header><strong>⚡ Beam Inspector</strong>
+    <span>
+      <a href="/api/har" style="color:#fff">Export HAR</a>
+      <label style="cursor:pointer">Import HAR<input type="file" accept=".har,application/json" onchange="importHar(this)" hidden></label>
+      <button onclick="clearAll()">Clear</button>
+    </span>
+  </header>
+  <table>
+    <thead><tr><th>#</th><th>Method</th><th>Path</th><th>Status</th><th>Time</th><th>Size</th></tr></thead>
+    <tbody id="rows"></tbody>
+  </table>
+</div>
+<div id="detail"><p>Select a request to see its details.</p></div>
+<script>
+const rows = document.getElementById('rows');
+const detail = document.getElementById('detail');
+let selected = null;
+const JSON_HEADERS = { 'Content-Type': 'application/json' };
+
+function esc(s) {
+  return String(s).replace(/[&<>"]/g, c => ({ '&': '&amp;', '<': '&lt;', '>': '&gt;', '"': '&quot;' }[c]));
+}
+
+function addRow(x, prepend) {
+  if (document.getElementById('r' + x.id)) return;
+  const tr = document.createElement('tr');
+  tr.className = 'row';
+  tr.id = 'r' + x.id;
+  tr.onclick = () => show(x.id);
+  tr.innerHTML = '<td>' + x.id + '</td><td>' + esc(x.method) + '</td><td>' + esc(x.uri) + '</td>' +
+    '<td class="' + (x.status >= 400 ? 'err' : '') + '">' + x.status + '</td>' +
+    '<td>' + x.duration_ms.toFixed(1) + 'ms</td><td>' + x.response_body.size + ' B</td>';
+  prepend ? rows.prepend(tr) : rows.append(tr);
+}
+
+function headers(list) {
+  return esc(list.map(h => h.name + ': ' + h.value).join('\n'));
+}
+
+function body(b) {
+  if (!b.size) return '<em>empty</em>';
+  return '<pre>' + esc(b.data) + '</pre>' + (b.truncated ? '<em>truncated (' + b.size + ' bytes total)</em>' : '');
+}
+
+async function show(id) {
+  if (selected) selected.classList.remove('selected');
+  selected = document.getElementById('r' + id);
+  if (selected) selected.classList.add('selected');
+  const x = await (await fetch('/api/requests/' + id)).json();
+  detail.innerHTML = '<h3>' + esc(x.method) + ' ' + esc(x.uri) + ' → ' + x.status +
+    ' <button onclick="replay(' + x.id + ')">Replay</button></h3>' +
+    (x.replay_of ? '<p>Replay of <a onclick="show(' + x.replay_of + ')">#' + x.replay_of + '</a></p>' : '') +
+    '<p>' + esc(x.client_addr) + ' · ' + esc(x.context) + ' · wait ' + x.wait_ms.toFixed(1) + 'ms · total ' + x.duration_ms.toFixed(1) + 'ms</p>' +
+    '<h4>Request headers</h4><pre>' + headers(x.request_headers) + '</pre>' +
+    '<h4>Request body</h4>' + body(x.request_body) +
+    '<h4>Response headers</h4><pre>' + headers(x.response_headers) + '</pre>' +
+    '<h4>Response body</h4>' + body(x.response_body);
+}
+
+async function replay(id) {
+  const res = await fetch('/api/requests/' + id + '/replay', { method: 'POST', headers: JSON_HEADERS, body: '{}' });
+  const result = await res.json();
+  if (!res.ok) return alert(result.error);
+  await show(result.replay.id);
+  detail.innerHTML += '<h4>Diff against #' + id + '</h4><pre>' + esc(JSON.stringify(result.diff, null, 2)) + '</pre>';
+}
+
+async function importHar(input) {
+  const file = input.files[0];
+  if (!file) return;
+  const res = await fetch('/api/har', { method: 'POST', headers: JSON_HEADERS, body: await file.text() });
+  const result = await res.json();
+  if (!res.ok) alert(result.error);
+  input.value = '';
+}
+
+async function clearAll() {
+  await fetch('/api/requests', { method: 'DELETE', headers: JSON_HEADERS });
+  rows.innerHTML = '';
+  detail.innerHTML = '<p>Select a request to see its details.</p>';
+}
+
+fetch('/api/requests').then(r => r.json()).then(list => list.forEach(x => addRow(x, false)));
+new EventSource('/api/events').addEventListener('request', e => addRow(JSON.parse(e.data), true));
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, host: &str, origin: Option<&str>, content_type: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri("/api/requests").header(header::HOST, host);
+        if let Some(origin) = origin {
+            builder = builder.header(header::ORIGIN, origin);
+        }
+        if let Some(content_type) = content_type {
+            builder = builder.header(header::CONTENT_TYPE, content_type);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn status(req: Request<Body>) -> Option<StatusCode> {
+        reject_foreign(&req, 4040).map(|res| res.status())
+    }
+
+    #[test]
+    fn test_rejects_foreign_hosts_and_origins() {
+        assert_eq!(status(request(Method::GET, "localhost:4040", None, None)), None);
+        assert_eq!(status(request(Method::GET, "127.0.0.1:4040", Some("http://127.0.0.1:4040"), None)), None);
+
+        // DNS rebinding: a foreign name resolving to loopback
+        assert_eq!(status(request(Method::GET, "evil.example:4040", None, None)), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status(request(Method::GET, "localhost:4041", None, None)), Some(StatusCode::FORBIDDEN));
+
+        // Cross-site requests from a page on another origin
+        let foreign = Some("http://evil.example");
+        assert_eq!(status(request(Method::GET, "localhost:4040", foreign, None)), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status(request(Method::GET, "localhost:4040", Some("null"), None)), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn test_mutations_require_json() {
+        let json = Some("application/json; charset=utf-8");
+        assert_eq!(status(request(Method::DELETE, "localhost:4040", None, json)), None);
+        assert_eq!(status(request(Method::POST, "localhost:4040", None, json)), None);
+
+        // Forms and plain text need no preflight, so they are refused
+        let form = Some("application/x-www-form-urlencoded");
+        assert_eq!(status(request(Method::POST, "localhost:4040", None, form)), Some(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        assert_eq!(status(request(Method::DELETE, "localhost:4040", None, None)), Some(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+}
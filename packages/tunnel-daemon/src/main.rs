@@ -1,22 +1,57 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use tracing::{info, error, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
 mod tunnel;
 mod tor;
+mod tor_control;
 mod dns;
+mod dns_server;
+mod hosts;
+mod mdns;
 mod context;
 mod cert;
+mod sni;
+mod mtls;
+mod acme;
+mod redirect;
 mod mode;
 mod p2p;
 mod cache;
-
-use tunnel::TunnelDaemon;
+mod pool;
+mod forwarding;
+mod routing;
+mod tcp;
+mod capture;
+mod inspector;
+mod replay;
+mod har;
+mod auth;
+mod ratelimit;
+
+use tunnel::{TunnelDaemon, TunnelProtocol};
 use tor::{TorManager, TorMode};
+use tor_control::TorCredentials;
 use dns::DualDNSResolver;
+use dns_server::BeamDnsHandler;
+use hosts::HostsFile;
+use mdns::{LanAddresses, MdnsResponder, MdnsZone};
 use mode::{TunnelMode, PerformanceConfig};
 use p2p::P2PManager;
 use cache::ResponseCache;
+use forwarding::ForwardingConfig;
+use routing::RoutingTable;
+use capture::CaptureStore;
+use auth::{AuthConfig, BypassContexts};
+use ratelimit::{RateLimit, RateLimiter};
+use cert::{CertStore, CertificateAuthority, TrustStore};
+use sni::SniResolver;
+use mtls::ClientAuth;
+use acme::{AcmeClient, AcmeConfig, ChallengeResponses, ChallengeType};
+use redirect::HttpsRedirect;
 
 /// Tunnel mode for CLI argument parsing
 #[derive(Debug, Clone, Copy, ValueEnum)]
@@ -29,14 +64,67 @@ enum CliTunnelMode {
     Private,
 }
 
+/// Protocol for CLI argument parsing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CliProtocol {
+    /// HTTP reverse proxy (caching, routing, WebSocket upgrades)
+    Http,
+    /// Raw TCP forwarding for non-HTTP services (databases, Redis, SSH)
+    Tcp,
+}
+
+/// Certificate formats for `ca export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CertFormat {
+    Pem,
+    Der,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the local certificate authority used for HTTPS
+    Ca {
+        #[command(subcommand)]
+        action: CaAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum CaAction {
+    /// Write the CA certificate so it can be trusted elsewhere
+    Export {
+        /// Output format
+        #[arg(long, value_enum, default_value = "pem")]
+        format: CertFormat,
+
+        /// Output file (stdout if omitted)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Add the CA certificate to a trust store
+    Install {
+        /// NSS database directory, e.g. ~/.pki/nssdb or a Firefox profile
+        #[arg(long, value_name = "DIR")]
+        nss_db: Option<PathBuf>,
+
+        /// Directory of trusted CA files (defaults to /usr/local/share/ca-certificates)
+        #[arg(long, value_name = "DIR", conflicts_with = "nss_db")]
+        ca_certificates_dir: Option<PathBuf>,
+    },
+}
+
 #[derive(Parser)]
 #[command(name = "beam-tunnel-daemon")]
 #[command(about = "Beam decentralized tunnel daemon")]
 #[command(version)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Target port where the local application is running (e.g., your dev server on 3000)
-    #[arg(short = 't', long)]
-    target_port: u16,
+    #[arg(short = 't', long, required = true)]
+    target_port: Option<u16>,
 
     /// Port for the tunnel daemon to listen on (defaults to target_port + 1000)
     #[arg(short = 'l', long)]
@@ -50,6 +138,10 @@ struct Args {
     #[arg(short, long, value_enum, default_value = "balanced")]
     mode: CliTunnelMode,
 
+    /// Protocol of the local service: http or tcp
+    #[arg(long, value_enum, default_value = "http")]
+    protocol: CliProtocol,
+
     /// Enable Tor hidden service (legacy flag, use --mode instead)
     #[arg(long)]
     tor: bool,
@@ -62,18 +154,117 @@ struct Args {
     #[arg(long, default_value = "9051")]
     tor_port: u16,
 
+    /// File holding the Tor control port password (otherwise BEAM_TOR_PASSWORD is used)
+    #[arg(long, value_name = "PATH")]
+    tor_password_file: Option<PathBuf>,
+
     /// DNS server port
     #[arg(long, default_value = "5353")]
     dns_port: u16,
 
-    /// Enable HTTPS with self-signed certificate
+    /// Address the DNS server binds to
+    #[arg(long, default_value = "127.0.0.1")]
+    dns_bind: IpAddr,
+
+    /// Forward queries for non-Beam names to this DNS server (NXDOMAIN otherwise)
+    #[arg(long, value_name = "ADDR:PORT")]
+    dns_upstream: Option<SocketAddr>,
+
+    /// Allow --dns-upstream forwarding on a non-loopback --dns-bind (serves as an open resolver)
+    #[arg(long, requires = "dns_upstream")]
+    dns_open_resolver: bool,
+
+    /// Don't run the DNS server
+    #[arg(long)]
+    no_dns: bool,
+
+    /// DNS view for queriers in a subnet: CIDR=local or CIDR=tor (may be repeated; LAN is local, others tor)
+    #[arg(long = "dns-view", value_name = "CIDR=VIEW")]
+    dns_views: Vec<String>,
+
+    /// Hosts file to add the domain to (defaults to the system hosts file)
+    #[arg(long, value_name = "PATH")]
+    hosts_file: Option<PathBuf>,
+
+    /// Don't advertise .local domains over multicast DNS
+    #[arg(long)]
+    no_mdns: bool,
+
+    /// LAN address advertised over mDNS and given to LAN DNS clients (detected from the default route otherwise)
+    #[arg(long, value_name = "IP")]
+    lan_ip: Option<IpAddr>,
+
+    /// Address the listen port binds to (use 0.0.0.0 to accept LAN clients)
+    #[arg(long)]
+    bind: Option<IpAddr>,
+
+    /// Enable HTTPS with a certificate from the local CA
     #[arg(long)]
     https: bool,
 
+    /// Directory of the local certificate authority (defaults to the Beam data directory)
+    #[arg(long, value_name = "DIR", global = true)]
+    ca_dir: Option<PathBuf>,
+
+    /// Directory for issued certificates (defaults to the Beam data directory)
+    #[arg(long, value_name = "DIR")]
+    cert_dir: Option<PathBuf>,
+
+    /// PEM certificate chain to serve instead of the local CA's (implies --https)
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert (PKCS#8, RSA or EC)
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Get the HTTPS certificate from an ACME CA such as Let's Encrypt (implies --https)
+    #[arg(long, conflicts_with = "tls_cert")]
+    acme: bool,
+
+    /// ACME directory URL (e.g. https://localhost:14000/dir for a local Pebble)
+    #[arg(long, value_name = "URL", default_value = acme::LETS_ENCRYPT_DIRECTORY)]
+    acme_directory: String,
+
+    /// Contact email for the ACME account
+    #[arg(long, value_name = "EMAIL")]
+    acme_email: Option<String>,
+
+    /// ACME challenge: http-01 (answered by the HTTP listener, which the CA
+    /// reaches on port 80) or dns-01 (answered by the DNS server; also covers *.domain)
+    #[arg(long, value_name = "TYPE", default_value = "http-01")]
+    acme_challenge: ChallengeType,
+
+    /// Extra CA certificates (PEM) to trust for the ACME server, e.g. Pebble's
+    #[arg(long, value_name = "PATH")]
+    acme_ca_bundle: Option<PathBuf>,
+
+    /// PEM bundle of CAs for verifying client certificates (enables mutual TLS);
+    /// unless they are optional, plain HTTP and onion requests are refused
+    #[arg(long, value_name = "PATH")]
+    client_ca: Option<PathBuf>,
+
+    /// Also accept HTTPS clients without a certificate (they go through normal auth)
+    #[arg(long, requires = "client_ca")]
+    client_cert_optional: bool,
+
     /// HTTPS port (defaults to listen port + 1)
     #[arg(long)]
     https_port: Option<u16>,
 
+    /// Redirect plain HTTP requests to HTTPS and send HSTS on HTTPS responses
+    #[arg(long)]
+    redirect_https: bool,
+
+    /// HSTS max-age in seconds sent with --redirect-https (0 disables HSTS);
+    /// never sent for localhost, IP addresses or .local hosts
+    #[arg(long, value_name = "SECS", default_value_t = redirect::DEFAULT_HSTS_MAX_AGE)]
+    hsts_max_age: u64,
+
+    /// Extend HSTS to all subdomains of the host
+    #[arg(long, requires = "redirect_https")]
+    hsts_include_subdomains: bool,
+
     /// Enable response caching for static assets
     #[arg(long, default_value = "true")]
     cache: bool,
@@ -97,6 +288,70 @@ struct Args {
     /// Disable circuit prebuilding
     #[arg(long)]
     no_prebuild: bool,
+
+    /// Pass the client's original Host header to the local application
+    #[arg(long)]
+    preserve_host: bool,
+
+    /// Forwarding headers per access context, e.g. external=x-forwarded-proto,x-forwarded-host (repeatable)
+    #[arg(long = "forward-headers", value_name = "CONTEXT=HEADERS")]
+    forward_headers: Vec<String>,
+
+    /// Route requests to another upstream: [host][/prefix]=<port|url>[,strip] (repeatable)
+    #[arg(long = "route", value_name = "RULE")]
+    routes: Vec<String>,
+
+    /// Load routes from a TOML, YAML or JSON file
+    #[arg(long)]
+    routes_file: Option<std::path::PathBuf>,
+
+    /// Require Basic auth with user:pass (repeatable)
+    #[arg(short = 'a', long = "auth", value_name = "USER:PASS")]
+    auth: Vec<String>,
+
+    /// Require a Bearer token (repeatable)
+    #[arg(long = "token", value_name = "TOKEN")]
+    tokens: Vec<String>,
+
+    /// Only accept clients from these IPs or CIDR ranges (comma-separated, repeatable)
+    #[arg(long = "allow-ip", value_name = "CIDRS")]
+    allow_ip: Vec<String>,
+
+    /// Refuse clients from these IPs or CIDR ranges (comma-separated, repeatable)
+    #[arg(long = "deny-ip", value_name = "CIDRS")]
+    deny_ip: Vec<String>,
+
+    /// Access contexts that skip auth, not IP checks (comma-separated, or "none"; local-browser only from loopback)
+    #[arg(long, value_name = "CONTEXTS", default_value = "local-browser")]
+    auth_bypass: BypassContexts,
+
+    /// Per-client rate limit for every access context, e.g. 10/s or 600/m:50
+    #[arg(long, value_name = "RATE")]
+    rate_limit: Option<RateLimit>,
+
+    /// Rate limit for one access context, e.g. external=5/s or local-browser=none (repeatable)
+    #[arg(long = "context-rate-limit", value_name = "CONTEXT=RATE")]
+    context_rate_limits: Vec<String>,
+
+    /// Maximum number of requests in flight (further requests get 503)
+    #[arg(long, value_name = "N")]
+    max_concurrent: Option<usize>,
+
+    /// Capture requests and serve the request inspector
+    #[arg(short, long)]
+    inspect: bool,
+
+    /// Port for the request inspector
+    #[arg(long, default_value_t = inspector::DEFAULT_INSPECT_PORT)]
+    inspect_port: u16,
+
+    /// Number of requests kept by the inspector
+    #[arg(long, default_value_t = capture::DEFAULT_CAPTURE_CAPACITY)]
+    inspect_history: usize,
+
+    /// Maximum bytes captured per request or response body
+    #[arg(long, default_value_t = capture::DEFAULT_MAX_BODY_SIZE)]
+    inspect_body_limit: usize,
 }
 
 #[tokio::main]
@@ -107,6 +362,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init();
 
     let args = Args::parse();
+    let ca_dir = args.ca_dir.clone().unwrap_or_else(cert::default_ca_dir);
+
+    if let Some(command) = args.command {
+        return run_command(command, &ca_dir);
+    }
+    let target_port = args.target_port.expect("--target-port is required");
 
     // Convert CLI mode to internal mode
     let tunnel_mode = match args.mode {
@@ -121,14 +382,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Calculate listen port (default: target_port + 1000, or use specified)
     let listen_port = args.listen_port.unwrap_or_else(|| {
         // Try target_port + 1000, but handle overflow
-        args.target_port.checked_add(1000).unwrap_or(args.target_port + 100)
+        target_port.checked_add(1000).unwrap_or(target_port + 100)
     });
 
     let (min_latency, max_latency) = tunnel_mode.expected_latency();
 
     info!("Starting Beam Tunnel Daemon v{}", env!("CARGO_PKG_VERSION"));
     info!("Mode: {} (expected latency: {}–{}ms)", tunnel_mode, min_latency, max_latency);
-    info!("Domain: {}, Target port: {}, Listen port: {}", args.domain, args.target_port, listen_port);
+    info!("Domain: {}, Target port: {}, Listen port: {}", args.domain, target_port, listen_port);
 
     // Initialize response cache
     let cache_enabled = args.cache && perf_config.enable_caching;
@@ -145,9 +406,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         ResponseCache::start_cleanup_task(cache_clone);
     }
 
+    // Clean up entries left in the hosts file by a daemon that didn't shut down cleanly
+    let hosts_file = args.hosts_file.clone().map(HostsFile::new).unwrap_or_default();
+    if let Err(e) = hosts_file.sweep_stale(&[args.domain.as_str()]).await {
+        warn!("Could not remove stale Beam entries from {}: {}", hosts_file.path().display(), e);
+    }
+
     // Handle mode-specific initialization
     let mut p2p_manager: Option<P2PManager> = None;
     let mut tor_manager: Option<TorManager> = None;
+    let mut onion_listener: Option<std::net::TcpListener> = None;
+    let mut p2p_listener: Option<std::net::TcpListener> = None;
     let mut dns_resolver: Option<DualDNSResolver> = None;
 
     match tunnel_mode {
@@ -155,6 +424,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             // Fast mode: Initialize P2P manager
             info!("Initializing P2P fast mode (direct connections)...");
             let mut p2p = P2PManager::new(listen_port).await?;
+            if args.protocol == CliProtocol::Tcp {
+                p2p_listener = Some(p2p.bind_stream_listener()?);
+            }
 
             // Discover public address
             match p2p.discover_public_address().await {
@@ -163,7 +435,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let token = p2p.generate_connection_token();
                     println!();
                     println!("⚡ Fast mode tunnel active!");
-                    println!("   Local:  http://127.0.0.1:{} → localhost:{}", listen_port, args.target_port);
+                    println!("   Local:  http://127.0.0.1:{} → localhost:{}", listen_port, target_port);
                     println!("   Share:  {}", token);
                     println!();
                     println!("   Expected latency: ~30-50ms");
@@ -174,7 +446,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     warn!("Could not discover public address: {}", e);
                     println!();
                     println!("⚡ Fast mode tunnel active (local only)");
-                    println!("   Local:  http://127.0.0.1:{} → localhost:{}", listen_port, args.target_port);
+                    println!("   Local:  http://127.0.0.1:{} → localhost:{}", listen_port, target_port);
                     println!();
                 }
             }
@@ -189,6 +461,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let tor_available = TorManager::check_tor_available().await;
             if tor_available {
                 let mut tor = TorManager::new_with_mode(args.tor_port, TorMode::SingleHop).await?;
+                tor.set_credentials(TorCredentials::load(args.tor_password_file.as_deref())?);
+
+                // Raw TCP services keep their own port number on the onion address
+                if args.protocol == CliProtocol::Tcp {
+                    tor.set_virtual_port(target_port);
+                }
 
                 // Configure geographic preferences if specified
                 if let Some(ref geo) = args.geo_prefer {
@@ -206,17 +484,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // Try to configure single-hop mode
                 let _ = tor.configure_single_hop_mode().await;
 
-                // Create hidden service
-                let onion_address = tor.create_hidden_service(args.target_port).await?;
+                // Create hidden service (routed through the daemon so onion traffic is proxied and cached)
+                let hs_port = onion_target_port(args.protocol, listen_port, &mut onion_listener)?;
+                let onion_address = tor.create_hidden_service(hs_port).await?;
 
                 // Initialize DNS resolver
                 let mut dns = DualDNSResolver::new();
+                dns.set_hosts_file(hosts_file.clone());
                 dns.configure_dual_resolution(&args.domain, &onion_address).await?;
                 let _ = dns.setup_local_dns_override(&args.domain).await;
 
                 println!();
                 println!("⚖️  Balanced mode tunnel active!");
-                println!("   Local:  http://127.0.0.1:{} → localhost:{}", listen_port, args.target_port);
+                println!("   Local:  http://127.0.0.1:{} → localhost:{}", listen_port, target_port);
                 println!("   Global: {}", onion_address);
                 println!();
                 println!("   Expected latency: ~80-150ms");
@@ -235,7 +515,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("   Install with: brew install tor (macOS) or apt install tor (Linux)");
                 println!();
                 println!("   Falling back to local-only mode:");
-                println!("   Local: http://127.0.0.1:{} → localhost:{}", listen_port, args.target_port);
+                println!("   Local: http://127.0.0.1:{} → localhost:{}", listen_port, target_port);
                 println!();
             }
         }
@@ -247,23 +527,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let tor_available = TorManager::check_tor_available().await;
             if tor_available {
                 let mut tor = TorManager::new_with_mode(args.tor_port, TorMode::Full).await?;
+                tor.set_credentials(TorCredentials::load(args.tor_password_file.as_deref())?);
+
+                // Raw TCP services keep their own port number on the onion address
+                if args.protocol == CliProtocol::Tcp {
+                    tor.set_virtual_port(target_port);
+                }
 
                 // Configure circuit prebuilding (more circuits for better anonymity)
                 tor.set_circuit_prebuilding(!args.no_prebuild, args.prebuild_circuits.max(5));
 
                 // Don't use geographic preferences in private mode (reduces anonymity)
 
-                // Create hidden service
-                let onion_address = tor.create_hidden_service(args.target_port).await?;
+                // Create hidden service (routed through the daemon so onion traffic is proxied and cached)
+                let hs_port = onion_target_port(args.protocol, listen_port, &mut onion_listener)?;
+                let onion_address = tor.create_hidden_service(hs_port).await?;
 
                 // Initialize DNS resolver for dual mode
                 let mut dns = DualDNSResolver::new();
+                dns.set_hosts_file(hosts_file.clone());
                 dns.configure_dual_resolution(&args.domain, &onion_address).await?;
                 let _ = dns.setup_local_dns_override(&args.domain).await;
 
                 println!();
                 println!("🔒 Private mode tunnel active!");
-                println!("   Local:  http://127.0.0.1:{} → localhost:{}", listen_port, args.target_port);
+                println!("   Local:  http://127.0.0.1:{} → localhost:{}", listen_port, target_port);
                 println!("   Global: {}", onion_address);
                 println!();
                 println!("   Expected latency: ~200-500ms");
@@ -280,7 +568,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("   Install with: brew install tor (macOS) or apt install tor (Linux)");
                 println!();
                 println!("   Falling back to local-only mode:");
-                println!("   Local: http://127.0.0.1:{} → localhost:{}", listen_port, args.target_port);
+                println!("   Local: http://127.0.0.1:{} → localhost:{}", listen_port, target_port);
                 println!();
             }
         }
@@ -294,18 +582,230 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         warn!("--tor flag is deprecated. Use --mode=private instead.");
     }
 
+    // Serve Beam domains over DNS (local mapping in every mode, onion TXT when Tor is up)
+    let mut dns_resolver = dns_resolver.unwrap_or_else(|| {
+        let mut dns = DualDNSResolver::new();
+        dns.set_hosts_file(hosts_file.clone());
+        dns.add_local_domain(&args.domain, IpAddr::from([127, 0, 0, 1]));
+        dns
+    });
+    for rule in &args.dns_views {
+        let (net, context) = dns::parse_view_rule(rule)?;
+        dns_resolver.add_context_subnet(net, context);
+    }
+    let https_enabled = args.https || args.tls_cert.is_some() || args.acme;
+    let acme_challenges = args.acme.then(|| Arc::new(ChallengeResponses::default()));
+    let mut lan_addresses = LanAddresses::detect();
+    if let Some(ip) = args.lan_ip {
+        lan_addresses = lan_addresses.with(ip);
+    }
+    if !args.no_dns {
+        let mut handler = BeamDnsHandler::new(Arc::new(dns_resolver.clone()));
+        handler.set_lan_addresses(lan_addresses);
+        if let Some(upstream) = args.dns_upstream {
+            if !args.dns_bind.is_loopback() && !args.dns_open_resolver {
+                return Err(format!(
+                    "--dns-upstream on non-loopback --dns-bind {} would make an open resolver; pass --dns-open-resolver to allow it",
+                    args.dns_bind
+                )
+                .into());
+            }
+            if !args.dns_bind.is_loopback() {
+                warn!("DNS server on {} forwards to {} for any client (open resolver)", args.dns_bind, upstream);
+            }
+            handler.set_upstream(upstream)?;
+        }
+        if let Some(challenges) = &acme_challenges {
+            handler.set_acme_challenges(Arc::clone(challenges));
+        }
+        if let Err(e) = dns_server::start(SocketAddr::new(args.dns_bind, args.dns_port), handler).await {
+            warn!("Could not start DNS server on {}:{}: {}", args.dns_bind, args.dns_port, e);
+        }
+    }
+
+    // Advertise .local domains to other devices on the LAN
+    let mut mdns_responder = None;
+    if !args.no_mdns {
+        let zone = MdnsZone::new(&dns_resolver, lan_addresses);
+        if !zone.is_empty() {
+            if args.bind.is_none_or(|ip| ip.is_loopback()) {
+                warn!("mDNS advertises {} on the LAN but the tunnel only listens on loopback; use --bind 0.0.0.0", args.domain);
+            }
+            match MdnsResponder::start(zone).await {
+                Ok(responder) => mdns_responder = Some(responder),
+                Err(e) => warn!("Could not start mDNS responder: {}", e),
+            }
+        } else if args.domain.ends_with(".local") {
+            warn!("No LAN address found for mDNS; pass --lan-ip to advertise {}", args.domain);
+        }
+    }
+
     // Initialize tunnel daemon with listen port and target port
-    let mut tunnel_daemon = TunnelDaemon::new(listen_port, args.target_port, args.domain.clone()).await?;
+    let mut tunnel_daemon = TunnelDaemon::new(listen_port, target_port, args.domain.clone()).await?;
+    tunnel_daemon.set_performance_config(&perf_config);
+    if let Some(challenges) = &acme_challenges {
+        tunnel_daemon.set_acme_challenges(Arc::clone(challenges));
+    }
+    if let Some(bind) = args.bind {
+        tunnel_daemon.set_bind_address(bind);
+    }
+    if let Some(listener) = onion_listener {
+        tunnel_daemon.set_onion_listener(listener);
+    }
+
+    if args.protocol == CliProtocol::Tcp {
+        tunnel_daemon.set_protocol(TunnelProtocol::Tcp);
+        if https_enabled {
+            warn!("--https is ignored in TCP mode");
+        }
+        if let Some(listener) = p2p_listener {
+            tunnel_daemon.set_p2p_listener(listener);
+        }
+    }
+
+    // Configure forwarding headers
+    let mut forwarding_config = ForwardingConfig::for_mode(tunnel_mode);
+    forwarding_config.preserve_host = args.preserve_host;
+    for rule in &args.forward_headers {
+        let (context, headers) = forwarding::parse_context_rule(rule)?;
+        forwarding_config.set_context_headers(context, headers);
+    }
+    tunnel_daemon.set_forwarding_config(forwarding_config);
+
+    // Configure host and path routing
+    let mut routes = RoutingTable::new();
+    if let Some(ref path) = args.routes_file {
+        routes.load_file(path)?;
+    }
+    for rule in &args.routes {
+        routes.add_route(rule.parse()?);
+    }
+    tunnel_daemon.set_routes(routes);
+    let request_stats = tunnel_daemon.stats();
 
     // Setup HTTPS if requested
-    if args.https {
+    if https_enabled && args.protocol == CliProtocol::Http {
         let https_port = args.https_port.unwrap_or(listen_port + 1);
-        tunnel_daemon.setup_https(&args.domain, https_port).await?;
+        let cert_dir = args.cert_dir.clone().unwrap_or_else(cert::default_cert_dir);
+        let mut acme_renewal = None;
+        let resolver = match (&args.tls_cert, &args.tls_key, &acme_challenges) {
+            (Some(cert_path), Some(key_path), _) => {
+                let provided = cert::load_pem_files(cert_path, key_path, &args.domain)?;
+                SniResolver::with_certificate(&args.domain, provided)?
+            }
+            (_, _, Some(challenges)) => {
+                if args.acme_challenge == ChallengeType::Dns01 && args.no_dns {
+                    warn!("ACME dns-01 challenges are answered by the built-in DNS server; remove --no-dns");
+                }
+                let config = AcmeConfig {
+                    directory_url: args.acme_directory.clone(),
+                    contact: args.acme_email.clone(),
+                    challenge: args.acme_challenge,
+                    ca_bundle: args.acme_ca_bundle.clone(),
+                };
+                let client = Arc::new(AcmeClient::new(config, cert_dir.join("acme"), Arc::clone(challenges))?);
+
+                // Serve a local certificate, renewed like any other, until the
+                // first ACME order completes
+                let ca = Arc::new(CertificateAuthority::load_or_create(&ca_dir)?);
+                let resolver = SniResolver::new(Arc::new(CertStore::new(ca, cert_dir)), &args.domain)?;
+                let not_after = match client.load(&args.domain) {
+                    Some(stored) => {
+                        let not_after = stored.not_after;
+                        resolver.set_primary(stored)?;
+                        Some(not_after)
+                    }
+                    None => None,
+                };
+                acme_renewal = Some((client, not_after));
+                resolver
+            }
+            _ => {
+                let ca = Arc::new(CertificateAuthority::load_or_create(&ca_dir)?);
+                SniResolver::new(Arc::new(CertStore::new(ca, cert_dir)), &args.domain)?
+            }
+        };
+        let client_auth = match &args.client_ca {
+            Some(bundle) => Some(ClientAuth::load(bundle, !args.client_cert_optional)?),
+            None => None,
+        };
+        let resolver = tunnel_daemon.setup_https(resolver, client_auth, https_port).await?;
+        if let Some((client, not_after)) = acme_renewal {
+            AcmeClient::start_renewal_task(client, resolver, args.domain.clone(), not_after);
+        }
+        if args.redirect_https {
+            tunnel_daemon.set_https_redirect(HttpsRedirect::new(https_port, args.hsts_max_age, args.hsts_include_subdomains));
+        }
+    } else {
+        if args.client_ca.is_some() {
+            warn!("--client-ca only applies to HTTPS; add --https or --tls-cert");
+        }
+        if args.redirect_https {
+            warn!("--redirect-https only applies to HTTPS; add --https or --tls-cert");
+        }
     }
 
     // Set DNS resolver on tunnel daemon
-    if let Some(ref resolver) = dns_resolver {
-        tunnel_daemon.set_dns_resolver(resolver.clone());
+    tunnel_daemon.set_dns_resolver(dns_resolver.clone());
+
+    // Serve cacheable responses from the response cache
+    if cache_enabled {
+        tunnel_daemon.set_cache(Arc::clone(&cache));
+    }
+
+    // Configure access control
+    let mut auth = AuthConfig::new();
+    for user_pass in &args.auth {
+        auth.add_basic_credentials(user_pass)?;
+    }
+    for token in &args.tokens {
+        auth.add_token(token)?;
+    }
+    for list in &args.allow_ip {
+        auth.add_allowed(list)?;
+    }
+    for list in &args.deny_ip {
+        auth.add_denied(list)?;
+    }
+    auth.set_bypass(args.auth_bypass.clone());
+    if auth.is_enabled() {
+        if args.protocol == CliProtocol::Tcp {
+            warn!("--auth, --token, --allow-ip and --deny-ip are ignored in TCP mode");
+        } else {
+            info!("Access control enabled");
+            tunnel_daemon.set_auth(auth);
+        }
+    }
+
+    // Configure rate limits and the concurrency cap
+    let mut limiter = RateLimiter::new();
+    limiter.set_default_limit(args.rate_limit);
+    for rule in &args.context_rate_limits {
+        let (context, limit) = ratelimit::parse_context_rule(rule)?;
+        limiter.set_context_limit(context, limit);
+    }
+    if let Some(max) = args.max_concurrent {
+        limiter.set_max_concurrent(max);
+    }
+    if limiter.is_enabled() {
+        if args.protocol == CliProtocol::Tcp {
+            warn!("--rate-limit, --context-rate-limit and --max-concurrent are ignored in TCP mode");
+        } else {
+            info!("Rate limiting enabled");
+            let limiter = Arc::new(limiter);
+            RateLimiter::start_cleanup_task(Arc::clone(&limiter));
+            tunnel_daemon.set_rate_limiter(limiter);
+        }
+    }
+
+    // Capture traffic for the request inspector
+    if args.inspect {
+        if args.protocol == CliProtocol::Tcp {
+            warn!("--inspect is ignored in TCP mode");
+        } else {
+            let store = Arc::new(CaptureStore::new(args.inspect_history, args.inspect_body_limit));
+            tunnel_daemon.set_inspector(store, args.inspect_port);
+        }
     }
 
     // Handle shutdown gracefully
@@ -326,16 +826,87 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         _ = shutdown_signal => {
             info!("Shutting down gracefully...");
+            info!("Requests: {} total, {} failed; upstream pool: {} hits, {} misses",
+                  request_stats.total_requests.load(Ordering::Relaxed),
+                  request_stats.failed_requests.load(Ordering::Relaxed),
+                  request_stats.pool_hits(),
+                  request_stats.pool_misses.load(Ordering::Relaxed));
+            info!("Throttled: {} rate limited, {} over the concurrency limit",
+                  request_stats.rate_limited_requests.load(Ordering::Relaxed),
+                  request_stats.overloaded_requests.load(Ordering::Relaxed));
+            if cache_enabled {
+                let stats = cache.get_stats().await;
+                info!("Cache: {} hits, {} misses ({:.1}% hit rate), {} entries",
+                      stats.hits, stats.misses, stats.hit_rate() * 100.0, stats.entry_count);
+            }
             if let Some(mut tor) = tor_manager {
                 let _ = tor.shutdown().await;
             }
             if let Some(p2p) = p2p_manager {
                 let _ = p2p.shutdown().await;
             }
+            if let Some(mdns) = mdns_responder {
+                mdns.shutdown().await;
+            }
         }
     }
 
+    // Remove this daemon's hosts-file entries
+    dns_resolver.remove_local_dns_override().await;
+
     Ok(())
 }
 
+/// Port the hidden service forwards to. HTTP tunnels give onion visitors a
+/// dedicated loopback listener so they are never mistaken for local clients;
+/// raw TCP has no access control and keeps the listen port.
+fn onion_target_port(
+    protocol: CliProtocol,
+    listen_port: u16,
+    onion_listener: &mut Option<std::net::TcpListener>,
+) -> std::io::Result<u16> {
+    if protocol == CliProtocol::Tcp {
+        return Ok(listen_port);
+    }
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+    *onion_listener = Some(listener);
+    Ok(port)
+}
 
+/// Run a maintenance subcommand instead of the tunnel
+fn run_command(command: Command, ca_dir: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Ca { action } => {
+            let ca = CertificateAuthority::load_or_create(ca_dir)?;
+            match action {
+                CaAction::Export { format, output } => {
+                    let bytes = match format {
+                        CertFormat::Pem => ca.cert_pem().as_bytes().to_vec(),
+                        CertFormat::Der => ca.cert_der()?,
+                    };
+                    match output {
+                        Some(path) => {
+                            std::fs::write(&path, bytes)?;
+                            println!("Wrote {}", path.display());
+                        }
+                        None => std::io::Write::write_all(&mut std::io::stdout(), &bytes)?,
+                    }
+                }
+                CaAction::Install { nss_db, ca_certificates_dir } => {
+                    let (store, target) = match nss_db {
+                        Some(db) => (TrustStore::Nss(db.clone()), format!("NSS database {}", db.display())),
+                        None => {
+                            let dir = ca_certificates_dir.unwrap_or_else(|| cert::SYSTEM_CA_DIR.into());
+                            (TrustStore::Directory(dir.clone()), dir.display().to_string())
+                        }
+                    };
+                    ca.install(&store)?;
+                    println!("Installed {} ({}) into {}", cert::CA_NICKNAME, ca.cert_path().display(), target);
+                }
+            }
+        }
+    }
+    Ok(())
+}
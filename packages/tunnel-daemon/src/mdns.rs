@@ -0,0 +1,377 @@
+//! mDNS Module
+//!
+//! Multicast DNS responder for the resolver's `.local` domains, so phones and
+//! other machines on the LAN can resolve them without hosts-file edits.
+//! Answers on 224.0.0.251:5353 and [ff02::fb]:5353 with the LAN interface
+//! address, announces records on start and withdraws them on shutdown.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket as StdUdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use trust_dns_proto::op::{Message, MessageType, OpCode};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::dns::DualDNSResolver;
+
+/// mDNS port
+pub const MDNS_PORT: u16 = 5353;
+
+/// IPv4 mDNS group
+const MDNS_GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+/// IPv6 link-local mDNS group
+const MDNS_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// TTL for host address records (RFC 6762 recommends 120s)
+const HOST_TTL: u32 = 120;
+
+/// Number of unsolicited announcements sent on start
+const ANNOUNCEMENTS: usize = 2;
+
+/// Addresses advertised for the Beam domains
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LanAddresses {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl LanAddresses {
+    /// Addresses of the interfaces holding the default routes
+    pub fn detect() -> Self {
+        LanAddresses {
+            v4: route_source(SocketAddr::from(([192, 0, 2, 1], 9))).and_then(|ip| match ip {
+                IpAddr::V4(ip) if !ip.is_loopback() && !ip.is_unspecified() => Some(ip),
+                _ => None,
+            }),
+            v6: route_source(SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9))).and_then(|ip| match ip {
+                IpAddr::V6(ip) if !ip.is_loopback() && !ip.is_unspecified() => Some(ip),
+                _ => None,
+            }),
+        }
+    }
+
+    /// Use an explicit address instead of (or alongside) the detected ones
+    pub fn with(mut self, ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => self.v4 = Some(ip),
+            IpAddr::V6(ip) => self.v6 = Some(ip),
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_none() && self.v6.is_none()
+    }
+}
+
+/// Local address the OS would use to reach `target` (no packets are sent)
+fn route_source(target: SocketAddr) -> Option<IpAddr> {
+    let bind: SocketAddr = if target.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { (Ipv6Addr::UNSPECIFIED, 0).into() };
+    let socket = StdUdpSocket::bind(bind).ok()?;
+    socket.connect(target).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Record set and query matching for the advertised domains
+#[derive(Debug, Clone)]
+pub struct MdnsZone {
+    domains: Vec<Name>,
+    addresses: LanAddresses,
+}
+
+impl MdnsZone {
+    /// Advertise the resolver's `.local` domains at the given addresses
+    pub fn new(resolver: &DualDNSResolver, addresses: LanAddresses) -> Self {
+        let mut domains: Vec<Name> = resolver
+            .local_domains()
+            .filter(|d| d.ends_with(".local"))
+            .filter_map(|d| Name::from_ascii(format!("{}.", d)).ok())
+            .collect();
+        domains.sort();
+
+        MdnsZone { domains, addresses }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty() || self.addresses.is_empty()
+    }
+
+    /// Address records for one domain, with the cache-flush bit (we own these names)
+    fn records_for(&self, name: &Name, record_type: RecordType, ttl: u32) -> Vec<Record> {
+        let mut records = Vec::new();
+        let any = record_type == RecordType::ANY;
+
+        if let Some(ip) = self.addresses.v4.filter(|_| any || record_type == RecordType::A) {
+            records.push(Record::from_rdata(name.clone(), ttl, RData::A(ip)));
+        }
+        if let Some(ip) = self.addresses.v6.filter(|_| any || record_type == RecordType::AAAA) {
+            records.push(Record::from_rdata(name.clone(), ttl, RData::AAAA(ip)));
+        }
+
+        for record in &mut records {
+            record.set_mdns_cache_flush(true);
+        }
+        records
+    }
+
+    /// Unsolicited response carrying every record (TTL 0 withdraws them)
+    pub fn announcement(&self, ttl: u32) -> Message {
+        let mut message = response_message(0);
+        for name in &self.domains {
+            message.add_answers(self.records_for(name, RecordType::ANY, ttl));
+        }
+        message
+    }
+
+    /// Answer a query message, if any of its questions are ours
+    pub fn respond(&self, query: &Message, legacy_unicast: bool) -> Option<Message> {
+        if query.message_type() != MessageType::Query || query.op_code() != OpCode::Query {
+            return None;
+        }
+
+        // Legacy unicast resolvers expect a conventional DNS reply
+        let mut response = response_message(if legacy_unicast { query.id() } else { 0 });
+        for question in query.queries() {
+            // Subdomains resolve to the same tunnel (`*.domain`)
+            if self.domains.iter().any(|d| d.zone_of(question.name())) {
+                let mut records = self.records_for(question.name(), question.query_type(), HOST_TTL);
+                if legacy_unicast {
+                    response.add_query(question.clone());
+                    for record in &mut records {
+                        record.set_mdns_cache_flush(false);
+                    }
+                }
+                response.add_answers(records);
+            }
+        }
+
+        (!response.answers().is_empty()).then_some(response)
+    }
+}
+
+fn response_message(id: u16) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_authoritative(true);
+    message
+}
+
+/// A socket joined to one mDNS group
+struct MdnsSocket {
+    socket: UdpSocket,
+    group: SocketAddr,
+}
+
+impl MdnsSocket {
+    fn bind_v4() -> std::io::Result<Self> {
+        let socket = shared_socket(socket2::Domain::IPV4)?;
+        socket.bind(&SocketAddr::from(([0, 0, 0, 0], MDNS_PORT)).into())?;
+        socket.join_multicast_v4(&MDNS_GROUP_V4, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(255)?;
+
+        Ok(MdnsSocket {
+            socket: UdpSocket::from_std(socket.into())?,
+            group: SocketAddrV4::new(MDNS_GROUP_V4, MDNS_PORT).into(),
+        })
+    }
+
+    fn bind_v6() -> std::io::Result<Self> {
+        let socket = shared_socket(socket2::Domain::IPV6)?;
+        socket.set_only_v6(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+        socket.join_multicast_v6(&MDNS_GROUP_V6, 0)?;
+        socket.set_multicast_loop_v6(true)?;
+        socket.set_multicast_hops_v6(255)?;
+
+        Ok(MdnsSocket {
+            socket: UdpSocket::from_std(socket.into())?,
+            group: SocketAddrV6::new(MDNS_GROUP_V6, MDNS_PORT, 0, 0).into(),
+        })
+    }
+
+    async fn send(&self, message: &Message, to: SocketAddr) {
+        match message.to_vec() {
+            Ok(bytes) => {
+                if let Err(e) = self.socket.send_to(&bytes, to).await {
+                    debug!("mDNS send to {} failed: {}", to, e);
+                }
+            }
+            Err(e) => warn!("Failed to encode mDNS message: {}", e),
+        }
+    }
+}
+
+/// UDP socket that shares port 5353 with other responders on the host
+fn shared_socket(domain: socket2::Domain) -> std::io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Running responder; call `shutdown` to withdraw the records
+pub struct MdnsResponder {
+    zone: Arc<MdnsZone>,
+    sockets: Vec<Arc<MdnsSocket>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MdnsResponder {
+    /// Join the mDNS groups, announce the zone and answer queries in the background
+    pub async fn start(zone: MdnsZone) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut sockets = Vec::new();
+        match MdnsSocket::bind_v4() {
+            Ok(socket) => sockets.push(Arc::new(socket)),
+            Err(e) => warn!("mDNS unavailable on IPv4: {}", e),
+        }
+        if zone.addresses.v6.is_some() {
+            match MdnsSocket::bind_v6() {
+                Ok(socket) => sockets.push(Arc::new(socket)),
+                Err(e) => debug!("mDNS unavailable on IPv6: {}", e),
+            }
+        }
+        if sockets.is_empty() {
+            return Err("could not join any mDNS multicast group".into());
+        }
+
+        let zone = Arc::new(zone);
+        let mut tasks = Vec::new();
+        for socket in &sockets {
+            tasks.push(tokio::spawn(answer_queries(Arc::clone(&zone), Arc::clone(socket))));
+        }
+
+        // Announce (RFC 6762 §8.3): at least two unsolicited responses, one second apart
+        let announce_zone = Arc::clone(&zone);
+        let announce_sockets = sockets.clone();
+        tasks.push(tokio::spawn(async move {
+            let announcement = announce_zone.announcement(HOST_TTL);
+            for round in 0..ANNOUNCEMENTS {
+                if round > 0 {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                for socket in &announce_sockets {
+                    socket.send(&announcement, socket.group).await;
+                }
+            }
+        }));
+
+        for name in &zone.domains {
+            info!("mDNS: advertising {} → {}", name, describe(&zone.addresses));
+        }
+
+        Ok(MdnsResponder { zone, sockets, tasks })
+    }
+
+    /// Send goodbye packets (TTL 0) and stop answering
+    pub async fn shutdown(self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+
+        let goodbye = self.zone.announcement(0);
+        for socket in &self.sockets {
+            socket.send(&goodbye, socket.group).await;
+        }
+        info!("mDNS: withdrew {} domain(s)", self.zone.domains.len());
+    }
+}
+
+async fn answer_queries(zone: Arc<MdnsZone>, socket: Arc<MdnsSocket>) {
+    let mut buf = vec![0u8; 9000];
+
+    loop {
+        let (len, src) = match socket.socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("mDNS receive error: {}", e);
+                continue;
+            }
+        };
+
+        let query = match Message::from_vec(&buf[..len]) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+
+        // Queries from a port other than 5353 come from simple (legacy) resolvers
+        let legacy_unicast = src.port() != MDNS_PORT;
+        let unicast = legacy_unicast || query.queries().iter().any(|q| q.mdns_unicast_response());
+
+        if let Some(response) = zone.respond(&query, legacy_unicast) {
+            debug!("mDNS: answering {} question(s) from {}", query.queries().len(), src);
+            socket.send(&response, if unicast { src } else { socket.group }).await;
+        }
+    }
+}
+
+fn describe(addresses: &LanAddresses) -> String {
+    [addresses.v4.map(IpAddr::V4), addresses.v6.map(IpAddr::V6)]
+        .iter()
+        .flatten()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_proto::op::Query;
+
+    fn zone() -> MdnsZone {
+        let mut resolver = DualDNSResolver::new();
+        resolver.add_local_domain("myapp.local", IpAddr::from([127, 0, 0, 1]));
+        resolver.add_local_domain("example.com", IpAddr::from([127, 0, 0, 1]));
+        MdnsZone::new(&resolver, LanAddresses {
+            v4: Some(Ipv4Addr::new(192, 168, 1, 20)),
+            v6: Some("fe80::1".parse().unwrap()),
+        })
+    }
+
+    fn query(name: &str, record_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message.set_id(7).add_query(Query::query(Name::from_ascii(name).unwrap(), record_type));
+        message
+    }
+
+    #[test]
+    fn test_only_local_domains_are_advertised() {
+        let announcement = zone().announcement(HOST_TTL);
+        assert_eq!(announcement.answers().len(), 2);
+        assert!(announcement.answers().iter().all(|r| r.name().to_ascii() == "myapp.local."));
+        assert!(announcement.answers().iter().all(|r| r.mdns_cache_flush()));
+
+        let goodbye = zone().announcement(0);
+        assert!(goodbye.answers().iter().all(|r| r.ttl() == 0));
+    }
+
+    #[test]
+    fn test_respond_to_queries() {
+        let zone = zone();
+
+        let response = zone.respond(&query("MyApp.local.", RecordType::A), false).unwrap();
+        assert_eq!(response.id(), 0);
+        assert_eq!(response.answers()[0].data(), Some(&RData::A(Ipv4Addr::new(192, 168, 1, 20))));
+        assert!(response.queries().is_empty());
+
+        let response = zone.respond(&query("myapp.local.", RecordType::AAAA), true).unwrap();
+        assert_eq!(response.id(), 7);
+        assert_eq!(response.queries().len(), 1);
+        assert!(!response.answers()[0].mdns_cache_flush());
+
+        let response = zone.respond(&query("tenant1.myapp.local.", RecordType::A), false).unwrap();
+        assert_eq!(response.answers()[0].name().to_ascii(), "tenant1.myapp.local.");
+
+        assert!(zone.respond(&query("other.local.", RecordType::A), false).is_none());
+        assert!(zone.respond(&query("myapp.local.", RecordType::TXT), false).is_none());
+    }
+}
@@ -141,6 +141,12 @@ pub struct PerformanceConfig {
     /// Maximum number of pooled connections
     pub max_pooled_connections: u32,
 
+    /// Seconds an idle pooled connection is kept open
+    pub pool_idle_timeout_secs: u64,
+
+    /// Timeout for connecting to the local application in milliseconds
+    pub connect_timeout_ms: u64,
+
     /// Enable circuit prebuilding (build circuits before needed)
     pub circuit_prebuilding: bool,
 
@@ -159,6 +165,8 @@ impl Default for PerformanceConfig {
             max_cache_size: 100, // 100 MB
             connection_pooling: true,
             max_pooled_connections: 10,
+            pool_idle_timeout_secs: 90,
+            connect_timeout_ms: 5000,
             circuit_prebuilding: true,
             prebuild_circuit_count: 3,
         }
@@ -177,6 +185,8 @@ impl PerformanceConfig {
             max_cache_size: 50,
             connection_pooling: true,
             max_pooled_connections: 20,
+            pool_idle_timeout_secs: 90,
+            connect_timeout_ms: 2000,
             circuit_prebuilding: false,
             prebuild_circuit_count: 0,
         }
@@ -193,6 +203,8 @@ impl PerformanceConfig {
             max_cache_size: 100,
             connection_pooling: true,
             max_pooled_connections: 10,
+            pool_idle_timeout_secs: 60,
+            connect_timeout_ms: 5000,
             circuit_prebuilding: true,
             prebuild_circuit_count: 2,
         }
@@ -209,6 +221,8 @@ impl PerformanceConfig {
             max_cache_size: 0,
             connection_pooling: true,
             max_pooled_connections: 5,
+            pool_idle_timeout_secs: 30,
+            connect_timeout_ms: 5000,
             circuit_prebuilding: true,
             prebuild_circuit_count: 5, // More circuits for better anonymity
         }
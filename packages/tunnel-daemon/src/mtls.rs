@@ -0,0 +1,144 @@
+//! Client Certificate Module
+//!
+//! Optional mutual TLS on the HTTPS listener. Client certificates are
+//! verified against a CA bundle, either required for every connection or
+//! only checked when a client offers one. The verified subject is passed to
+//! the local application in headers and serves as the client's identity for
+//! access control and rate limits. Clients can never set those headers
+//! themselves: incoming copies are always removed. When certificates are
+//! required, nothing is served without one: plain HTTP requests (onion
+//! visitors included) are redirected to HTTPS if that is enabled and refused
+//! otherwise.
+
+use hyper::header::{HeaderMap, HeaderValue};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier};
+use rustls::{Certificate, RootCertStore};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+pub const X_CLIENT_CERT_SUBJECT: &str = "x-client-cert-subject";
+pub const X_CLIENT_CERT_FINGERPRINT: &str = "x-client-cert-fingerprint";
+
+/// Client certificate verification for the HTTPS listener
+pub struct ClientAuth {
+    /// CAs client certificates must chain to
+    roots: RootCertStore,
+
+    /// Refuse handshakes without a client certificate
+    required: bool,
+}
+
+impl ClientAuth {
+    /// Load the client CA bundle (PEM, one or more certificates)
+    pub fn load(bundle: &Path, required: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = File::open(bundle).map_err(|e| format!("Cannot read {}: {}", bundle.display(), e))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .map_err(|e| format!("Invalid PEM in {}: {}", bundle.display(), e))?;
+
+        let mut roots = RootCertStore::empty();
+        let (added, skipped) = roots.add_parsable_certificates(&certs);
+        if added == 0 {
+            return Err(format!("No usable CA certificates found in {}", bundle.display()).into());
+        }
+        info!(
+            "Loaded {} client CA certificate(s) from {:?} ({} skipped), client certificates {}",
+            added,
+            bundle,
+            skipped,
+            if required { "required" } else { "optional" }
+        );
+
+        Ok(ClientAuth { roots, required })
+    }
+
+    /// Whether every client must present a certificate
+    pub fn is_required(&self) -> bool {
+        self.required
+    }
+
+    /// Verifier for the rustls server config
+    pub fn verifier(&self) -> Arc<dyn ClientCertVerifier> {
+        if self.required {
+            AllowAnyAuthenticatedClient::new(self.roots.clone()).boxed()
+        } else {
+            AllowAnyAnonymousOrAuthenticatedClient::new(self.roots.clone()).boxed()
+        }
+    }
+}
+
+/// A verified client certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Subject distinguished name, e.g. `CN=alice, O=Team`
+    pub subject: String,
+
+    /// Hex SHA-256 of the certificate
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    /// Client certificate of a finished handshake (the leaf comes first)
+    pub fn from_peer_certificates(certs: Option<&[Certificate]>) -> Option<Self> {
+        let leaf = certs?.first()?;
+        let (_, parsed) = x509_parser::parse_x509_certificate(&leaf.0).ok()?;
+        Some(ClientCert {
+            subject: parsed.subject().to_string(),
+            fingerprint: Sha256::digest(&leaf.0).iter().map(|b| format!("{:02x}", b)).collect(),
+        })
+    }
+
+    /// Identity used by access control and rate limits
+    pub fn identity(&self) -> String {
+        format!("cert:{}", self.subject)
+    }
+}
+
+/// Replace any client-supplied certificate headers with the verified certificate
+pub fn apply_client_cert_headers(headers: &mut HeaderMap, cert: Option<&ClientCert>) {
+    headers.remove(X_CLIENT_CERT_SUBJECT);
+    headers.remove(X_CLIENT_CERT_FINGERPRINT);
+
+    if let Some(cert) = cert {
+        if let Ok(value) = HeaderValue::from_str(&cert.subject) {
+            headers.insert(X_CLIENT_CERT_SUBJECT, value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&cert.fingerprint) {
+            headers.insert(X_CLIENT_CERT_FINGERPRINT, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{Certificate as RcgenCertificate, CertificateParams, DnType};
+
+    #[test]
+    fn test_client_cert_headers_replace_spoofed_ones() {
+        let mut params = CertificateParams::new(Vec::new());
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params.distinguished_name.push(DnType::OrganizationName, "Team");
+        let der = RcgenCertificate::from_params(params).unwrap().serialize_der().unwrap();
+
+        let cert = ClientCert::from_peer_certificates(Some(&[Certificate(der)])).unwrap();
+        assert!(cert.subject.contains("CN=alice"), "{}", cert.subject);
+        assert_eq!(cert.fingerprint.len(), 64);
+        assert!(cert.identity().starts_with("cert:"));
+        assert_eq!(ClientCert::from_peer_certificates(None), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(X_CLIENT_CERT_SUBJECT, HeaderValue::from_static("CN=admin"));
+        apply_client_cert_headers(&mut headers, Some(&cert));
+        assert_eq!(headers.get(X_CLIENT_CERT_SUBJECT).unwrap(), cert.subject.as_str());
+        assert_eq!(headers.get(X_CLIENT_CERT_FINGERPRINT).unwrap(), cert.fingerprint.as_str());
+
+        // Without a verified certificate the headers are only stripped
+        apply_client_cert_headers(&mut headers, None);
+        assert!(headers.get(X_CLIENT_CERT_SUBJECT).is_none());
+        assert!(headers.get(X_CLIENT_CERT_FINGERPRINT).is_none());
+    }
+}
@@ -23,6 +23,9 @@ pub struct P2PManager {
     /// Our public address (discovered via STUN)
     public_addr: Option<SocketAddr>,
 
+    /// Port peers open raw TCP streams to, when the tunnel carries them
+    stream_port: Option<u16>,
+
     /// Whether we're behind NAT
     behind_nat: bool,
 
@@ -114,6 +117,7 @@ impl P2PManager {
             connections: Arc::new(RwLock::new(HashMap::new())),
             stun_servers,
             public_addr: None,
+            stream_port: None,
             behind_nat: false,
             stats: Arc::new(RwLock::new(P2PStats::default())),
         };
@@ -123,6 +127,16 @@ impl P2PManager {
         Ok(manager)
     }
 
+    /// Open the listener peers use for raw TCP tunnels. It gets a port of its
+    /// own so the tunnel's listen port can stay on the configured bind address;
+    /// the connection token advertises it.
+    pub fn bind_stream_listener(&mut self) -> std::io::Result<std::net::TcpListener> {
+        let listener = std::net::TcpListener::bind(SocketAddr::new(self.local_addr.ip(), 0))?;
+        self.stream_port = Some(listener.local_addr()?.port());
+        info!("P2P stream listener on {}", listener.local_addr()?);
+        Ok(listener)
+    }
+
     /// Discover our public address using STUN
     pub async fn discover_public_address(&mut self) -> Result<SocketAddr, Box<dyn std::error::Error>> {
         info!("Discovering public address via STUN...");
@@ -225,7 +239,10 @@ impl P2PManager {
 
     /// Generate a shareable connection token for P2P
     pub fn generate_connection_token(&self) -> String {
-        let addr = self.public_addr.unwrap_or(self.local_addr);
+        let mut addr = self.public_addr.unwrap_or(self.local_addr);
+        if let Some(port) = self.stream_port {
+            addr.set_port(port);
+        }
 
         // Base64 encode the connection info
         let info = format!("beam-p2p:{}:{}", addr.ip(), addr.port());
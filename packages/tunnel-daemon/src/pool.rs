@@ -0,0 +1,72 @@
+//! Upstream Connection Pool
+//!
+//! Provides the shared keep-alive HTTP client used to reach the local
+//! application, sized from the active mode's `PerformanceConfig`.
+
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client, Uri};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+use crate::mode::PerformanceConfig;
+use crate::tunnel::RequestStats;
+
+/// HTTP client used for all upstream requests
+pub type UpstreamClient = Client<CountingConnector, Body>;
+
+/// Connector that counts every new upstream connection as a pool miss
+#[derive(Clone)]
+pub struct CountingConnector {
+    /// Underlying TCP connector
+    inner: HttpConnector,
+
+    /// Shared request statistics
+    stats: Arc<RequestStats>,
+}
+
+impl Service<Uri> for CountingConnector {
+    type Response = TcpStream;
+    type Error = <HttpConnector as Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        self.stats.pool_misses.fetch_add(1, Ordering::Relaxed);
+        debug!("Opening new upstream connection to {}", uri);
+        Box::pin(self.inner.call(uri))
+    }
+}
+
+/// Build the upstream client from the performance configuration
+pub fn build_upstream_client(config: &PerformanceConfig, stats: Arc<RequestStats>) -> UpstreamClient {
+    let mut connector = HttpConnector::new();
+    connector.set_nodelay(true);
+    connector.set_connect_timeout(Some(Duration::from_millis(config.connect_timeout_ms)));
+
+    // Disabling pooling keeps no idle connections around
+    let max_idle = if config.connection_pooling {
+        config.max_pooled_connections as usize
+    } else {
+        0
+    };
+
+    info!(
+        "Upstream pool: {} idle connections, {}s idle timeout, {}ms connect timeout",
+        max_idle, config.pool_idle_timeout_secs, config.connect_timeout_ms
+    );
+
+    Client::builder()
+        .pool_max_idle_per_host(max_idle)
+        .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
+        .build(CountingConnector { inner: connector, stats })
+}
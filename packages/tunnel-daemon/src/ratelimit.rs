@@ -0,0 +1,364 @@
+//! Rate Limiting Module
+//!
+//! Token-bucket limits per client (authenticated identity, or IP address for
+//! anonymous clients; anonymous onion visitors, who all arrive from loopback,
+//! share one client), configurable per access context, plus a global cap on
+//! requests in flight. Throttled requests get 429 and overload gets 503, both
+//! with `Retry-After`. Each client has one bucket whatever context its
+//! requests are detected as, held to the strictest limit it has been under,
+//! so switching contexts never adds allowance. The number of buckets is
+//! capped; when full, the least recently used ones are dropped.
+
+use hyper::{Body, Response, StatusCode, header};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::context::AccessContext;
+
+/// Buckets untouched for this long are dropped by the cleanup task
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(600);
+
+/// Upper bound on buckets kept between cleanups
+const MAX_BUCKETS: usize = 65_536;
+
+/// Retry-After sent when the concurrency cap is reached
+const OVERLOAD_RETRY_AFTER_SECS: u64 = 1;
+
+/// A token-bucket rate: `rate` requests per second with bursts up to `burst`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Tokens added per second
+    pub rate: f64,
+
+    /// Bucket capacity
+    pub burst: f64,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parse `<n>/<s|m|h>[:burst]`, e.g. `10/s`, `600/m:50`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rate limit '{}'. Expected <n>/<s|m|h>[:burst], e.g. 10/s or 600/m:50", s);
+
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst.trim().parse::<u32>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let (count, unit) = rate.split_once('/').ok_or_else(invalid)?;
+        let count: u32 = count.trim().parse().map_err(|_| invalid())?;
+        let per_secs = match unit.trim() {
+            "s" | "sec" | "second" => 1.0,
+            "m" | "min" | "minute" => 60.0,
+            "h" | "hour" => 3600.0,
+            _ => return Err(invalid()),
+        };
+
+        let burst = burst.unwrap_or(count);
+        if count == 0 || burst == 0 {
+            return Err(invalid());
+        }
+
+        Ok(RateLimit {
+            rate: count as f64 / per_secs,
+            burst: burst as f64,
+        })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}/s (burst {})", self.rate, self.burst)
+    }
+}
+
+/// Parse a per-context rule: `CONTEXT=RATE` or `CONTEXT=none`
+pub fn parse_context_rule(rule: &str) -> Result<(AccessContext, Option<RateLimit>), String> {
+    let (context, limit) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid rate limit rule '{}'. Expected CONTEXT=RATE", rule))?;
+
+    let limit = if limit.trim().eq_ignore_ascii_case("none") {
+        None
+    } else {
+        Some(limit.trim().parse()?)
+    };
+
+    Ok((context.trim().parse()?, limit))
+}
+
+/// Why a request was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttle {
+    /// Client exceeded its rate; retry after the given delay (429)
+    RateLimited(Duration),
+    /// Too many requests in flight (503)
+    Overloaded,
+}
+
+impl Throttle {
+    /// Response for a throttled request
+    pub fn response(&self) -> Response<Body> {
+        let (status, retry_after, message) = match *self {
+            Throttle::RateLimited(wait) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                wait.as_secs_f64().ceil().max(1.0) as u64,
+                "Rate limit exceeded",
+            ),
+            Throttle::Overloaded => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                OVERLOAD_RETRY_AFTER_SECS,
+                "Too many concurrent requests",
+            ),
+        };
+
+        Response::builder()
+            .status(status)
+            .header(header::RETRY_AFTER, retry_after)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{"error": "{}"}}"#, message)))
+            .unwrap()
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+
+    /// Strictest limit applied to the client so far
+    limit: RateLimit,
+}
+
+/// Rate limits and concurrency cap shared by all requests
+pub struct RateLimiter {
+    /// Limit for contexts without their own rule (unlimited if `None`)
+    default_limit: Option<RateLimit>,
+
+    /// Per-context overrides (`None` means unlimited)
+    context_limits: HashMap<AccessContext, Option<RateLimit>>,
+
+    /// Token buckets by client key
+    buckets: Mutex<HashMap<String, Bucket>>,
+
+    /// Permits for requests in flight, if capped
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    /// A limiter that lets everything through
+    pub fn new() -> Self {
+        RateLimiter {
+            default_limit: None,
+            context_limits: HashMap::new(),
+            buckets: Mutex::new(HashMap::new()),
+            concurrency: None,
+        }
+    }
+
+    /// Rate limit applied to every context without its own rule
+    pub fn set_default_limit(&mut self, limit: Option<RateLimit>) {
+        self.default_limit = limit;
+    }
+
+    /// Rate limit for one context (`None` exempts it)
+    pub fn set_context_limit(&mut self, context: AccessContext, limit: Option<RateLimit>) {
+        self.context_limits.insert(context, limit);
+    }
+
+    /// Cap the number of requests in flight
+    pub fn set_max_concurrent(&mut self, max: usize) {
+        self.concurrency = Some(Arc::new(Semaphore::new(max)));
+    }
+
+    /// Whether any limit is configured
+    pub fn is_enabled(&self) -> bool {
+        self.default_limit.is_some()
+            || self.context_limits.values().any(Option::is_some)
+            || self.concurrency.is_some()
+    }
+
+    fn limit_for(&self, context: AccessContext) -> Option<RateLimit> {
+        self.context_limits.get(&context).copied().unwrap_or(self.default_limit)
+    }
+
+    /// Take a token for `client` in `context`, or say how long to wait
+    pub fn check_rate(&self, context: AccessContext, client: &str) -> Result<(), Throttle> {
+        let limit = match self.limit_for(context) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(client) {
+            evict_least_recent(&mut buckets);
+        }
+        let bucket = buckets
+            .entry(client.to_string())
+            .or_insert(Bucket { tokens: limit.burst, updated: now, limit });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.limit.rate).min(bucket.limit.burst);
+        bucket.updated = now;
+        bucket.limit = RateLimit {
+            rate: bucket.limit.rate.min(limit.rate),
+            burst: bucket.limit.burst.min(limit.burst),
+        };
+        bucket.tokens = bucket.tokens.min(bucket.limit.burst);
+        let limit = bucket.limit;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / limit.rate;
+            debug!("Rate limited {} ({:?}), retry in {:.2}s", client, context, wait);
+            Err(Throttle::RateLimited(Duration::from_secs_f64(wait)))
+        }
+    }
+
+    /// Reserve a slot for a request in flight; the slot frees when the permit drops
+    pub fn try_acquire(&self) -> Result<Option<OwnedSemaphorePermit>, Throttle> {
+        match self.concurrency {
+            Some(ref semaphore) => Arc::clone(semaphore)
+                .try_acquire_owned()
+                .map(Some)
+                .map_err(|_| Throttle::Overloaded),
+            None => Ok(None),
+        }
+    }
+
+    /// Drop buckets that have been idle long enough to be full again; a
+    /// client's strictest limit is forgotten with its bucket
+    pub fn cleanup(&self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|_, bucket| bucket.updated.elapsed() < IDLE_BUCKET_TTL);
+        if buckets.len() < before {
+            debug!("Dropped {} idle rate limit buckets", before - buckets.len());
+        }
+    }
+
+    /// Start background task to prune idle buckets
+    pub fn start_cleanup_task(limiter: Arc<RateLimiter>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+            loop {
+                interval.tick().await;
+                limiter.cleanup();
+            }
+        })
+    }
+}
+
+/// Drop the least recently used eighth of the buckets
+fn evict_least_recent(buckets: &mut HashMap<String, Bucket>) {
+    let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+    let index = updated.len() / 8;
+    let (_, cutoff, _) = updated.select_nth_unstable(index);
+    let cutoff = *cutoff;
+
+    let before = buckets.len();
+    buckets.retain(|_, bucket| bucket.updated > cutoff);
+    debug!("Rate limit buckets full, dropped {} least recently used", before - buckets.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!("10/s".parse::<RateLimit>().unwrap(), RateLimit { rate: 10.0, burst: 10.0 });
+        assert_eq!("120/m:5".parse::<RateLimit>().unwrap(), RateLimit { rate: 2.0, burst: 5.0 });
+        assert!("10".parse::<RateLimit>().is_err());
+        assert!("0/s".parse::<RateLimit>().is_err());
+        assert!("10/d".parse::<RateLimit>().is_err());
+
+        let (context, limit) = parse_context_rule("local=none").unwrap();
+        assert_eq!(context, AccessContext::LocalBrowser);
+        assert_eq!(limit, None);
+    }
+
+    #[test]
+    fn test_token_bucket_per_client_and_context() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_default_limit(Some("1/m:2".parse().unwrap()));
+        limiter.set_context_limit(AccessContext::LocalBrowser, None);
+        let ctx = AccessContext::ExternalAccess;
+
+        assert!(limiter.check_rate(ctx, "203.0.113.1").is_ok());
+        assert!(limiter.check_rate(ctx, "203.0.113.1").is_ok());
+        match limiter.check_rate(ctx, "203.0.113.1") {
+            Err(Throttle::RateLimited(wait)) => assert!(wait > Duration::from_secs(55)),
+            other => panic!("expected rate limit, got {:?}", other),
+        }
+
+        // Other clients and exempt contexts have their own allowance
+        assert!(limiter.check_rate(ctx, "203.0.113.2").is_ok());
+        for _ in 0..10 {
+            assert!(limiter.check_rate(AccessContext::LocalBrowser, "127.0.0.1").is_ok());
+        }
+
+        let response = Throttle::RateLimited(Duration::from_millis(1500)).response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+    }
+
+    #[test]
+    fn test_one_bucket_per_client_at_strictest_limit() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_default_limit(Some("1/m:3".parse().unwrap()));
+        limiter.set_context_limit(AccessContext::APIClient, Some("1/m:1".parse().unwrap()));
+        let client = "203.0.113.1";
+
+        // Claiming another context draws from the same bucket
+        assert!(limiter.check_rate(AccessContext::ExternalAccess, client).is_ok());
+        assert!(limiter.check_rate(AccessContext::WebhookService, client).is_ok());
+        assert!(limiter.check_rate(AccessContext::APIClient, client).is_ok());
+        assert!(limiter.check_rate(AccessContext::WebhookService, client).is_err());
+
+        // Once held to the stricter burst, looser contexts don't restore it
+        let mut limiter = RateLimiter::new();
+        limiter.set_default_limit(Some("1/m:3".parse().unwrap()));
+        limiter.set_context_limit(AccessContext::APIClient, Some("1/m:1".parse().unwrap()));
+        assert!(limiter.check_rate(AccessContext::APIClient, client).is_ok());
+        assert!(limiter.check_rate(AccessContext::ExternalAccess, client).is_err());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_bucket_count_is_capped() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_default_limit(Some("1/m".parse().unwrap()));
+        for i in 0..MAX_BUCKETS + 10 {
+            let _ = limiter.check_rate(AccessContext::ExternalAccess, &format!("2001:db8::{:x}", i));
+        }
+        assert!(limiter.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+    }
+
+    #[test]
+    fn test_concurrency_cap() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_max_concurrent(1);
+
+        let permit = limiter.try_acquire().unwrap();
+        assert!(permit.is_some());
+        assert_eq!(limiter.try_acquire().unwrap_err(), Throttle::Overloaded);
+        drop(permit);
+        assert!(limiter.try_acquire().is_ok());
+    }
+}
@@ -0,0 +1,161 @@
+//! HTTPS Redirect Module
+//!
+//! Optional redirect of plain HTTP requests to the HTTPS listener, with an
+//! HSTS header on HTTPS responses so browsers go straight to HTTPS next time.
+//! Onion hosts are never redirected: the onion service only exposes the plain
+//! listener, and Tor already encrypts the connection end to end. HSTS is left
+//! out for localhost, IP addresses and `.local` names: browsers would pin
+//! HTTPS for every other local server on the same name.
+
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::net::IpAddr;
+
+use crate::routing;
+
+/// Five minutes, so a policy sent by mistake wears off quickly; raise it
+/// once HTTPS works for the domain
+pub const DEFAULT_HSTS_MAX_AGE: u64 = 300;
+
+/// Redirect settings for the plain HTTP listener
+#[derive(Debug, Clone)]
+pub struct HttpsRedirect {
+    /// Port of the HTTPS listener (left out of the location when 443)
+    https_port: u16,
+
+    /// HSTS max-age in seconds, 0 to send no HSTS header
+    hsts_max_age: u64,
+
+    /// Extend HSTS to every subdomain (tenant subdomains included)
+    include_subdomains: bool,
+}
+
+impl HttpsRedirect {
+    pub fn new(https_port: u16, hsts_max_age: u64, include_subdomains: bool) -> Self {
+        HttpsRedirect { https_port, hsts_max_age, include_subdomains }
+    }
+
+    /// HTTPS URL for a plain HTTP request, if it should be redirected
+    pub fn location(&self, req: &Request<Body>) -> Option<String> {
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().host())
+            .map(routing::strip_port)?;
+        if host.is_empty() || host.trim_end_matches('.').to_ascii_lowercase().ends_with(".onion") {
+            return None;
+        }
+
+        let path_and_query = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+        Some(match self.https_port {
+            443 => format!("https://{}{}", host, path_and_query),
+            port => format!("https://{}:{}{}", host, port, path_and_query),
+        })
+    }
+
+    /// Redirect response for a plain HTTP request, if it should be redirected
+    pub fn redirect(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let location = self.location(req)?;
+
+        // 308 keeps the method and body of non-GET requests
+        let status = match *req.method() {
+            Method::GET | Method::HEAD => StatusCode::MOVED_PERMANENTLY,
+            _ => StatusCode::PERMANENT_REDIRECT,
+        };
+        Response::builder()
+            .status(status)
+            .header(header::LOCATION, location)
+            .body(Body::empty())
+            .ok()
+    }
+
+    /// Strict-Transport-Security value for HTTPS responses
+    pub fn hsts_header(&self) -> Option<HeaderValue> {
+        if self.hsts_max_age == 0 {
+            return None;
+        }
+        let mut value = format!("max-age={}", self.hsts_max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+
+    /// Add HSTS to an HTTPS response for `host` unless the application set
+    /// its own or the host is a local development name
+    pub fn apply_hsts(&self, host: Option<&str>, response: &mut Response<Body>) {
+        if !host.is_some_and(hsts_applies) {
+            return;
+        }
+        if let Some(value) = self.hsts_header() {
+            response.headers_mut().entry(header::STRICT_TRANSPORT_SECURITY).or_insert(value);
+        }
+    }
+}
+
+/// Whether browsers should pin HTTPS for a Host header value
+fn hsts_applies(host: &str) -> bool {
+    let host = routing::strip_port(host).trim_start_matches('[').trim_end_matches(']');
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    !(host.is_empty()
+        || host.parse::<IpAddr>().is_ok()
+        || host == "localhost"
+        || host.ends_with(".localhost")
+        || host.ends_with(".local"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, host: &str, uri: &str) -> Request<Body> {
+        Request::builder().method(method).uri(uri).header(header::HOST, host).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_redirect_location_and_hsts() {
+        let redirect = HttpsRedirect::new(8443, 31_536_000, false);
+
+        let res = redirect.redirect(&request(Method::GET, "myapp.local:8080", "/a/b?x=1")).unwrap();
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()[header::LOCATION], "https://myapp.local:8443/a/b?x=1");
+
+        let res = redirect.redirect(&request(Method::POST, "[::1]:8080", "/hook")).unwrap();
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[header::LOCATION], "https://[::1]:8443/hook");
+
+        // Standard port is left out, onion hosts stay on HTTP
+        let standard = HttpsRedirect::new(443, 0, false);
+        assert_eq!(standard.location(&request(Method::GET, "myapp.local", "/")).unwrap(), "https://myapp.local/");
+        assert!(standard.location(&request(Method::GET, "abcdef.onion", "/")).is_none());
+
+        assert_eq!(redirect.hsts_header().unwrap(), "max-age=31536000");
+        assert_eq!(HttpsRedirect::new(443, 60, true).hsts_header().unwrap(), "max-age=60; includeSubDomains");
+        assert!(standard.hsts_header().is_none());
+
+        // The application's own policy wins
+        let mut res = Response::builder()
+            .header(header::STRICT_TRANSPORT_SECURITY, "max-age=0")
+            .body(Body::empty())
+            .unwrap();
+        redirect.apply_hsts(Some("myapp.example.com"), &mut res);
+        assert_eq!(res.headers()[header::STRICT_TRANSPORT_SECURITY], "max-age=0");
+    }
+
+    #[test]
+    fn test_no_hsts_for_local_hosts() {
+        let redirect = HttpsRedirect::new(443, DEFAULT_HSTS_MAX_AGE, false);
+        let hsts = |host: Option<&str>| {
+            let mut res = Response::new(Body::empty());
+            redirect.apply_hsts(host, &mut res);
+            res.headers().get(header::STRICT_TRANSPORT_SECURITY).cloned()
+        };
+
+        assert_eq!(hsts(Some("myapp.example.com:8443")).unwrap(), "max-age=300");
+        for host in ["localhost:8443", "app.localhost", "127.0.0.1:8443", "[::1]:8443", "myapp.local", "MyApp.Local."] {
+            assert!(hsts(Some(host)).is_none(), "{}", host);
+        }
+        assert!(hsts(None).is_none());
+    }
+}
@@ -0,0 +1,346 @@
+//! Request Replay Module
+//!
+//! Rebuilds captured requests (optionally with header and body overrides) so
+//! they can be re-sent through the proxy, and compares the replayed response
+//! with the original one.
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::capture::{self, BodyEncoding, CapturedBody, CapturedExchange, CapturedHeader};
+
+/// Headers recomputed for the replayed body rather than copied from the capture
+const RECOMPUTED_HEADERS: &[&str] = &["content-length", "transfer-encoding", "connection"];
+
+/// Largest body (in lines, both sides multiplied) that gets a line-by-line diff
+const MAX_DIFF_CELLS: usize = 1_000_000;
+
+/// Changes applied to a captured request before replaying it
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ReplayOverrides {
+    /// Headers to set; a `null` value removes the header
+    #[serde(default)]
+    pub headers: HashMap<String, Option<String>>,
+
+    /// Replacement request body
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// Encoding of `body`
+    #[serde(default)]
+    pub body_encoding: BodyEncoding,
+}
+
+/// Rebuild a captured request with overrides applied, returning it with its body
+pub fn build_request(
+    original: &CapturedExchange,
+    overrides: &ReplayOverrides,
+) -> Result<(Request<Body>, Vec<u8>), Box<dyn std::error::Error>> {
+    let body = match overrides.body {
+        Some(ref data) => CapturedBody {
+            data: data.clone(),
+            encoding: overrides.body_encoding,
+            ..Default::default()
+        }
+        .bytes()?,
+        None if original.request_body.truncated => {
+            return Err("Captured request body was truncated; supply a body override to replay it".into());
+        }
+        None => original.request_body.bytes()?,
+    };
+
+    let mut builder = Request::builder()
+        .method(original.method.as_str())
+        .uri(original.uri.as_str());
+
+    // Credentials are never replayed from a capture; an override can supply them
+    let headers = builder.headers_mut().ok_or("Invalid captured request")?;
+    for header in &original.request_headers {
+        if RECOMPUTED_HEADERS.contains(&header.name.as_str()) || capture::is_credential_header(&header.name) {
+            continue;
+        }
+        headers.append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_str(&header.value)?,
+        );
+    }
+
+    for (name, value) in &overrides.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())?;
+        headers.remove(&name);
+        if let Some(value) = value {
+            headers.insert(name, HeaderValue::from_str(value)?);
+        }
+    }
+
+    Ok((builder.body(Body::empty())?, body))
+}
+
+/// Original and replayed values of a field
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change<T> {
+    pub original: T,
+    pub replay: T,
+}
+
+/// Status code comparison
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatusDiff {
+    pub original: u16,
+    pub replay: u16,
+    pub changed: bool,
+}
+
+/// Response header comparison, keyed by lowercase header name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct HeaderDiff {
+    /// Headers only present in the replayed response
+    pub added: BTreeMap<String, String>,
+
+    /// Headers only present in the original response
+    pub removed: BTreeMap<String, String>,
+
+    /// Headers present in both with different values
+    pub changed: BTreeMap<String, Change<String>>,
+}
+
+/// One line of a body diff
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", content = "line", rename_all = "lowercase")]
+pub enum DiffLine {
+    Equal(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Response body comparison
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BodyDiff {
+    pub changed: bool,
+    pub original_size: u64,
+    pub replay_size: u64,
+
+    /// Line-by-line diff for text bodies (omitted for binary or very large bodies)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<Vec<DiffLine>>,
+}
+
+/// Differences between an original response and its replay
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayDiff {
+    pub status: StatusDiff,
+    pub headers: HeaderDiff,
+    pub body: BodyDiff,
+}
+
+impl ReplayDiff {
+    pub fn between(original: &CapturedExchange, replay: &CapturedExchange) -> Self {
+        ReplayDiff {
+            status: StatusDiff {
+                original: original.status,
+                replay: replay.status,
+                changed: original.status != replay.status,
+            },
+            headers: diff_headers(&original.response_headers, &replay.response_headers),
+            body: diff_bodies(&original.response_body, &replay.response_body),
+        }
+    }
+}
+
+/// Result returned by the replay endpoint
+#[derive(Debug, Serialize)]
+pub struct ReplayResult {
+    pub original_id: u64,
+    pub replay: CapturedExchange,
+    pub diff: ReplayDiff,
+}
+
+fn header_map(headers: &[CapturedHeader]) -> BTreeMap<String, String> {
+    let mut map: BTreeMap<String, String> = BTreeMap::new();
+    for header in headers {
+        map.entry(header.name.to_lowercase())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(&header.value);
+            })
+            .or_insert_with(|| header.value.clone());
+    }
+    map
+}
+
+fn diff_headers(original: &[CapturedHeader], replay: &[CapturedHeader]) -> HeaderDiff {
+    let original = header_map(original);
+    let mut replay = header_map(replay);
+    let mut diff = HeaderDiff::default();
+
+    for (name, value) in original {
+        match replay.remove(&name) {
+            Some(new) if new != value => {
+                diff.changed.insert(name, Change { original: value, replay: new });
+            }
+            Some(_) => {}
+            None => {
+                diff.removed.insert(name, value);
+            }
+        }
+    }
+    diff.added = replay;
+
+    diff
+}
+
+fn diff_bodies(original: &CapturedBody, replay: &CapturedBody) -> BodyDiff {
+    let changed = original.size != replay.size
+        || original.encoding != replay.encoding
+        || original.data != replay.data;
+
+    let lines = (changed
+        && original.encoding == BodyEncoding::Utf8
+        && replay.encoding == BodyEncoding::Utf8)
+        .then(|| diff_lines(&original.data, &replay.data))
+        .flatten();
+
+    BodyDiff {
+        changed,
+        original_size: original.size,
+        replay_size: replay.size,
+        lines,
+    }
+}
+
+/// Longest-common-subsequence line diff
+fn diff_lines(original: &str, replay: &str) -> Option<Vec<DiffLine>> {
+    let a: Vec<&str> = original.lines().collect();
+    let b: Vec<&str> = replay.lines().collect();
+    if a.len().saturating_mul(b.len()) > MAX_DIFF_CELLS {
+        return None;
+    }
+
+    // lcs[i][j] = length of the LCS of a[i..] and b[j..]
+    let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            lines.push(DiffLine::Equal(a[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(DiffLine::Removed(a[i].to_string()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(b[j].to_string()));
+            j += 1;
+        }
+    }
+    lines.extend(a[i..].iter().map(|l| DiffLine::Removed(l.to_string())));
+    lines.extend(b[j..].iter().map(|l| DiffLine::Added(l.to_string())));
+
+    Some(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> CapturedHeader {
+        CapturedHeader { name: name.to_string(), value: value.to_string() }
+    }
+
+    fn text(data: &str) -> CapturedBody {
+        CapturedBody::from_bytes(data.as_bytes(), data.len() as u64)
+    }
+
+    fn exchange(status: u16, headers: Vec<CapturedHeader>, body: &str) -> CapturedExchange {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "started_at_ms": 0,
+            "method": "POST",
+            "uri": "/hook?x=1",
+            "host": "myapp.local",
+            "http_version": "HTTP/1.1",
+            "client_addr": "127.0.0.1:5000",
+            "context": "WebhookService",
+            "request_headers": [
+                { "name": "host", "value": "myapp.local" },
+                { "name": "content-length", "value": "2" },
+                { "name": "x-signature", "value": "abc" },
+                { "name": "authorization", "value": "Bearer secret" },
+            ],
+            "request_body": text("{}"),
+            "status": status,
+            "response_headers": headers,
+            "response_body": text(body),
+            "wait_ms": 0.0,
+            "duration_ms": 0.0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build_request_applies_overrides() {
+        let original = exchange(200, vec![], "");
+        let overrides = ReplayOverrides {
+            headers: HashMap::from([
+                ("x-signature".to_string(), None),
+                ("x-test".to_string(), Some("1".to_string())),
+            ]),
+            body: Some("{\"ok\":true}".to_string()),
+            body_encoding: BodyEncoding::Utf8,
+        };
+
+        let (req, body) = build_request(&original, &overrides).unwrap();
+        assert_eq!(req.method(), "POST");
+        assert_eq!(req.uri(), "/hook?x=1");
+        assert_eq!(req.headers()["host"], "myapp.local");
+        assert_eq!(req.headers()["x-test"], "1");
+        assert!(!req.headers().contains_key("x-signature"));
+        assert!(!req.headers().contains_key("authorization"));
+        assert!(!req.headers().contains_key("content-length"));
+        assert_eq!(body, b"{\"ok\":true}");
+
+        let (_, body) = build_request(&original, &ReplayOverrides::default()).unwrap();
+        assert_eq!(body, b"{}");
+    }
+
+    #[test]
+    fn test_truncated_body_requires_override() {
+        let mut original = exchange(200, vec![], "");
+        original.request_body.size = 4096;
+        original.request_body.truncated = true;
+        assert!(build_request(&original, &ReplayOverrides::default()).is_err());
+    }
+
+    #[test]
+    fn test_diff_status_headers_and_body() {
+        let original = exchange(200, vec![header("content-type", "text/plain"), header("x-old", "1"), header("etag", "a")], "one\ntwo\nthree");
+        let replay = exchange(500, vec![header("content-type", "text/plain"), header("x-new", "2"), header("etag", "b")], "one\n2\nthree");
+
+        let diff = ReplayDiff::between(&original, &replay);
+        assert!(diff.status.changed);
+        assert_eq!(diff.headers.added.get("x-new").map(String::as_str), Some("2"));
+        assert_eq!(diff.headers.removed.get("x-old").map(String::as_str), Some("1"));
+        assert_eq!(diff.headers.changed["etag"], Change { original: "a".to_string(), replay: "b".to_string() });
+        assert!(!diff.headers.changed.contains_key("content-type"));
+
+        assert!(diff.body.changed);
+        assert_eq!(diff.body.lines.unwrap(), vec![
+            DiffLine::Equal("one".to_string()),
+            DiffLine::Removed("two".to_string()),
+            DiffLine::Added("2".to_string()),
+            DiffLine::Equal("three".to_string()),
+        ]);
+    }
+}
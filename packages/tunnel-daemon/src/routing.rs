@@ -0,0 +1,328 @@
+//! Request Routing Module
+//!
+//! Maps host names and path prefixes to different local upstreams so one
+//! tunnel (listen port, onion address) can front several services, e.g. a
+//! frontend, an API and an auth server.
+
+use hyper::{Body, Request, Uri};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::info;
+
+/// A local upstream the proxy can forward to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    /// `host:port` of the upstream
+    authority: String,
+
+    /// Path prepended to forwarded requests (no trailing slash)
+    base_path: String,
+}
+
+impl Upstream {
+    /// Upstream on a local port
+    pub fn local(port: u16) -> Self {
+        Upstream {
+            authority: format!("127.0.0.1:{}", port),
+            base_path: String::new(),
+        }
+    }
+
+    /// `host:port` of the upstream, used as its Host header
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    /// Absolute URI for a request path on this upstream
+    pub fn uri_for(&self, path_and_query: &str) -> String {
+        format!("http://{}{}{}", self.authority, self.base_path, path_and_query)
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    /// Parse a port (`4000`), `host:port` or `http://host:port/base` URL
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(port) = s.parse::<u16>() {
+            return Ok(Upstream::local(port));
+        }
+
+        let with_scheme = if s.contains("://") { s.to_string() } else { format!("http://{}", s) };
+        let uri: Uri = with_scheme
+            .parse()
+            .map_err(|e| format!("Invalid upstream '{}': {}", s, e))?;
+
+        if uri.scheme_str() != Some("http") {
+            return Err(format!("Invalid upstream '{}': only http:// upstreams are supported", s));
+        }
+
+        let authority = uri
+            .authority()
+            .ok_or_else(|| format!("Invalid upstream '{}': missing host", s))?;
+
+        Ok(Upstream {
+            authority: authority.to_string(),
+            base_path: uri.path().trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority, self.base_path)
+    }
+}
+
+/// A single routing rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// Host name to match (any host if `None`)
+    pub host: Option<String>,
+
+    /// Path prefix to match, matched on segment boundaries
+    pub path_prefix: String,
+
+    /// Remove the matched prefix before forwarding
+    pub strip_prefix: bool,
+
+    /// Where matching requests are sent
+    pub upstream: Upstream,
+}
+
+impl Route {
+    /// Whether this route matches a request's host and path
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        if let Some(ref route_host) = self.host {
+            match host {
+                Some(h) if h.eq_ignore_ascii_case(route_host) => {}
+                _ => return false,
+            }
+        }
+
+        let prefix = self.path_prefix.trim_end_matches('/');
+        prefix.is_empty()
+            || path == prefix
+            || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'))
+    }
+
+    /// Path and query forwarded to the upstream
+    fn forwarded_path(&self, path_and_query: &str) -> String {
+        if !self.strip_prefix {
+            return path_and_query.to_string();
+        }
+
+        let prefix = self.path_prefix.trim_end_matches('/');
+        match path_and_query.strip_prefix(prefix) {
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            Some(rest) => format!("/{}", rest),
+            None => path_and_query.to_string(),
+        }
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Parse `[host][/prefix]=<upstream>[,strip]`, e.g. `/api=4000,strip` or `auth.myapp.local=5000`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (matcher, target) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid route '{}'. Expected [host][/prefix]=<upstream>[,strip]", s))?;
+
+        let (target, strip_prefix) = match target.strip_suffix(",strip") {
+            Some(t) => (t, true),
+            None => (target, false),
+        };
+
+        let (host, path_prefix) = match matcher.find('/') {
+            Some(0) => (None, matcher.to_string()),
+            Some(i) => (Some(matcher[..i].to_string()), matcher[i..].to_string()),
+            None if matcher.is_empty() => (None, "/".to_string()),
+            None => (Some(matcher.to_string()), "/".to_string()),
+        };
+
+        Ok(Route {
+            host: host.map(|h| h.to_lowercase()),
+            path_prefix,
+            strip_prefix,
+            upstream: target.trim().parse()?,
+        })
+    }
+}
+
+/// Route entry as written in a routes file
+#[derive(Debug, Deserialize)]
+struct RouteSpec {
+    host: Option<String>,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    strip_prefix: bool,
+    upstream: String,
+}
+
+/// Top-level layout of a routes file
+#[derive(Debug, Deserialize)]
+struct RoutesFile {
+    #[serde(default)]
+    routes: Vec<RouteSpec>,
+}
+
+/// Upstream chosen for a request, carried in the request extensions
+#[derive(Debug, Clone)]
+pub struct RouteTarget {
+    /// Selected upstream
+    pub upstream: Upstream,
+
+    /// Path and query to request from the upstream
+    pub path_and_query: String,
+}
+
+/// Ordered set of routes; unmatched requests go to the default upstream
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        RoutingTable { routes: Vec::new() }
+    }
+
+    /// Add a routing rule
+    pub fn add_route(&mut self, route: Route) {
+        info!(
+            "Route: {}{} → {}{}",
+            route.host.as_deref().unwrap_or("*"),
+            route.path_prefix,
+            route.upstream,
+            if route.strip_prefix { " (prefix stripped)" } else { "" }
+        );
+        self.routes.push(route);
+    }
+
+    /// Load routes from a TOML, YAML or JSON file with a `routes` list
+    pub fn load_file(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let file: RoutesFile = config::Config::builder()
+            .add_source(config::File::from(path))
+            .build()?
+            .try_deserialize()?;
+
+        for spec in file.routes {
+            self.add_route(Route {
+                host: spec.host.map(|h| h.to_lowercase()),
+                path_prefix: spec.path.unwrap_or_else(|| "/".to_string()),
+                strip_prefix: spec.strip_prefix,
+                upstream: spec.upstream.parse()?,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    /// Host names that have their own routes
+    pub fn hosts(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().filter_map(|r| r.host.as_deref())
+    }
+
+    /// Most specific matching route: host-specific first, then longest prefix
+    pub fn find(&self, host: Option<&str>, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|r| r.matches(host, path))
+            .max_by_key(|r| (r.host.is_some(), r.path_prefix.trim_end_matches('/').len()))
+    }
+
+    /// Resolve the upstream target for a request
+    pub fn resolve(&self, req: &Request<Body>) -> Option<RouteTarget> {
+        let host = req
+            .headers()
+            .get(hyper::header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().host())
+            .map(strip_port);
+
+        let path_and_query = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+
+        self.find(host, req.uri().path()).map(|route| RouteTarget {
+            upstream: route.upstream.clone(),
+            path_and_query: route.forwarded_path(path_and_query),
+        })
+    }
+}
+
+/// Remove the port from a Host header value
+pub fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 literal, e.g. [::1]:8080
+        return host.split(']').next().map(|h| &host[..h.len() + 1]).unwrap_or(host);
+    }
+    host.rsplit_once(':').map(|(h, _)| h).unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(rules: &[&str]) -> RoutingTable {
+        let mut table = RoutingTable::new();
+        for rule in rules {
+            table.add_route(rule.parse().unwrap());
+        }
+        table
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let route: Route = "/api=4000,strip".parse().unwrap();
+        assert_eq!(route.host, None);
+        assert_eq!(route.path_prefix, "/api");
+        assert!(route.strip_prefix);
+        assert_eq!(route.upstream, Upstream::local(4000));
+
+        let route: Route = "Auth.MyApp.local=http://127.0.0.1:5000/auth".parse().unwrap();
+        assert_eq!(route.host.as_deref(), Some("auth.myapp.local"));
+        assert_eq!(route.path_prefix, "/");
+        assert_eq!(route.upstream.uri_for("/login"), "http://127.0.0.1:5000/auth/login");
+
+        assert!("/api".parse::<Route>().is_err());
+        assert!("/api=https://example.com".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn test_most_specific_route_wins() {
+        let table = table(&["/api=4000", "/api/v2=4002", "auth.myapp.local=5000"]);
+
+        assert_eq!(table.find(None, "/api/users").unwrap().upstream, Upstream::local(4000));
+        assert_eq!(table.find(None, "/api/v2/users").unwrap().upstream, Upstream::local(4002));
+        assert_eq!(table.find(Some("AUTH.myapp.local"), "/api").unwrap().upstream, Upstream::local(5000));
+        assert!(table.find(None, "/apiary").is_none());
+        assert!(table.find(Some("myapp.local"), "/").is_none());
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let route: Route = "/api=4000,strip".parse().unwrap();
+        assert_eq!(route.forwarded_path("/api/users?page=2"), "/users?page=2");
+        assert_eq!(route.forwarded_path("/api"), "/");
+        assert_eq!(route.forwarded_path("/api?x=1"), "/?x=1");
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("myapp.local:8080"), "myapp.local");
+        assert_eq!(strip_port("myapp.local"), "myapp.local");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+    }
+}
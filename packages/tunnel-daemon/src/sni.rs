@@ -0,0 +1,419 @@
+//! SNI Certificate Module
+//!
+//! Picks the server certificate by the TLS server name, so one HTTPS port can
+//! serve every domain the tunnel answers for. Every certificate covers its
+//! domain and the direct subdomains of it. The primary domain's certificate is
+//! also the fallback; other configured names get a certificate from the local
+//! CA, issued in the background so a handshake never waits on signing, and
+//! kept in a bounded cache that evicts the least recently used entry. Until a
+//! name's certificate is ready, and for any name not configured, clients get
+//! the primary certificate. Certificates close to expiry are renewed and
+//! swapped in place, so the listener keeps running with the same rustls
+//! config. A certificate provided by the user is served for every name and is
+//! never replaced. A primary certificate set from elsewhere (ACME) is left to
+//! whoever set it; until then the local one is renewed like any other.
+
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::cert::{CertStore, IssuedCert};
+
+/// How often certificates are checked for upcoming expiry
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Upper bound on issued certificates kept in memory
+const MAX_CACHED_CERTS: usize = 256;
+
+/// Build a rustls certified key from a chain and private key
+pub fn certified_key(chain: Vec<Certificate>, key: &PrivateKey) -> Result<Arc<CertifiedKey>, Box<dyn std::error::Error>> {
+    let signing_key = sign::any_supported_type(key).map_err(|_| "Unsupported private key type")?;
+    Ok(Arc::new(CertifiedKey::new(chain, signing_key)))
+}
+
+/// A certificate ready for rustls, with its expiry
+struct Entry {
+    key: Arc<CertifiedKey>,
+    cert: IssuedCert,
+}
+
+impl Entry {
+    fn new(cert: IssuedCert) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Entry {
+            key: certified_key(cert.chain.clone(), &cert.key)?,
+            cert,
+        })
+    }
+}
+
+/// An issued certificate and when a handshake last used it
+struct Cached {
+    entry: Entry,
+    last_used: Instant,
+}
+
+/// Issued certificates by the name they were issued for
+#[derive(Default)]
+struct CertCache {
+    entries: HashMap<String, Cached>,
+
+    /// Names with an issuance in progress
+    issuing: HashSet<String>,
+}
+
+impl CertCache {
+    /// Key for `name` or its parent, marking it as used
+    fn get(&mut self, name: &str, parent: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let key = if self.entries.contains_key(name) { name } else { parent.filter(|p| self.entries.contains_key(*p))? };
+        let cached = self.entries.get_mut(key)?;
+        cached.last_used = Instant::now();
+        Some(Arc::clone(&cached.entry.key))
+    }
+
+    /// Add an entry, evicting the least recently used one when full
+    fn insert(&mut self, name: String, entry: Entry) {
+        if !self.entries.contains_key(&name) && self.entries.len() >= MAX_CACHED_CERTS {
+            let oldest = self.entries.iter().min_by_key(|(_, cached)| cached.last_used).map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                debug!("Evicting certificate for {}", oldest);
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(name, Cached { entry, last_used: Instant::now() });
+    }
+}
+
+/// Server certificate resolver keyed by SNI
+pub struct SniResolver {
+    /// Domain the primary certificate was issued for
+    primary_domain: String,
+
+    /// Certificate for the primary domain, also the fallback
+    primary: RwLock<Entry>,
+
+    /// Whether the primary certificate was set from elsewhere and is renewed there
+    external_primary: AtomicBool,
+
+    /// Store issuing certificates for other names (`None` for a provided certificate)
+    store: Option<Arc<CertStore>>,
+
+    /// Names we issue certificates for, each also covering its direct subdomains
+    domains: HashSet<String>,
+
+    /// Certificates issued for names in `domains`
+    cache: Arc<Mutex<CertCache>>,
+}
+
+impl SniResolver {
+    /// Serve `primary_domain` with its stored certificate, issuing one if needed
+    pub fn new(store: Arc<CertStore>, primary_domain: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let primary_domain = primary_domain.to_lowercase();
+        let primary = Entry::new(store.get_or_issue(&primary_domain)?)?;
+        Ok(SniResolver {
+            domains: HashSet::from([primary_domain.clone()]),
+            primary_domain,
+            primary: RwLock::new(primary),
+            external_primary: AtomicBool::new(false),
+            store: Some(store),
+            cache: Arc::default(),
+        })
+    }
+
+    /// Serve a certificate provided by the user for every name
+    pub fn with_certificate(primary_domain: &str, cert: IssuedCert) -> Result<Self, Box<dyn std::error::Error>> {
+        let primary_domain = primary_domain.to_lowercase();
+        Ok(SniResolver {
+            domains: HashSet::from([primary_domain.clone()]),
+            primary_domain,
+            primary: RwLock::new(Entry::new(cert)?),
+            external_primary: AtomicBool::new(true),
+            store: None,
+            cache: Arc::default(),
+        })
+    }
+
+    /// Whether the primary certificate comes from the local CA
+    pub fn uses_local_ca(&self) -> bool {
+        !self.external_primary.load(Ordering::Relaxed)
+    }
+
+    /// Also serve `domain` and its direct subdomains
+    pub fn add_domain(&mut self, domain: &str) {
+        self.domains.insert(domain.trim_end_matches('.').to_lowercase());
+    }
+
+    /// Configured name whose certificate covers `name`, if any
+    fn covering_domain<'a>(&self, name: &'a str, parent: Option<&'a str>) -> Option<&'a str> {
+        if self.domains.contains(name) {
+            Some(name)
+        } else {
+            parent.filter(|parent| self.domains.contains(*parent))
+        }
+    }
+
+    /// Certificate for a server name (`None` when the client sent no SNI)
+    pub fn resolve_name(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let name = match server_name {
+            Some(name) => name.trim_end_matches('.').to_lowercase(),
+            None => return self.primary_key(),
+        };
+
+        // A certificate for `d` also covers `*.d`
+        let parent = name.split_once('.').map(|(_, parent)| parent);
+        if name == self.primary_domain || parent == Some(self.primary_domain.as_str()) {
+            return self.primary_key();
+        }
+        if let Some(key) = self.cache.lock().unwrap().get(&name, parent) {
+            return key;
+        }
+
+        match (&self.store, self.covering_domain(&name, parent)) {
+            (Some(store), Some(domain)) => self.issue_in_background(Arc::clone(store), domain.to_string()),
+            _ => debug!("No certificate for SNI {}, using {}", name, self.primary_domain),
+        }
+        self.primary_key()
+    }
+
+    /// Issue a certificate for `domain` off the handshake path
+    fn issue_in_background(&self, store: Arc<CertStore>, domain: String) {
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return,
+        };
+        if !self.cache.lock().unwrap().issuing.insert(domain.clone()) {
+            return;
+        }
+
+        debug!("Issuing certificate for {} in the background", domain);
+        let cache = Arc::clone(&self.cache);
+        runtime.spawn_blocking(move || {
+            let issued = store.get_or_issue(&domain).and_then(Entry::new);
+            let mut cache = cache.lock().unwrap();
+            cache.issuing.remove(&domain);
+            match issued {
+                Ok(entry) => cache.insert(domain, entry),
+                Err(e) => warn!("Failed to issue certificate for {}: {}", domain, e),
+            }
+        });
+    }
+
+    /// Issue certificates for every configured name up front, so their first
+    /// handshakes already get the right one
+    pub fn pre_issue(&self) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+        let domains = self.domains.iter().filter(|d| **d != self.primary_domain);
+        for domain in domains.take(MAX_CACHED_CERTS) {
+            if self.cache.lock().unwrap().entries.contains_key(domain) {
+                continue;
+            }
+            match store.get_or_issue(domain).and_then(Entry::new) {
+                Ok(entry) => self.cache.lock().unwrap().insert(domain.clone(), entry),
+                Err(e) => warn!("Failed to issue certificate for {}: {}", domain, e),
+            }
+        }
+    }
+
+    /// Replace the primary certificate, e.g. with one obtained over ACME; the
+    /// caller renews it from then on
+    pub fn set_primary(&self, cert: IssuedCert) -> Result<(), Box<dyn std::error::Error>> {
+        *self.primary.write().unwrap() = Entry::new(cert)?;
+        self.external_primary.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn primary_key(&self) -> Arc<CertifiedKey> {
+        Arc::clone(&self.primary.read().unwrap().key)
+    }
+
+    /// Renew certificates close to expiry and swap them in for new handshakes
+    pub fn renew_expiring(&self) {
+        let not_after = {
+            let primary = self.primary.read().unwrap();
+            primary.cert.needs_renewal().then_some(primary.cert.not_after)
+        };
+        match (not_after, &self.store) {
+            (None, _) => {}
+            // Renewed by whoever set it (the ACME renewal task)
+            (Some(_), Some(_)) if self.external_primary.load(Ordering::Relaxed) => {}
+            (Some(_), Some(store)) => match store.renew(&self.primary_domain).and_then(Entry::new) {
+                Ok(entry) => {
+                    let mut primary = self.primary.write().unwrap();
+                    // ACME may have set a certificate while this one was signed
+                    if !self.external_primary.load(Ordering::Relaxed) {
+                        info!("Renewed certificate for {} (valid until {})", self.primary_domain, entry.cert.not_after);
+                        *primary = entry;
+                    }
+                }
+                Err(e) => warn!("Failed to renew certificate for {}: {}", self.primary_domain, e),
+            },
+            (Some(not_after), None) => {
+                warn!("Provided certificate for {} expires at {}, replace it and restart", self.primary_domain, not_after)
+            }
+        }
+
+        // Other names are reissued by the next `pre_issue`
+        self.cache.lock().unwrap().entries.retain(|name, cached| {
+            let keep = !cached.entry.cert.needs_renewal();
+            if !keep {
+                debug!("Dropping expiring certificate for {}", name);
+            }
+            keep
+        });
+    }
+
+    /// Start background task issuing certificates for configured names and
+    /// renewing them before they expire
+    pub fn start_renewal_task(resolver: Arc<SniResolver>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RENEWAL_CHECK_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                let resolver = Arc::clone(&resolver);
+                // Issuing signs with the CA key; keep it off the async workers
+                let _ = tokio::task::spawn_blocking(move || {
+                    resolver.renew_expiring();
+                    resolver.pre_issue();
+                })
+                .await;
+            }
+        })
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.resolve_name(client_hello.server_name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert::CertificateAuthority;
+
+    fn resolver(dir: &std::path::Path) -> SniResolver {
+        let ca = Arc::new(CertificateAuthority::load_or_create(&dir.join("ca")).unwrap());
+        let store = Arc::new(CertStore::new(ca, dir.join("certs")));
+        let mut resolver = SniResolver::new(store, "myapp.local").unwrap();
+        resolver.add_domain("api.other.local");
+        resolver
+    }
+
+    #[test]
+    fn test_primary_covers_domain_and_subdomains() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver = resolver(dir.path());
+        let primary = resolver.resolve_name(Some("myapp.local"));
+        assert!(Arc::ptr_eq(&primary, &resolver.resolve_name(Some("Tenant1.MyApp.local."))));
+        assert!(Arc::ptr_eq(&primary, &resolver.resolve_name(None)));
+        assert!(Arc::ptr_eq(&primary, &resolver.resolve_name(Some("unknown.example"))));
+    }
+
+    #[test]
+    fn test_issues_for_configured_names_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver = resolver(dir.path());
+        let primary = resolver.resolve_name(None);
+
+        // Nothing is issued during a handshake without a runtime to issue on
+        assert!(Arc::ptr_eq(&primary, &resolver.resolve_name(Some("api.other.local"))));
+        assert!(resolver.cache.lock().unwrap().entries.is_empty());
+
+        resolver.pre_issue();
+        let api = resolver.resolve_name(Some("api.other.local"));
+        assert!(!Arc::ptr_eq(&primary, &api));
+        assert!(Arc::ptr_eq(&api, &resolver.resolve_name(Some("v2.api.other.local"))));
+        assert!(dir.path().join("certs").join("api_other_local.json").exists());
+
+        // Only one level of subdomain is covered, deeper names get the fallback
+        assert!(Arc::ptr_eq(&primary, &resolver.resolve_name(Some("a.v2.api.other.local"))));
+        assert!(Arc::ptr_eq(&primary, &resolver.resolve_name(Some("a.b.myapp.local"))));
+        assert_eq!(resolver.cache.lock().unwrap().entries.len(), 1);
+
+        // Nothing is close to expiry, so renewal keeps everything
+        resolver.renew_expiring();
+        assert!(Arc::ptr_eq(&primary, &resolver.resolve_name(None)));
+        assert_eq!(resolver.cache.lock().unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn test_local_primary_is_renewed_until_set_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver = resolver(dir.path());
+        let expiring = || {
+            let primary = resolver.primary.read().unwrap();
+            IssuedCert {
+                chain: primary.cert.chain.clone(),
+                key: primary.cert.key.clone(),
+                not_after: time::OffsetDateTime::now_utc(),
+            }
+        };
+        assert!(resolver.uses_local_ca());
+
+        // The local fallback is renewed like any other certificate
+        let cert = expiring();
+        *resolver.primary.write().unwrap() = Entry::new(cert).unwrap();
+        let fallback = resolver.resolve_name(None);
+        resolver.renew_expiring();
+        assert!(!Arc::ptr_eq(&fallback, &resolver.resolve_name(None)));
+
+        // A certificate set from elsewhere (ACME) is renewed there, not here
+        resolver.set_primary(expiring()).unwrap();
+        assert!(!resolver.uses_local_ca());
+        let acme = resolver.resolve_name(None);
+        resolver.renew_expiring();
+        assert!(Arc::ptr_eq(&acme, &resolver.resolve_name(None)));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_issues_in_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver = resolver(dir.path());
+        let primary = resolver.resolve_name(None);
+
+        assert!(Arc::ptr_eq(&primary, &resolver.resolve_name(Some("v2.api.other.local"))));
+        for _ in 0..100 {
+            if !resolver.cache.lock().unwrap().entries.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let api = resolver.resolve_name(Some("v2.api.other.local"));
+        assert!(!Arc::ptr_eq(&primary, &api));
+        assert!(resolver.cache.lock().unwrap().issuing.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver = resolver(dir.path());
+        resolver.pre_issue();
+        let entry = || {
+            let cache = resolver.cache.lock().unwrap();
+            let cert = &cache.entries["api.other.local"].entry.cert;
+            let cert = IssuedCert { chain: cert.chain.clone(), key: cert.key.clone(), not_after: cert.not_after };
+            Entry::new(cert).unwrap()
+        };
+
+        let mut cache = CertCache::default();
+        for i in 0..MAX_CACHED_CERTS {
+            cache.insert(format!("host{}.local", i), entry());
+        }
+        assert!(cache.get("host0.local", None).is_some());
+        cache.insert("new.local".to_string(), entry());
+
+        assert_eq!(cache.entries.len(), MAX_CACHED_CERTS);
+        assert!(cache.get("host0.local", None).is_some());
+        assert!(cache.get("host1.local", None).is_none());
+        assert!(cache.get("new.local", None).is_some());
+    }
+}
@@ -0,0 +1,183 @@
+//! Raw TCP Tunnel Module
+//!
+//! Forwards plain byte streams to a local TCP port for services that don't
+//! speak HTTP (databases, Redis, SSH, ...). Serves the listen port, which is
+//! also where the onion service forwards, plus any extra listeners such as the
+//! one P2P peers connect to.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+use crate::tunnel::RequestStats;
+
+/// A live forwarded TCP connection
+#[derive(Debug, Clone)]
+pub struct TcpConnection {
+    /// Client address
+    pub peer_addr: SocketAddr,
+
+    /// When the connection was accepted
+    pub opened_at: Instant,
+}
+
+/// TCP forwarder from a listen address to a local port
+pub struct TcpTunnel {
+    /// Address to accept connections on
+    listen_addr: SocketAddr,
+
+    /// Local port to forward to
+    target_port: u16,
+
+    /// Shared statistics (bytes and connection counts)
+    stats: Arc<RequestStats>,
+
+    /// Live connections by ID
+    connections: Arc<RwLock<HashMap<u64, TcpConnection>>>,
+
+    /// Further listeners to accept on (P2P peers)
+    extra_listeners: Vec<std::net::TcpListener>,
+
+    /// Next connection ID
+    next_id: AtomicU64,
+}
+
+impl TcpTunnel {
+    pub fn new(listen_addr: SocketAddr, target_port: u16, stats: Arc<RequestStats>) -> Self {
+        TcpTunnel {
+            listen_addr,
+            target_port,
+            stats,
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            extra_listeners: Vec::new(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Also forward connections accepted on an already bound listener
+    pub fn add_listener(&mut self, listener: std::net::TcpListener) {
+        self.extra_listeners.push(listener);
+    }
+
+    /// Accept connections and forward each one until the listen port fails
+    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(self.listen_addr).await?;
+        info!("TCP tunnel listening on {} → 127.0.0.1:{}", self.listen_addr, self.target_port);
+
+        let extra_listeners = std::mem::take(&mut self.extra_listeners);
+        let tunnel = Arc::new(self);
+        for extra in extra_listeners {
+            extra.set_nonblocking(true)?;
+            let extra = TcpListener::from_std(extra)?;
+            info!("TCP tunnel also listening on {}", extra.local_addr()?);
+            let tunnel = Arc::clone(&tunnel);
+            tokio::spawn(async move {
+                let _ = tunnel.accept_loop(extra).await;
+            });
+        }
+
+        Ok(tunnel.accept_loop(listener).await?)
+    }
+
+    /// Accept and forward connections from one listener until it fails
+    async fn accept_loop(&self, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("TCP accept error: {}", e);
+                    return Err(e);
+                }
+            };
+
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            self.connections.write().await.insert(id, TcpConnection {
+                peer_addr,
+                opened_at: Instant::now(),
+            });
+
+            self.stats.total_requests.fetch_add(1, Ordering::Relaxed);
+            self.stats.active_connections.fetch_add(1, Ordering::Relaxed);
+
+            let target_port = self.target_port;
+            let stats = Arc::clone(&self.stats);
+            let connections = Arc::clone(&self.connections);
+
+            tokio::spawn(async move {
+                match forward(stream, target_port).await {
+                    Ok((bytes_in, bytes_out)) => {
+                        stats.successful_requests.fetch_add(1, Ordering::Relaxed);
+                        stats.total_bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+                        stats.total_bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+                        debug!("TCP #{} {} → {} in, {} out", id, peer_addr, bytes_in, bytes_out);
+                    }
+                    Err(e) => {
+                        stats.failed_requests.fetch_add(1, Ordering::Relaxed);
+                        warn!("TCP #{} from {} failed: {}", id, peer_addr, e);
+                    }
+                }
+
+                stats.active_connections.fetch_sub(1, Ordering::Relaxed);
+                if let Some(conn) = connections.write().await.remove(&id) {
+                    info!("TCP #{} from {} closed after {:?}", id, conn.peer_addr, conn.opened_at.elapsed());
+                }
+            });
+        }
+    }
+}
+
+/// Splice a client stream to the local target, returning bytes (in, out)
+async fn forward(mut client: TcpStream, target_port: u16) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+    let mut upstream = tokio::time::timeout(
+        Duration::from_secs(5),
+        TcpStream::connect(("127.0.0.1", target_port)),
+    )
+    .await
+    .map_err(|_| "Timed out connecting to local service")??;
+
+    let _ = client.set_nodelay(true);
+    let _ = upstream.set_nodelay(true);
+
+    Ok(tokio::io::copy_bidirectional(&mut client, &mut upstream).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_forwards_from_added_listener() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let listen_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let peer_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer_listener.local_addr().unwrap();
+
+        let stats = Arc::new(RequestStats::default());
+        let mut tunnel = TcpTunnel::new(SocketAddr::from(([127, 0, 0, 1], listen_port)), target_port, Arc::clone(&stats));
+        tunnel.add_listener(peer_listener);
+        tokio::spawn(async move {
+            let _ = tunnel.run().await;
+        });
+
+        let mut client = TcpStream::connect(peer_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        assert_eq!(stats.total_requests.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,13 +1,20 @@
 use std::process::Command;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tracing::{info, warn, error, debug};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::fs;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use std::collections::HashMap;
 
+use crate::tor_control::{Event, TorControl, TorControlError, TorCredentials};
+
+/// Events the manager subscribes to on its control connection
+const SUBSCRIBED_EVENTS: &[&str] = &["CIRC", "HS_DESC"];
+
 /// Tor operating mode
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TorMode {
@@ -46,6 +53,12 @@ impl Default for GeoPreferences {
 
 pub struct TorManager {
     control_port: u16,
+    /// Authenticated control connection, kept open for the manager's lifetime
+    control: Option<Arc<TorControl>>,
+    /// Credentials for control port authentication
+    credentials: TorCredentials,
+    /// Service ID of the ephemeral onion service we created
+    service_id: Option<String>,
     tor_process: Option<std::process::Child>,
     hidden_service_dir: PathBuf,
     onion_address: Option<String>,
@@ -59,6 +72,8 @@ pub struct TorManager {
     geo_prefs: GeoPreferences,
     /// Whether circuit prebuilding is enabled
     circuit_prebuilding: bool,
+    /// Port exposed on the onion address
+    virtual_port: u16,
 }
 
 impl TorManager {
@@ -122,6 +137,9 @@ impl TorManager {
 
         let manager = TorManager {
             control_port,
+            control: None,
+            credentials: TorCredentials::default(),
+            service_id: None,
             tor_process: None,
             hidden_service_dir,
             onion_address: None,
@@ -130,6 +148,7 @@ impl TorManager {
             prebuild_count: 3,
             geo_prefs: GeoPreferences::default(),
             circuit_prebuilding: true,
+            virtual_port: 80,
         };
 
         info!("TorManager initialized in {:?} mode", mode);
@@ -143,74 +162,133 @@ impl TorManager {
         info!("Tor mode set to {:?}", mode);
     }
 
+    /// Set the credentials used to authenticate to the control port
+    pub fn set_credentials(&mut self, credentials: TorCredentials) {
+        self.credentials = credentials;
+    }
+
     /// Set geographic preferences for relay selection
     pub fn set_geo_preferences(&mut self, prefs: GeoPreferences) {
         self.geo_prefs = prefs;
     }
 
+    /// Set the port exposed on the onion address (80 for HTTP)
+    pub fn set_virtual_port(&mut self, port: u16) {
+        self.virtual_port = port;
+    }
+
     /// Enable/disable circuit prebuilding
     pub fn set_circuit_prebuilding(&mut self, enabled: bool, count: u32) {
         self.circuit_prebuilding = enabled;
         self.prebuild_count = count;
     }
 
+    /// Control connection to Tor, connecting and authenticating on first use
+    /// and again whenever the previous connection has closed
+    async fn control(&mut self) -> Result<Arc<TorControl>, Box<dyn std::error::Error>> {
+        match &self.control {
+            Some(control) if !control.is_closed() => return Ok(Arc::clone(control)),
+            Some(_) => {
+                warn!("Tor control connection lost, reconnecting");
+                self.control = None;
+                // Ephemeral onion services end with the connection that created them
+                if let Some(service_id) = self.service_id.take() {
+                    warn!("Onion service {}.onion went down with the old control connection", service_id);
+                }
+            }
+            None => {}
+        }
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], self.control_port));
+        let (control, events) = TorControl::connect(addr).await?;
+        let info = control.authenticate(&self.credentials).await?;
+        debug!("Authenticated to Tor control port (offered: {})", info.methods.join(","));
+        match control.get_info("version").await {
+            Ok(version) => info!("Connected to Tor {} on control port {}", version, self.control_port),
+            Err(e) => debug!("Tor did not report its version: {}", e),
+        }
+
+        control.set_events(SUBSCRIBED_EVENTS).await?;
+        Self::start_event_task(events, Arc::clone(&self.circuits));
+
+        let control = Arc::new(control);
+        self.control = Some(Arc::clone(&control));
+        Ok(control)
+    }
+
+    /// Track circuit state and onion service publication from control events
+    fn start_event_task(
+        mut events: mpsc::Receiver<Event>,
+        circuits: Arc<RwLock<HashMap<String, PersistentCircuit>>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match (event.keyword.as_str(), event.args.as_slice()) {
+                    // CIRC <id> <status> [<path>] ...
+                    ("CIRC", [id, status, rest @ ..]) => {
+                        let mut circuits = circuits.write().await;
+                        if let Some(circuit) = circuits.get_mut(id) {
+                            match status.as_str() {
+                                "BUILT" => {
+                                    circuit.path = rest
+                                        .first()
+                                        .filter(|p| p.starts_with('$'))
+                                        .map(|p| p.split(',').map(str::to_string).collect())
+                                        .unwrap_or_default();
+                                    debug!("Circuit {} built through {} relays", id, circuit.path.len());
+                                }
+                                "FAILED" | "CLOSED" => {
+                                    circuit.is_active = false;
+                                    debug!("Circuit {} {}", id, status.to_lowercase());
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    // HS_DESC <action> <address> <auth> <hsdir> ...
+                    ("HS_DESC", [action, address, _, hsdir, ..]) => match action.as_str() {
+                        "UPLOADED" => debug!("Descriptor for {}.onion uploaded to {}", address, hsdir),
+                        "FAILED" => warn!("Descriptor upload for {}.onion to {} failed", address, hsdir),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        })
+    }
+
     /// Prebuild circuits for faster initial connections
-    pub async fn prebuild_circuits(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn prebuild_circuits(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if !self.circuit_prebuilding {
             return Ok(());
         }
 
         info!("Prebuilding {} circuits for faster connections...", self.prebuild_count);
 
-        // Connect to Tor control port
-        let stream = match TcpStream::connect(format!("127.0.0.1:{}", self.control_port)).await {
-            Ok(s) => s,
+        let control = match self.control().await {
+            Ok(control) => control,
             Err(e) => {
                 warn!("Cannot prebuild circuits - Tor not accessible: {}", e);
                 return Ok(());
             }
         };
 
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
-
-        // Authenticate
-        writer.write_all(b"AUTHENTICATE\r\n").await?;
-        let mut response = String::new();
-        reader.read_line(&mut response).await?;
-
-        if !response.starts_with("250") {
-            return Err("Tor authentication failed".into());
-        }
-
-        // Build circuits using EXTENDCIRCUIT
+        // Use geographic preferences if set
+        let purpose = (!self.geo_prefs.preferred_countries.is_empty()).then_some("GENERAL");
         for i in 0..self.prebuild_count {
-            let cmd = if self.geo_prefs.preferred_countries.is_empty() {
-                "EXTENDCIRCUIT 0\r\n".to_string()
-            } else {
-                // Use geographic preferences if set
-                format!(
-                    "EXTENDCIRCUIT 0 purpose=GENERAL\r\n"
-                )
-            };
-
-            writer.write_all(cmd.as_bytes()).await?;
-            response.clear();
-            reader.read_line(&mut response).await?;
-
-            if response.starts_with("250") {
-                // Parse circuit ID from response: "250 EXTENDED <circuit_id>"
-                if let Some(circuit_id) = response.split_whitespace().nth(2) {
+            match control.extend_circuit(purpose).await {
+                Ok(circuit_id) => {
                     let circuit = PersistentCircuit {
-                        circuit_id: circuit_id.to_string(),
+                        circuit_id: circuit_id.clone(),
                         created_at: std::time::Instant::now(),
                         is_active: true,
                         path: vec![],
                     };
 
-                    self.circuits.write().await.insert(circuit_id.to_string(), circuit);
+                    self.circuits.write().await.insert(circuit_id.clone(), circuit);
                     debug!("Prebuilt circuit {}: {}", i + 1, circuit_id);
                 }
+                Err(e) => warn!("Failed to prebuild circuit {}: {}", i + 1, e),
             }
         }
 
@@ -237,9 +315,16 @@ impl TorManager {
         }
 
         // Try to connect to existing Tor control port
-        if let Ok(onion) = self.connect_and_create_hs(local_port).await {
-            self.onion_address = Some(onion.clone());
-            return Ok(onion);
+        match self.connect_and_create_hs(local_port).await {
+            Ok(onion) => {
+                self.onion_address = Some(onion.clone());
+                return Ok(onion);
+            }
+            // A running Tor refused us; starting another one would not help
+            Err(e) if matches!(e.downcast_ref::<TorControlError>(), Some(TorControlError::Authentication(_))) => {
+                return Err(e);
+            }
+            Err(e) => debug!("No usable Tor control port: {}", e),
         }
 
         // If no running Tor, try to start it
@@ -263,120 +348,63 @@ impl TorManager {
         }
     }
 
-    /// Connect to Tor control port and create hidden service using ADD_ONION
-    pub async fn connect_and_create_hs(&self, local_port: u16) -> Result<String, Box<dyn std::error::Error>> {
-        let stream = TcpStream::connect(format!("127.0.0.1:{}", self.control_port)).await?;
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
-
-        // Authenticate (try no-auth first, common for local setups)
-        writer.write_all(b"AUTHENTICATE\r\n").await?;
-        let mut response = String::new();
-        reader.read_line(&mut response).await?;
-
-        if !response.starts_with("250") {
-            // Try with empty password
-            writer.write_all(b"AUTHENTICATE \"\"\r\n").await?;
-            response.clear();
-            reader.read_line(&mut response).await?;
-
-            if !response.starts_with("250") {
-                return Err(format!("Tor authentication failed: {}", response).into());
-            }
-        }
-
-        debug!("Tor authentication successful");
+    /// Create an ephemeral hidden service over the control connection using ADD_ONION
+    pub async fn connect_and_create_hs(&mut self, local_port: u16) -> Result<String, Box<dyn std::error::Error>> {
+        let control = self.control().await?;
 
-        // Create ephemeral hidden service using ADD_ONION
-        // NEW:BEST generates a new key using the best available algorithm
-        // For single-hop mode, we add the NonAnonymous flag
-        let cmd = match self.mode {
+        // NEW:BEST generates a new key using the best available algorithm; the
+        // key is never stored, so Tor need not send it back
+        let flags: &[&str] = match self.mode {
             TorMode::SingleHop => {
                 info!("Creating single-hop hidden service (balanced mode - faster but server not anonymous)");
                 // NonAnonymous flag creates a single-hop service
                 // This requires HiddenServiceSingleHopMode 1 in torrc
-                format!("ADD_ONION NEW:BEST Flags=NonAnonymous Port=80,127.0.0.1:{}\r\n", local_port)
+                &["DiscardPK", "NonAnonymous"]
             }
             TorMode::Full => {
                 info!("Creating full 3-hop hidden service (private mode - maximum anonymity)");
-                format!("ADD_ONION NEW:BEST Port=80,127.0.0.1:{}\r\n", local_port)
+                &["DiscardPK"]
             }
         };
+        let target = SocketAddr::from(([127, 0, 0, 1], local_port));
+        let service_id = control.add_onion(flags, &[(self.virtual_port, target)]).await?;
 
-        writer.write_all(cmd.as_bytes()).await?;
-
-        // Read response - should contain the onion address
-        response.clear();
-        loop {
-            reader.read_line(&mut response).await?;
-            if response.contains("250 OK") || response.contains("250-") == false {
-                break;
-            }
-        }
+        let onion_addr = format!("{}.onion", service_id);
+        self.service_id = Some(service_id);
 
-        // Parse the onion address from response
-        // Format: 250-ServiceID=xxxxxxxxxxxxxxxxxxxx
-        if let Some(line) = response.lines().find(|l| l.contains("ServiceID=")) {
-            if let Some(onion) = line.split("ServiceID=").nth(1) {
-                let onion_addr = format!("{}.onion", onion.trim());
-
-                let mode_desc = match self.mode {
-                    TorMode::SingleHop => "single-hop (balanced)",
-                    TorMode::Full => "3-hop (private)",
-                };
-                info!("Tor hidden service created [{}]: {}", mode_desc, onion_addr);
-
-                // If circuit prebuilding is enabled, prebuild circuits now
-                if self.circuit_prebuilding {
-                    let _ = self.prebuild_circuits().await;
-                }
+        let mode_desc = match self.mode {
+            TorMode::SingleHop => "single-hop (balanced)",
+            TorMode::Full => "3-hop (private)",
+        };
+        info!("Tor hidden service created [{}]: {}", mode_desc, onion_addr);
 
-                return Ok(onion_addr);
-            }
+        // If circuit prebuilding is enabled, prebuild circuits now
+        if self.circuit_prebuilding {
+            let _ = self.prebuild_circuits().await;
         }
 
-        Err(format!("Failed to parse onion address from response: {}", response).into())
+        Ok(onion_addr)
     }
 
     /// Configure Tor for single-hop mode (requires modifying torrc)
-    pub async fn configure_single_hop_mode(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn configure_single_hop_mode(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Configuring Tor for single-hop (balanced) mode...");
 
-        let stream = TcpStream::connect(format!("127.0.0.1:{}", self.control_port)).await?;
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
-
-        // Authenticate
-        writer.write_all(b"AUTHENTICATE\r\n").await?;
-        let mut response = String::new();
-        reader.read_line(&mut response).await?;
-
-        if !response.starts_with("250") {
-            return Err("Tor authentication failed".into());
-        }
+        let control = self.control().await?;
 
         // Set configuration for single-hop mode
         // Note: These settings make the hidden service non-anonymous but faster
-        let configs = [
-            "SETCONF HiddenServiceSingleHopMode=1",
-            "SETCONF HiddenServiceNonAnonymousMode=1",
-        ];
-
-        for config in &configs {
-            writer.write_all(format!("{}\r\n", config).as_bytes()).await?;
-            response.clear();
-            reader.read_line(&mut response).await?;
-
-            if !response.starts_with("250") {
-                warn!("Failed to set {}: {}", config, response.trim());
+        for key in ["HiddenServiceSingleHopMode", "HiddenServiceNonAnonymousMode"] {
+            if let Err(e) = control.set_conf(key, "1").await {
                 // Continue anyway - some Tor versions may not support these
+                warn!("Failed to set {}: {}", key, e);
             }
         }
 
         // Save configuration
-        writer.write_all(b"SAVECONF\r\n").await?;
-        response.clear();
-        reader.read_line(&mut response).await?;
+        if let Err(e) = control.save_conf().await {
+            warn!("Failed to save Tor configuration: {}", e);
+        }
 
         info!("Single-hop mode configuration applied");
         Ok(())
@@ -402,11 +430,12 @@ impl TorManager {
         let torrc_content = format!(
             "DataDirectory {}\n\
              HiddenServiceDir {}\n\
-             HiddenServicePort 80 127.0.0.1:{}\n\
+             HiddenServicePort {} 127.0.0.1:{}\n\
              ControlPort {}\n\
              SocksPort 0\n",
             self.hidden_service_dir.display(),
             self.hidden_service_dir.join("hs").display(),
+            self.virtual_port,
             local_port,
             self.control_port
         );
@@ -468,22 +497,20 @@ impl TorManager {
     }
 
     pub async fn shutdown(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // Remove the ephemeral hidden service; closing the control connection
+        // would drop it too, this just makes it immediate (best effort)
+        if let (Some(control), Some(service_id)) = (self.control.take(), self.service_id.take()) {
+            if let Err(e) = control.del_onion(&service_id).await {
+                debug!("DEL_ONION {} failed: {}", service_id, e);
+            }
+        }
+
         if let Some(mut process) = self.tor_process.take() {
             info!("Shutting down Tor process...");
             let _ = process.kill();
             let _ = process.wait();
         }
 
-        // Clean up ephemeral hidden service if we created one via control protocol
-        if self.onion_address.is_some() {
-            if let Ok(stream) = TcpStream::connect(format!("127.0.0.1:{}", self.control_port)).await {
-                let (_, mut writer) = stream.into_split();
-                let _ = writer.write_all(b"AUTHENTICATE\r\n").await;
-                // DEL_ONION to remove ephemeral services
-                // Note: This is best-effort cleanup
-            }
-        }
-
         Ok(())
     }
 
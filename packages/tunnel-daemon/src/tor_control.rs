@@ -0,0 +1,804 @@
+//! Tor Control Module
+//!
+//! Client for Tor's control protocol (control-spec.txt). One persistent
+//! connection carries every command: replies are matched to commands in
+//! order, and asynchronous `650` events are handed to a channel instead. The
+//! connection matters beyond efficiency: ephemeral onion services created
+//! with `ADD_ONION` only live as long as the control connection that made them.
+//!
+//! Authentication follows `PROTOCOLINFO`: no credentials when the control port
+//! is open, the configured password for HASHEDPASSWORD, otherwise the auth
+//! cookie, preferably through the SAFECOOKIE challenge so the cookie itself
+//! never crosses the connection.
+
+use ring::hmac;
+use std::collections::VecDeque;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Status code of asynchronous event replies
+const EVENT_CODE: u16 = 650;
+
+/// How long to wait for the reply to a command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Events buffered before new ones are dropped
+const EVENT_BUFFER: usize = 256;
+
+/// Environment variable holding the control port password
+pub const TOR_PASSWORD_ENV: &str = "BEAM_TOR_PASSWORD";
+
+/// Length of Tor's authentication cookie
+const COOKIE_LEN: usize = 32;
+
+const SAFECOOKIE_SERVER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const SAFECOOKIE_CLIENT_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+/// Errors from the control connection
+#[derive(Debug, thiserror::Error)]
+pub enum TorControlError {
+    #[error("Tor control connection failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Tor control connection closed")]
+    Closed,
+
+    #[error("No reply from Tor within {0:?}")]
+    Timeout(Duration),
+
+    #[error("Malformed Tor control reply: {0}")]
+    Protocol(String),
+
+    #[error("Tor refused the command ({code}): {message}")]
+    Command { code: u16, message: String },
+
+    #[error("Tor authentication failed: {0}")]
+    Authentication(String),
+}
+
+/// One line of a reply, with the data block of a `+` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyLine {
+    pub text: String,
+    pub data: Option<String>,
+}
+
+/// A complete reply: every line up to the one with a space separator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<ReplyLine>,
+}
+
+impl Reply {
+    /// Text of the final line, e.g. `OK`
+    pub fn message(&self) -> &str {
+        self.lines.last().map(|l| l.text.as_str()).unwrap_or("")
+    }
+
+    /// Value of a `Key=Value` line, or the data of a `Key=` data line
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.lines.iter().find_map(|line| {
+            let value = line.text.strip_prefix(key)?.strip_prefix('=')?;
+            Some(line.data.as_deref().unwrap_or(value))
+        })
+    }
+}
+
+/// An asynchronous event, e.g. `650 CIRC 5 BUILT ...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Event name, e.g. `CIRC` or `HS_DESC`
+    pub keyword: String,
+
+    /// Words after the keyword on the first line
+    pub args: Vec<String>,
+
+    pub reply: Reply,
+}
+
+impl Event {
+    fn from_reply(reply: Reply) -> Self {
+        let mut words = reply.lines.first().map(|l| l.text.split_whitespace()).into_iter().flatten();
+        let keyword = words.next().unwrap_or_default().to_string();
+        let args = words.map(str::to_string).collect();
+        Event { keyword, args, reply }
+    }
+}
+
+/// Incremental parser turning reply lines (without CRLF) into replies
+#[derive(Debug, Default)]
+pub struct ReplyParser {
+    code: Option<u16>,
+    lines: Vec<ReplyLine>,
+
+    /// Data lines of the current `+` line, until the terminating `.`
+    data: Option<Vec<String>>,
+}
+
+impl ReplyParser {
+    /// Feed one line; returns the reply once its final line has been read
+    pub fn push_line(&mut self, line: &str) -> Result<Option<Reply>, TorControlError> {
+        if let Some(data) = self.data.as_mut() {
+            if line == "." {
+                let data = self.data.take().unwrap_or_default().join("\n");
+                if let Some(last) = self.lines.last_mut() {
+                    last.data = Some(data);
+                }
+            } else {
+                // Leading dots are doubled on the wire
+                data.push(line.strip_prefix('.').unwrap_or(line).to_string());
+            }
+            return Ok(None);
+        }
+
+        let malformed = || TorControlError::Protocol(line.to_string());
+        let code: u16 = line.get(..3).and_then(|c| c.parse().ok()).ok_or_else(malformed)?;
+        // Check the separator before slicing: a non-ASCII byte there would
+        // put the text outside a char boundary
+        let separator = match line.as_bytes().get(3) {
+            Some(&separator @ (b'-' | b'+' | b' ')) => separator,
+            _ => return Err(malformed()),
+        };
+        let text = line.get(4..).ok_or_else(malformed)?;
+        if self.code.is_some_and(|current| current != code) {
+            return Err(malformed());
+        }
+        self.code = Some(code);
+        self.lines.push(ReplyLine { text: text.to_string(), data: None });
+
+        match separator {
+            b'-' => Ok(None),
+            b'+' => {
+                self.data = Some(Vec::new());
+                Ok(None)
+            }
+            _ => {
+                self.code = None;
+                Ok(Some(Reply { code, lines: std::mem::take(&mut self.lines) }))
+            }
+        }
+    }
+}
+
+/// Quote a value as a control-protocol QuotedString
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// `KEY=VALUE` pairs of a reply line; quoted values may contain spaces and escapes
+fn parse_pairs(text: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| *c == ' ').is_some() {}
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ' ')).collect();
+        if key.is_empty() {
+            break;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            continue;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value.extend(std::iter::from_fn(|| chars.next_if(|c| *c != ' ')));
+        }
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// What `PROTOCOLINFO` says about authentication
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    /// Accepted methods, e.g. `COOKIE`, `SAFECOOKIE`, `HASHEDPASSWORD` or `NULL`
+    pub methods: Vec<String>,
+
+    /// Location of the authentication cookie
+    pub cookie_file: Option<PathBuf>,
+
+    pub tor_version: Option<String>,
+}
+
+impl ProtocolInfo {
+    fn from_reply(reply: &Reply) -> Self {
+        let mut info = ProtocolInfo::default();
+        for line in &reply.lines {
+            let (keyword, rest) = line.text.split_once(' ').unwrap_or((&line.text, ""));
+            for (key, value) in parse_pairs(rest) {
+                match (keyword, key.as_str()) {
+                    ("AUTH", "METHODS") => info.methods = value.split(',').map(str::to_string).collect(),
+                    ("AUTH", "COOKIEFILE") => info.cookie_file = Some(PathBuf::from(value)),
+                    ("VERSION", "Tor") => info.tor_version = Some(value),
+                    _ => {}
+                }
+            }
+        }
+        info
+    }
+
+    fn supports(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == method)
+    }
+}
+
+/// Credentials for the control port
+#[derive(Clone, Default)]
+pub struct TorCredentials {
+    /// Password for HASHEDPASSWORD authentication
+    password: Option<String>,
+}
+
+impl TorCredentials {
+    /// Password from `file` if given, otherwise from `BEAM_TOR_PASSWORD`
+    pub fn load(password_file: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let password = match password_file {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("Cannot read Tor password file {}: {}", path.display(), e))?;
+                Some(contents.trim_end_matches(['\r', '\n']).to_string())
+            }
+            None => std::env::var(TOR_PASSWORD_ENV).ok(),
+        };
+        Ok(TorCredentials { password: password.filter(|p| !p.is_empty()) })
+    }
+}
+
+type ReplySender = oneshot::Sender<Result<Reply, TorControlError>>;
+
+/// Commands waiting for a reply, in the order they were sent; `None` once the
+/// connection has closed
+type Pending = Arc<Mutex<Option<VecDeque<ReplySender>>>>;
+
+/// A persistent control connection
+pub struct TorControl {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    pending: Pending,
+    reader: JoinHandle<()>,
+}
+
+impl TorControl {
+    /// Connect to a control port; events arrive on the returned receiver
+    /// once subscribed with [`TorControl::set_events`]
+    pub async fn connect(addr: SocketAddr) -> Result<(Self, mpsc::Receiver<Event>), TorControlError> {
+        let stream = TcpStream::connect(addr).await?;
+        let (reader, writer) = stream.into_split();
+
+        let pending: Pending = Arc::new(Mutex::new(Some(VecDeque::new())));
+        let (events_tx, events_rx) = mpsc::channel(EVENT_BUFFER);
+        let reader = tokio::spawn(Self::read_replies(reader, Arc::clone(&pending), events_tx));
+
+        let control = TorControl { writer: tokio::sync::Mutex::new(writer), pending, reader };
+        Ok((control, events_rx))
+    }
+
+    /// Route replies to waiting commands and events to the channel
+    async fn read_replies(reader: OwnedReadHalf, pending: Pending, events: mpsc::Sender<Event>) {
+        let mut reader = BufReader::new(reader);
+        let mut parser = ReplyParser::default();
+        let mut buf = Vec::new();
+
+        let error = loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) => break TorControlError::Closed,
+                Ok(_) => {}
+                Err(e) => break TorControlError::Io(e),
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let reply = match parser.push_line(line.trim_end_matches(['\r', '\n'])) {
+                Ok(Some(reply)) => reply,
+                Ok(None) => continue,
+                Err(e) => break e,
+            };
+
+            if reply.code == EVENT_CODE {
+                let event = Event::from_reply(reply);
+                if let Err(mpsc::error::TrySendError::Full(event)) = events.try_send(event) {
+                    warn!("Dropping Tor {} event, event channel is full", event.keyword);
+                }
+                continue;
+            }
+
+            let waiter = pending.lock().unwrap().as_mut().and_then(|p| p.pop_front());
+            match waiter {
+                Some(waiter) => {
+                    let _ = waiter.send(Ok(reply));
+                }
+                None => debug!("Unsolicited Tor control reply: {} {}", reply.code, reply.message()),
+            }
+        };
+
+        // Fail the command that was waiting, the rest see a closed connection
+        debug!("Tor control connection ended: {}", error);
+        let waiters = pending.lock().unwrap().take().unwrap_or_default();
+        if let Some(first) = waiters.into_iter().next() {
+            let _ = first.send(Err(error));
+        }
+    }
+
+    /// Send a command and wait for its reply; 4xx and 5xx replies are errors
+    pub async fn command(&self, command: &str) -> Result<Reply, TorControlError> {
+        if command.contains(['\r', '\n']) {
+            return Err(TorControlError::Protocol(format!("command contains a line break: {:?}", command)));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let mut in_flight = {
+            // Queue the waiter while holding the writer so replies stay in order
+            let mut writer = self.writer.lock().await;
+            self.pending.lock().unwrap().as_mut().ok_or(TorControlError::Closed)?.push_back(tx);
+            let in_flight = InFlight { control: self, answered: false };
+            writer.write_all(format!("{}\r\n", command).as_bytes()).await?;
+            in_flight
+        };
+
+        let reply = tokio::time::timeout(COMMAND_TIMEOUT, rx)
+            .await
+            .map_err(|_| TorControlError::Timeout(COMMAND_TIMEOUT))?
+            .map_err(|_| TorControlError::Closed);
+        in_flight.answered = true;
+        let reply = reply??;
+
+        if reply.code >= 400 {
+            return Err(TorControlError::Command { code: reply.code, message: reply.message().to_string() });
+        }
+        Ok(reply)
+    }
+
+    /// Whether the connection has ended or was given up on; it then fails
+    /// every command and must be replaced
+    pub fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+
+    /// Stop using the connection: a command gave up on its reply, so later
+    /// replies can no longer be matched to their commands
+    fn poison(&self) {
+        if self.pending.lock().unwrap().take().is_some() {
+            warn!("Closing Tor control connection after a command was abandoned");
+        }
+        self.reader.abort();
+    }
+
+    /// Ask Tor how it wants controllers to authenticate
+    pub async fn protocol_info(&self) -> Result<ProtocolInfo, TorControlError> {
+        let reply = self.command("PROTOCOLINFO 1").await?;
+        Ok(ProtocolInfo::from_reply(&reply))
+    }
+
+    /// Authenticate with the best method Tor offers that we have credentials
+    /// for. Tor closes the connection after a failed attempt, so the method is
+    /// chosen up front and there is no retry.
+    pub async fn authenticate(&self, credentials: &TorCredentials) -> Result<ProtocolInfo, TorControlError> {
+        let info = self.protocol_info().await?;
+        let auth_error = |message: String| TorControlError::Authentication(message);
+
+        let command = if info.supports("NULL") {
+            "AUTHENTICATE".to_string()
+        } else if let (true, Some(password)) = (info.supports("HASHEDPASSWORD"), &credentials.password) {
+            format!("AUTHENTICATE {}", quote(password))
+        } else if info.supports("SAFECOOKIE") || info.supports("COOKIE") {
+            let path = info.cookie_file.as_deref().ok_or_else(|| auth_error("Tor did not say where its auth cookie is".to_string()))?;
+            let cookie = fs::read(path).map_err(|e| {
+                auth_error(format!("cannot read auth cookie {} ({}); add this user to Tor's group or set {}", path.display(), e, TOR_PASSWORD_ENV))
+            })?;
+            if cookie.len() != COOKIE_LEN {
+                return Err(auth_error(format!("auth cookie {} is not {} bytes", path.display(), COOKIE_LEN)));
+            }
+
+            if info.supports("SAFECOOKIE") {
+                format!("AUTHENTICATE {}", self.safecookie_response(&cookie).await?)
+            } else {
+                format!("AUTHENTICATE {}", hex(&cookie))
+            }
+        } else if info.supports("HASHEDPASSWORD") {
+            return Err(auth_error(format!("Tor requires a password; set {} or --tor-password-file", TOR_PASSWORD_ENV)));
+        } else {
+            return Err(auth_error(format!("no supported method among {}", info.methods.join(","))));
+        };
+
+        match self.command(&command).await {
+            Ok(_) => Ok(info),
+            Err(TorControlError::Command { message, .. }) => Err(auth_error(message)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Run the SAFECOOKIE challenge and return the client hash (hex).
+    /// Tor proves it knows the cookie before we prove that we do.
+    async fn safecookie_response(&self, cookie: &[u8]) -> Result<String, TorControlError> {
+        let client_nonce: [u8; 32] = rand::random();
+        let reply = self.command(&format!("AUTHCHALLENGE SAFECOOKIE {}", hex(&client_nonce))).await?;
+
+        let pairs = reply.lines.first().map(|l| parse_pairs(l.text.trim_start_matches("AUTHCHALLENGE"))).unwrap_or_default();
+        let field = |name: &str| pairs.iter().find(|(k, _)| k == name).and_then(|(_, v)| from_hex(v));
+        let (server_hash, server_nonce) = match (field("SERVERHASH"), field("SERVERNONCE")) {
+            (Some(hash), Some(nonce)) => (hash, nonce),
+            _ => return Err(TorControlError::Protocol(reply.message().to_string())),
+        };
+
+        let message = [cookie, &client_nonce, &server_nonce].concat();
+        let server_key = hmac::Key::new(hmac::HMAC_SHA256, SAFECOOKIE_SERVER_KEY);
+        hmac::verify(&server_key, &message, &server_hash)
+            .map_err(|_| TorControlError::Authentication("Tor's SAFECOOKIE hash does not match the auth cookie".to_string()))?;
+
+        let client_key = hmac::Key::new(hmac::HMAC_SHA256, SAFECOOKIE_CLIENT_KEY);
+        Ok(hex(hmac::sign(&client_key, &message).as_ref()))
+    }
+
+    /// Subscribe to asynchronous events (replaces the previous subscription)
+    pub async fn set_events(&self, events: &[&str]) -> Result<(), TorControlError> {
+        self.command(&format!("SETEVENTS {}", events.join(" "))).await.map(|_| ())
+    }
+
+    /// Value of a GETINFO key
+    pub async fn get_info(&self, key: &str) -> Result<String, TorControlError> {
+        let reply = self.command(&format!("GETINFO {}", key)).await?;
+        reply
+            .value(key)
+            .map(str::to_string)
+            .ok_or_else(|| TorControlError::Protocol(format!("GETINFO reply has no {}", key)))
+    }
+
+    /// Set one configuration option
+    pub async fn set_conf(&self, key: &str, value: &str) -> Result<(), TorControlError> {
+        self.command(&format!("SETCONF {}={}", key, quote(value))).await.map(|_| ())
+    }
+
+    /// Write the running configuration to torrc
+    pub async fn save_conf(&self) -> Result<(), TorControlError> {
+        self.command("SAVECONF").await.map(|_| ())
+    }
+
+    /// Create an ephemeral onion service with a new key, returning its service ID.
+    /// `ports` maps onion ports to local targets.
+    pub async fn add_onion(&self, flags: &[&str], ports: &[(u16, SocketAddr)]) -> Result<String, TorControlError> {
+        let mut command = "ADD_ONION NEW:BEST".to_string();
+        if !flags.is_empty() {
+            command.push_str(&format!(" Flags={}", flags.join(",")));
+        }
+        for (virtual_port, target) in ports {
+            command.push_str(&format!(" Port={},{}", virtual_port, target));
+        }
+
+        let reply = self.command(&command).await?;
+        reply
+            .value("ServiceID")
+            .map(str::to_string)
+            .ok_or_else(|| TorControlError::Protocol("ADD_ONION reply has no ServiceID".to_string()))
+    }
+
+    /// Remove an ephemeral onion service
+    pub async fn del_onion(&self, service_id: &str) -> Result<(), TorControlError> {
+        self.command(&format!("DEL_ONION {}", service_id)).await.map(|_| ())
+    }
+
+    /// Build a new circuit, returning its ID
+    pub async fn extend_circuit(&self, purpose: Option<&str>) -> Result<String, TorControlError> {
+        let command = match purpose {
+            Some(purpose) => format!("EXTENDCIRCUIT 0 purpose={}", purpose),
+            None => "EXTENDCIRCUIT 0".to_string(),
+        };
+        let reply = self.command(&command).await?;
+
+        // 250 EXTENDED <circuit id>
+        match reply.message().split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["EXTENDED", id] => Ok(id.to_string()),
+            _ => Err(TorControlError::Protocol(reply.message().to_string())),
+        }
+    }
+}
+
+/// A command whose reply is still owed; poisons the connection if the
+/// command stops waiting (timeout, failed write or dropped future) first
+struct InFlight<'a> {
+    control: &'a TorControl,
+    answered: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.control.poison();
+        }
+    }
+}
+
+impl Drop for TorControl {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn parse(lines: &[&str]) -> Result<Vec<Reply>, TorControlError> {
+        let mut parser = ReplyParser::default();
+        let mut replies = Vec::new();
+        for line in lines {
+            replies.extend(parser.push_line(line)?);
+        }
+        Ok(replies)
+    }
+
+    #[test]
+    fn test_reply_parser() {
+        let replies = parse(&[
+            "250-ServiceID=abcdef",
+            "250-PrivateKey=ED25519-V3:key",
+            "250 OK",
+            "250+config-text=",
+            "SocksPort 0",
+            "..hidden",
+            ".",
+            "250 OK",
+            "650 CIRC 5 BUILT $AAAA~relay PURPOSE=GENERAL",
+        ])
+        .unwrap();
+
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0].value("ServiceID"), Some("abcdef"));
+        assert_eq!(replies[0].message(), "OK");
+        assert_eq!(replies[1].value("config-text"), Some("SocksPort 0\n.hidden"));
+
+        let event = Event::from_reply(replies[2].clone());
+        assert_eq!(event.keyword, "CIRC");
+        assert_eq!(event.args[..2], ["5".to_string(), "BUILT".to_string()]);
+
+        // Codes must match within a reply, and every line needs a separator
+        assert!(matches!(parse(&["250-a", "251 b"]), Err(TorControlError::Protocol(_))));
+        assert!(matches!(parse(&["25"]), Err(TorControlError::Protocol(_))));
+        assert!(matches!(parse(&["250*x"]), Err(TorControlError::Protocol(_))));
+        assert!(matches!(parse(&["250\u{e9}"]), Err(TorControlError::Protocol(_))));
+        assert!(matches!(parse(&["250\u{fffd}OK"]), Err(TorControlError::Protocol(_))));
+        assert_eq!(quote(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+
+    /// Control port that answers each command line with `handler`'s reply,
+    /// hanging up when it returns None
+    async fn mock_control_port(mut handler: impl FnMut(&str) -> Option<String> + Send + 'static) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                match handler(&line) {
+                    Some(reply) => writer.write_all(reply.as_bytes()).await.unwrap(),
+                    None => break,
+                }
+            }
+        });
+        addr
+    }
+
+    /// Handler expecting exactly these commands, in order
+    fn scripted<S: Into<String>>(replies: Vec<(S, S)>) -> impl FnMut(&str) -> Option<String> {
+        let mut replies = replies.into_iter();
+        move |line| {
+            let (expected, reply) = replies.next()?;
+            assert_eq!(line, expected.into());
+            Some(reply.into())
+        }
+    }
+
+    fn protocol_info(methods: &str, cookie_file: &Path) -> String {
+        format!(
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS={} COOKIEFILE={}\r\n250-VERSION Tor=\"0.4.8.9\"\r\n250 OK\r\n",
+            methods,
+            quote(&cookie_file.display().to_string())
+        )
+    }
+
+    /// Control port requiring SAFECOOKIE; `honest` controls whether it proves it knows the cookie
+    fn safecookie_tor(cookie: Vec<u8>, cookie_file: PathBuf, honest: bool) -> impl FnMut(&str) -> Option<String> {
+        let server_nonce = [7u8; 32];
+        let mut expected = None;
+        move |line| {
+            let words: Vec<&str> = line.split(' ').collect();
+            match words.as_slice() {
+                ["PROTOCOLINFO", "1"] => Some(protocol_info("COOKIE,SAFECOOKIE", &cookie_file)),
+                ["AUTHCHALLENGE", "SAFECOOKIE", nonce] => {
+                    let message = [cookie.as_slice(), &from_hex(nonce).unwrap(), &server_nonce].concat();
+                    let server_key = hmac::Key::new(hmac::HMAC_SHA256, if honest { SAFECOOKIE_SERVER_KEY } else { b"guess" });
+                    let client_key = hmac::Key::new(hmac::HMAC_SHA256, SAFECOOKIE_CLIENT_KEY);
+                    expected = Some(hex(hmac::sign(&client_key, &message).as_ref()));
+                    Some(format!(
+                        "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}\r\n",
+                        hex(hmac::sign(&server_key, &message).as_ref()),
+                        hex(&server_nonce)
+                    ))
+                }
+                ["AUTHENTICATE", response] if expected.as_deref() == Some(*response) => Some("250 OK\r\n".to_string()),
+                ["AUTHENTICATE", ..] => Some("515 Authentication failed: Safe cookie response did not match expected value.\r\n".to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    async fn authenticate_against(
+        handler: impl FnMut(&str) -> Option<String> + Send + 'static,
+        credentials: &TorCredentials,
+    ) -> Result<ProtocolInfo, TorControlError> {
+        let addr = mock_control_port(handler).await;
+        let (control, _events) = TorControl::connect(addr).await.unwrap();
+        control.authenticate(credentials).await
+    }
+
+    fn auth_failure(result: Result<ProtocolInfo, TorControlError>) -> String {
+        match result {
+            Err(TorControlError::Authentication(message)) => message,
+            other => panic!("expected an authentication error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_protocol_info_and_password_file() {
+        let reply = parse(&[
+            "250-PROTOCOLINFO 1",
+            r#"250-AUTH METHODS=COOKIE,SAFECOOKIE,HASHEDPASSWORD COOKIEFILE="/run/tor/my \"cookie\"""#,
+            r#"250-VERSION Tor="0.4.8.9""#,
+            "250 OK",
+        ])
+        .unwrap()
+        .remove(0);
+        let info = ProtocolInfo::from_reply(&reply);
+        assert_eq!(info.methods, ["COOKIE", "SAFECOOKIE", "HASHEDPASSWORD"]);
+        assert_eq!(info.cookie_file.unwrap(), Path::new(r#"/run/tor/my "cookie""#));
+        assert_eq!(info.tor_version.as_deref(), Some("0.4.8.9"));
+        assert_eq!(from_hex(&hex(&[0, 0xab, 0xff])).unwrap(), [0, 0xab, 0xff]);
+        assert!(from_hex("abc").is_none());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        fs::write(&path, "s3cret \"pw\"\n").unwrap();
+        assert_eq!(TorCredentials::load(Some(&path)).unwrap().password.as_deref(), Some(r#"s3cret "pw""#));
+        assert!(TorCredentials::load(Some(&dir.path().join("missing"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authentication_methods() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_file = dir.path().join("control_auth_cookie");
+        let cookie: Vec<u8> = (0..COOKIE_LEN as u8).collect();
+        fs::write(&cookie_file, &cookie).unwrap();
+        let anonymous = TorCredentials::default();
+        let password = TorCredentials { password: Some(r#"pa"ss"#.to_string()) };
+
+        // SAFECOOKIE: both sides prove knowledge of the cookie, and a control
+        // port that does not know it never gets our response
+        let info = authenticate_against(safecookie_tor(cookie.clone(), cookie_file.clone(), true), &anonymous).await.unwrap();
+        assert_eq!(info.tor_version.as_deref(), Some("0.4.8.9"));
+        let message = auth_failure(authenticate_against(safecookie_tor(cookie.clone(), cookie_file.clone(), false), &anonymous).await);
+        assert!(message.contains("does not match the auth cookie"), "{}", message);
+
+        // Plain COOKIE sends the cookie itself
+        let script = vec![
+            ("PROTOCOLINFO 1".to_string(), protocol_info("COOKIE", &cookie_file)),
+            (format!("AUTHENTICATE {}", hex(&cookie)), "250 OK\r\n".to_string()),
+        ];
+        authenticate_against(scripted(script), &anonymous).await.unwrap();
+
+        // HASHEDPASSWORD is preferred when a password is configured
+        let script = vec![
+            ("PROTOCOLINFO 1".to_string(), protocol_info("COOKIE,SAFECOOKIE,HASHEDPASSWORD", &cookie_file)),
+            (r#"AUTHENTICATE "pa\"ss""#.to_string(), "250 OK\r\n".to_string()),
+        ];
+        authenticate_against(scripted(script), &password).await.unwrap();
+
+        // Wrong password, no password, unreadable cookie
+        let script = vec![
+            ("PROTOCOLINFO 1".to_string(), protocol_info("HASHEDPASSWORD", &cookie_file)),
+            (r#"AUTHENTICATE "pa\"ss""#.to_string(), "515 Authentication failed: Password did not match\r\n".to_string()),
+        ];
+        let message = auth_failure(authenticate_against(scripted(script), &password).await);
+        assert!(message.contains("Password did not match"), "{}", message);
+
+        let script = vec![("PROTOCOLINFO 1".to_string(), protocol_info("HASHEDPASSWORD", &cookie_file))];
+        let message = auth_failure(authenticate_against(scripted(script), &anonymous).await);
+        assert!(message.contains(TOR_PASSWORD_ENV), "{}", message);
+
+        let script = vec![("PROTOCOLINFO 1".to_string(), protocol_info("COOKIE", &dir.path().join("missing")))];
+        let message = auth_failure(authenticate_against(scripted(script), &anonymous).await);
+        assert!(message.contains("cannot read auth cookie"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_commands_and_events_share_one_connection() {
+        let addr = mock_control_port(scripted(vec![
+            ("PROTOCOLINFO 1", "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250 OK\r\n"),
+            ("AUTHENTICATE", "250 OK\r\n"),
+            ("SETEVENTS HS_DESC", "250 OK\r\n"),
+            (
+                "ADD_ONION NEW:BEST Flags=DiscardPK Port=80,127.0.0.1:8080",
+                // An event may arrive ahead of the reply
+                "650 HS_DESC CREATED abcdef UNKNOWN UNKNOWN desc\r\n250-ServiceID=abcdef\r\n250 OK\r\n",
+            ),
+            ("GETINFO version", "250-version=0.4.8.9\r\n250 OK\r\n"),
+            ("EXTENDCIRCUIT 0", "250 EXTENDED 17\r\n"),
+            ("DEL_ONION missing", "552 Unknown Onion Service id\r\n"),
+        ]))
+        .await;
+
+        let (control, mut events) = TorControl::connect(addr).await.unwrap();
+        control.authenticate(&TorCredentials::default()).await.unwrap();
+        control.set_events(&["HS_DESC"]).await.unwrap();
+
+        let target: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(control.add_onion(&["DiscardPK"], &[(80, target)]).await.unwrap(), "abcdef");
+        let event = events.recv().await.unwrap();
+        assert_eq!((event.keyword.as_str(), event.args[0].as_str()), ("HS_DESC", "CREATED"));
+
+        assert_eq!(control.get_info("version").await.unwrap(), "0.4.8.9");
+        assert_eq!(control.extend_circuit(None).await.unwrap(), "17");
+
+        match control.del_onion("missing").await {
+            Err(TorControlError::Command { code, message }) => assert_eq!((code, message.as_str()), (552, "Unknown Onion Service id")),
+            other => panic!("expected a command error, got {:?}", other),
+        }
+
+        // The mock hangs up after its script
+        assert!(matches!(control.command("GETINFO version").await, Err(TorControlError::Closed)));
+        assert!(control.command("SETEVENTS\r\nSIGNAL HALT").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_command_closes_connection() {
+        // Tor answers the first command only after the controller gave up on it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(_)) = lines.next_line().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let _ = writer.write_all(b"250-version=0.4.8.9\r\n250 OK\r\n").await;
+            }
+        });
+
+        let (control, _events) = TorControl::connect(addr).await.unwrap();
+        let slow = tokio::time::timeout(Duration::from_millis(20), control.get_info("version")).await;
+        assert!(slow.is_err());
+        assert!(control.is_closed());
+
+        // The late reply must not be taken as the answer to the next command
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(matches!(control.get_info("config-file").await, Err(TorControlError::Closed)));
+    }
+}
@@ -1,19 +1,52 @@
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server, StatusCode, header, Client};
+use hyper::{Body, Method, Request, Response, Server, StatusCode, Version, header};
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
+use tokio::sync::OwnedSemaphorePermit;
 use tokio_rustls::TlsAcceptor;
+use rustls::server::ResolvesServerCert;
 use rustls::ServerConfig;
 use tracing::{info, error, debug, warn};
 
-use crate::dns::DualDNSResolver;
+use crate::dns::{self, DualDNSResolver};
 use crate::context::{ContextDetector, AccessContext};
-use crate::cert;
+use crate::sni::SniResolver;
+use crate::mtls::{self, ClientAuth, ClientCert};
+use crate::acme::{self, ChallengeResponses};
+use crate::redirect::HttpsRedirect;
+use crate::cache::{self, ResponseCache, CacheEntry};
+use crate::mode::PerformanceConfig;
+use crate::pool::{self, UpstreamClient};
+use crate::forwarding::{self, ForwardingConfig};
+use crate::routing::{RoutingTable, RouteTarget, Upstream};
+use crate::tcp::TcpTunnel;
+use crate::capture::{CaptureStore, CapturedExchange};
+use crate::inspector;
+use crate::auth::{AuthConfig, AuthDecision};
+use crate::ratelimit::{RateLimiter, Throttle};
+
+/// Header reporting whether a response was served from the response cache
+const CACHE_STATUS_HEADER: &str = "x-beam-cache";
+
+/// ALPN protocols offered by the TLS listener, preferred first
+const ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// Hop-by-hop headers that must not be replayed from a cached response
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
 
 /// Request statistics for monitoring
 #[derive(Default)]
@@ -23,16 +56,89 @@ pub struct RequestStats {
     pub failed_requests: AtomicU64,
     pub total_bytes_in: AtomicU64,
     pub total_bytes_out: AtomicU64,
+    /// Requests forwarded to the local application
+    pub upstream_requests: AtomicU64,
+    /// New upstream connections opened (requests not served by a pooled connection)
+    pub pool_misses: AtomicU64,
+    /// Currently open raw TCP connections
+    pub active_connections: AtomicU64,
+    /// Requests refused by a per-client rate limit (429)
+    pub rate_limited_requests: AtomicU64,
+    /// Requests refused by the concurrency cap (503)
+    pub overloaded_requests: AtomicU64,
 }
 
+impl RequestStats {
+    /// Upstream requests that reused a pooled keep-alive connection
+    pub fn pool_hits(&self) -> u64 {
+        self.upstream_requests.load(Ordering::Relaxed)
+            .saturating_sub(self.pool_misses.load(Ordering::Relaxed))
+    }
+}
+
+/// Protocol spoken on the listen port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelProtocol {
+    /// HTTP reverse proxy with caching, routing and upgrades
+    Http,
+    /// Raw byte-stream forwarding for non-HTTP services
+    Tcp,
+}
+
+/// Details of the client connection a request arrived on
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Peer address of the client
+    pub remote_addr: SocketAddr,
+
+    /// Whether the connection was accepted by the TLS listener
+    pub secure: bool,
+
+    /// Verified TLS client certificate, if the client presented one
+    pub client_cert: Option<Arc<ClientCert>>,
+
+    /// Whether the connection came in through the onion service listener.
+    /// Tor delivers every onion visitor from loopback, so this, not the Host
+    /// header, is what tells them apart from local clients.
+    pub via_onion: bool,
+}
+
+impl ConnectionInfo {
+    /// Scheme the client used to reach the tunnel
+    pub fn scheme(&self) -> &'static str {
+        if self.secure { "https" } else { "http" }
+    }
+}
+
+/// Request extension that sends a cacheable request to the upstream even when
+/// the response cache holds an answer for it
+#[derive(Debug, Clone, Copy)]
+struct BypassCache;
+
 pub struct TunnelDaemon {
     listen_port: u16,
+    bind_addr: IpAddr,
+    protocol: TunnelProtocol,
     target_port: u16,
     domain: String,
     https_port: Option<u16>,
     dns_resolver: Option<DualDNSResolver>,
     context_detector: ContextDetector,
     tls_config: Option<Arc<ServerConfig>>,
+    https_local_ca: bool,
+    require_client_cert: bool,
+    acme_challenges: Option<Arc<ChallengeResponses>>,
+    https_redirect: Option<HttpsRedirect>,
+    onion_listener: Option<std::net::TcpListener>,
+    p2p_listener: Option<std::net::TcpListener>,
+    cache: Option<Arc<ResponseCache>>,
+    client: UpstreamClient,
+    forwarding: ForwardingConfig,
+    routes: RoutingTable,
+    auth: AuthConfig,
+    limits: Arc<RateLimiter>,
+    captures: Option<Arc<CaptureStore>>,
+    inspect_port: Option<u16>,
     stats: Arc<RequestStats>,
     start_time: Instant,
 }
@@ -41,15 +147,34 @@ impl TunnelDaemon {
     pub async fn new(listen_port: u16, target_port: u16, domain: String) -> Result<Self, Box<dyn std::error::Error>> {
         info!("Tunnel daemon initialized: listen on {}, proxy to {}, domain {}", listen_port, target_port, domain);
 
+        let stats = Arc::new(RequestStats::default());
+        let client = pool::build_upstream_client(&PerformanceConfig::default(), Arc::clone(&stats));
+
         Ok(TunnelDaemon {
             listen_port,
+            bind_addr: IpAddr::from([127, 0, 0, 1]),
+            protocol: TunnelProtocol::Http,
             target_port,
             domain,
             https_port: None,
             dns_resolver: None,
             context_detector: ContextDetector::new(),
             tls_config: None,
-            stats: Arc::new(RequestStats::default()),
+            https_local_ca: false,
+            require_client_cert: false,
+            acme_challenges: None,
+            https_redirect: None,
+            onion_listener: None,
+            p2p_listener: None,
+            cache: None,
+            client,
+            forwarding: ForwardingConfig::default(),
+            routes: RoutingTable::new(),
+            auth: AuthConfig::new(),
+            limits: Arc::new(RateLimiter::new()),
+            captures: None,
+            inspect_port: None,
+            stats,
             start_time: Instant::now(),
         })
     }
@@ -65,6 +190,11 @@ impl TunnelDaemon {
         )
     }
 
+    /// Shared handle to the request statistics
+    pub fn stats(&self) -> Arc<RequestStats> {
+        Arc::clone(&self.stats)
+    }
+
     /// Get uptime in seconds
     pub fn uptime_secs(&self) -> u64 {
         self.start_time.elapsed().as_secs()
@@ -74,56 +204,191 @@ impl TunnelDaemon {
         self.dns_resolver = Some(resolver);
     }
 
-    pub async fn setup_https(&mut self, domain: &str, https_port: u16) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Setting up HTTPS for domain: {}", domain);
-        
-        // Generate or load certificate
-        let (cert_chain, key) = cert::get_or_create_cert(domain, None)?;
+    /// Choose between HTTP proxying and raw TCP forwarding
+    pub fn set_protocol(&mut self, protocol: TunnelProtocol) {
+        self.protocol = protocol;
+    }
+
+    /// Address to bind the listen port on (loopback by default)
+    pub fn set_bind_address(&mut self, addr: IpAddr) {
+        self.bind_addr = addr;
+    }
+
+    /// Rebuild the upstream connection pool from the mode's performance settings
+    pub fn set_performance_config(&mut self, config: &PerformanceConfig) {
+        self.client = pool::build_upstream_client(config, Arc::clone(&self.stats));
+    }
+
+    /// Configure forwarding headers and Host preservation
+    pub fn set_forwarding_config(&mut self, config: ForwardingConfig) {
+        self.forwarding = config;
+    }
+
+    /// Route requests to upstreams by host and path prefix
+    pub fn set_routes(&mut self, routes: RoutingTable) {
+        self.routes = routes;
+    }
+
+    /// Serve cacheable responses from the given cache
+    pub fn set_cache(&mut self, cache: Arc<ResponseCache>) {
+        self.cache = Some(cache);
+    }
+
+    /// Require credentials or restrict client addresses
+    pub fn set_auth(&mut self, auth: AuthConfig) {
+        self.auth = auth;
+    }
+
+    /// Throttle clients and cap requests in flight
+    pub fn set_rate_limiter(&mut self, limits: Arc<RateLimiter>) {
+        self.limits = limits;
+    }
+
+    /// Capture traffic into the store and serve the inspector on the given port
+    pub fn set_inspector(&mut self, store: Arc<CaptureStore>, port: u16) {
+        self.captures = Some(store);
+        self.inspect_port = Some(port);
+    }
+
+    /// Answer ACME HTTP-01 validation requests from these responses
+    pub fn set_acme_challenges(&mut self, challenges: Arc<ChallengeResponses>) {
+        self.acme_challenges = Some(challenges);
+    }
+
+    /// Redirect plain HTTP requests to the HTTPS listener and send HSTS
+    pub fn set_https_redirect(&mut self, redirect: HttpsRedirect) {
+        self.https_redirect = Some(redirect);
+    }
+
+    /// Serve the onion service from its own loopback listener
+    pub fn set_onion_listener(&mut self, listener: std::net::TcpListener) {
+        self.onion_listener = Some(listener);
+    }
+
+    /// Listener P2P peers open raw TCP streams on (TCP mode only)
+    pub fn set_p2p_listener(&mut self, listener: std::net::TcpListener) {
+        self.p2p_listener = Some(listener);
+    }
+
+    /// Store holding captured exchanges, if capture is enabled
+    pub fn capture_store(&self) -> Option<&Arc<CaptureStore>> {
+        self.captures.as_ref()
+    }
+
+    pub async fn setup_https(
+        &mut self,
+        mut resolver: SniResolver,
+        client_auth: Option<ClientAuth>,
+        https_port: u16,
+    ) -> Result<Arc<SniResolver>, Box<dyn std::error::Error>> {
+        info!("Setting up HTTPS for domain: {}", self.domain);
+
+        // Pick certificates by SNI so routed host names get their own
+        self.https_local_ca = resolver.uses_local_ca();
+        self.require_client_cert = client_auth.as_ref().is_some_and(ClientAuth::is_required);
+        for host in self.routes.hosts() {
+            resolver.add_domain(host);
+        }
+        let resolver = Arc::new(resolver);
+        SniResolver::start_renewal_task(Arc::clone(&resolver));
         
         // Create TLS config
-        let config = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(cert_chain, key)?;
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_auth {
+            Some(client_auth) => builder.with_client_cert_verifier(client_auth.verifier()),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+
+        // Offer HTTP/2 so clients can multiplex requests over one connection
+        config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
         
         self.tls_config = Some(Arc::new(config));
         self.https_port = Some(https_port);
         
         info!("HTTPS configured for port {}", https_port);
-        Ok(())
+        Ok(resolver)
     }
 
-    pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
         let listen_port = self.listen_port;
         let target_port = self.target_port;
         let domain = self.domain.clone();
         let https_port = self.https_port;
         let tls_config = self.tls_config.clone();
+        let https_local_ca = self.https_local_ca;
+        let https_redirect = self.https_redirect.is_some();
+        let require_client_cert = self.require_client_cert;
+        let routes = self.routes.clone();
+        let bind_addr = self.bind_addr;
+        let inspect_port = self.inspect_port;
+
+        if self.protocol == TunnelProtocol::Tcp {
+            println!();
+            println!("🎉 Beam TCP tunnel active!");
+            println!("   TCP:   {}:{} → localhost:{}", bind_addr, listen_port, target_port);
+            if let Some(listener) = &self.p2p_listener {
+                println!("   P2P:   {} → localhost:{}", listener.local_addr()?, target_port);
+            }
+            println!();
+
+            let listen_addr = SocketAddr::new(bind_addr, listen_port);
+            let mut tunnel = TcpTunnel::new(listen_addr, target_port, Arc::clone(&self.stats));
+            if let Some(listener) = self.p2p_listener.take() {
+                tunnel.add_listener(listener);
+            }
+            return tunnel.run().await;
+        }
 
         info!("Tunnel daemon running: listening on {}, proxying to {}, domain {}", listen_port, target_port, domain);
 
         // Create the service that will handle requests
+        let onion_listener = self.onion_listener.take();
         let daemon = Arc::new(self);
-        let daemon_for_http = Arc::clone(&daemon);
-        let make_svc = make_service_fn(move |socket: &AddrStream| {
-            let remote_addr = socket.remote_addr();
-            let daemon_clone = Arc::clone(&daemon_for_http);
-            async move {
-                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                    let daemon = Arc::clone(&daemon_clone);
-                    async move { daemon.handle_request(req, remote_addr).await }
-                }))
-            }
-        });
+
+        if let Some(port) = inspect_port {
+            let daemon_inspector = Arc::clone(&daemon);
+            tokio::spawn(async move {
+                if let Err(e) = inspector::serve(port, daemon_inspector).await {
+                    error!("Request inspector failed: {}", e);
+                }
+            });
+        }
+
+        let plain_service = |daemon: Arc<TunnelDaemon>, via_onion: bool| {
+            make_service_fn(move |socket: &AddrStream| {
+                let remote_addr = socket.remote_addr();
+                let daemon_clone = Arc::clone(&daemon);
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let daemon = Arc::clone(&daemon_clone);
+                        let conn = ConnectionInfo { remote_addr, secure: false, client_cert: None, via_onion };
+                        async move { daemon.handle_request(req, conn).await }
+                    }))
+                }
+            })
+        };
 
         // Start HTTP server
-        let http_addr = ([127, 0, 0, 1], listen_port).into();
-        let http_server = Server::bind(&http_addr).serve(make_svc);
+        let http_addr = SocketAddr::new(bind_addr, listen_port);
+        let http_server = Server::bind(&http_addr).serve(plain_service(Arc::clone(&daemon), false));
         info!("HTTP server listening on http://{}", http_addr);
 
+        // Onion visitors get their own listener so they are never mistaken for local clients
+        if let Some(listener) = onion_listener {
+            let onion_addr = listener.local_addr()?;
+            let onion_server = Server::from_tcp(listener)?.serve(plain_service(Arc::clone(&daemon), true));
+            info!("Onion service listener on http://{}", onion_addr);
+            tokio::spawn(async move {
+                if let Err(e) = onion_server.await {
+                    error!("Onion service listener failed: {}", e);
+                }
+            });
+        }
+
         // Start HTTPS server if configured
         let https_task = if let (Some(port), Some(config)) = (https_port, tls_config) {
-            let https_addr: std::net::SocketAddr = ([127, 0, 0, 1], port).into();
+            let https_addr = SocketAddr::new(bind_addr, port);
             let listener = TcpListener::bind(&https_addr).await?;
             let acceptor = TlsAcceptor::from(config);
             
@@ -139,12 +404,17 @@ impl TunnelDaemon {
                             tokio::spawn(async move {
                                 match acceptor.accept(stream).await {
                                     Ok(tls_stream) => {
-                                        let remote_addr = tls_stream.get_ref().0.peer_addr().unwrap_or_else(|_| ([127, 0, 0, 1], 0).into());
+                                        let remote_addr = tls_stream.get_ref().0.peer_addr().unwrap_or_else(|_| SocketAddr::new(bind_addr, 0));
+                                        let client_cert = ClientCert::from_peer_certificates(tls_stream.get_ref().1.peer_certificates()).map(Arc::new);
+                                        let h2 = tls_stream.get_ref().1.alpn_protocol() == Some(b"h2");
                                         if let Ok(conn) = hyper::server::conn::Http::new()
+                                            .http2_only(h2)
                                             .serve_connection(tls_stream, service_fn(move |req: Request<Body>| {
                                                 let daemon = Arc::clone(&daemon);
-                                                async move { daemon.handle_request(req, remote_addr).await }
+                                                let conn = ConnectionInfo { remote_addr, secure: true, client_cert: client_cert.clone(), via_onion: false };
+                                                async move { daemon.handle_request(req, conn).await }
                                             }))
+                                            .with_upgrades()
                                             .await
                                         {
                                             let _ = conn;
@@ -170,10 +440,26 @@ impl TunnelDaemon {
         println!();
         println!("🎉 Beam tunnel active!");
         println!("   Domain: {}", domain);
-        println!("   HTTP:  http://127.0.0.1:{} → localhost:{}", listen_port, target_port);
+        if https_redirect {
+            println!("   HTTP:  http://{} → redirects to HTTPS", SocketAddr::new(bind_addr, listen_port));
+        } else if require_client_cert {
+            println!("   HTTP:  http://{} → refused, client certificate required", SocketAddr::new(bind_addr, listen_port));
+        } else {
+            println!("   HTTP:  http://{} → localhost:{}", SocketAddr::new(bind_addr, listen_port), target_port);
+        }
+        if !routes.is_empty() {
+            println!("   Routes: {} configured (see log for details)", routes.len());
+        }
         if let Some(port) = https_port {
-            println!("   HTTPS: https://127.0.0.1:{} → localhost:{} (self-signed certificate)", port, target_port);
-            println!("   ⚠️  Browser will show security warning - this is normal for local development");
+            if https_local_ca {
+                println!("   HTTPS: https://{} → localhost:{} (trust it with: beam-tunnel-daemon ca install)", SocketAddr::new(bind_addr, port), target_port);
+                println!("   ⚠️  Browser will show security warning - this is normal for local development");
+            } else {
+                println!("   HTTPS: https://{} → localhost:{}", SocketAddr::new(bind_addr, port), target_port);
+            }
+        }
+        if let Some(port) = inspect_port {
+            println!("   Inspector: http://localhost:{}", port);
         }
         println!("   Status: Ready for local development");
         println!();
@@ -199,14 +485,26 @@ impl TunnelDaemon {
 
     async fn handle_request(
         &self,
-        req: Request<Body>,
-        remote_addr: SocketAddr,
+        mut req: Request<Body>,
+        conn: ConnectionInfo,
     ) -> Result<Response<Body>, Infallible> {
+        let remote_addr = conn.remote_addr;
         let request_start = Instant::now();
         self.stats.total_requests.fetch_add(1, Ordering::Relaxed);
 
+        // HTTP/2 carries the host in :authority; give the rest of the pipeline a Host header
+        if !req.headers().contains_key(header::HOST) {
+            if let Some(authority) = req.uri().authority().and_then(|a| header::HeaderValue::from_str(a.as_str()).ok()) {
+                req.headers_mut().insert(header::HOST, authority);
+            }
+        }
+
         let method = req.method().clone();
         let uri = req.uri().clone();
+        let host = req.headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .map(|s| s.to_string());
         let user_agent = req.headers()
             .get(header::USER_AGENT)
             .and_then(|h| h.to_str().ok())
@@ -226,28 +524,45 @@ impl TunnelDaemon {
         // Track incoming bytes
         self.stats.total_bytes_in.fetch_add(content_length, Ordering::Relaxed);
 
-        let context = self.context_detector.detect_context(
-            user_agent.as_deref(),
-            remote_addr.ip(),
-            referer.as_deref()
-        );
+        // Onion visitors are external whatever their headers claim
+        let context = if conn.via_onion {
+            AccessContext::ExternalAccess
+        } else {
+            self.context_detector.detect_context(user_agent.as_deref(), remote_addr.ip(), referer.as_deref())
+        };
 
-        // Detailed request logging
-        debug!("→ {} {} from {} (context: {:?}, UA: {:?})",
-              method, uri, remote_addr, context,
-              user_agent.as_ref().map(|s| if s.len() > 50 { &s[..50] } else { s }));
+        // Capture the request as the client sent it, before any rewriting
+        let capture = self.captures.as_ref().map(|store| {
+            store.begin(&req, &conn, context)
+        });
+        if let Some(ref capture) = capture {
+            req = capture.tee_request(req);
+        }
 
-        // Route based on context
-        let response = match context {
-            AccessContext::LocalBrowser => {
-                self.proxy_to_local_app(req).await
-            }
-            AccessContext::WebhookService => {
-                debug!("Webhook detected from {}", remote_addr);
-                self.handle_webhook_request(req).await
-            }
-            AccessContext::APIClient | AccessContext::ExternalAccess => {
-                self.handle_external_request(req, context).await
+        // Access control runs before anything is routed or forwarded; ACME
+        // validation requests come from the CA and must never be refused
+        let mut response = if let Some(key_authorization) = self.acme_key_authorization(&req) {
+            info!("Answering ACME HTTP-01 challenge from {}", remote_addr);
+            Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(Body::from(key_authorization))
+                .unwrap())
+        } else if let Some(redirect) = self.https_redirect.as_ref().filter(|_| !conn.secure).and_then(|r| r.redirect(&req)) {
+            Ok(redirect)
+        } else if self.require_client_cert && !conn.secure {
+            // Only the TLS handshake can check the certificate
+            warn!("Refusing plain HTTP request from {}, client certificate required", remote_addr);
+            Ok(client_cert_required())
+        } else {
+            match self.check_access(&mut req, context, &conn) {
+                Err(decision) => Ok(self.auth.reject(&decision)),
+                Ok(identity) => {
+                    let client = rate_limit_key(identity, &conn);
+                    match self.admit(context, &client) {
+                        Ok(_permit) => self.dispatch(req, context, &conn, user_agent.as_deref()).await,
+                        Err(throttle) => Ok(throttle.response()),
+                    }
+                }
             }
         };
 
@@ -280,9 +595,181 @@ impl TunnelDaemon {
             }
         }
 
+        if let (true, Some(redirect), Ok(res)) = (conn.secure, &self.https_redirect, &mut response) {
+            redirect.apply_hsts(host.as_deref(), res);
+        }
+
+        if let Some(capture) = capture {
+            response = response.map(|res| capture.finish(res, elapsed));
+        }
+
         response
     }
 
+    /// Key authorization for a pending ACME HTTP-01 challenge on this path
+    fn acme_key_authorization(&self, req: &Request<Body>) -> Option<String> {
+        if req.method() != Method::GET {
+            return None;
+        }
+        let token = req.uri().path().strip_prefix(acme::HTTP_CHALLENGE_PREFIX)?;
+        self.acme_challenges.as_ref()?.http_response(token)
+    }
+
+    /// Apply access control, returning the client's identity if it authenticated
+    /// or the refusal decision otherwise
+    fn check_access(
+        &self,
+        req: &mut Request<Body>,
+        context: AccessContext,
+        conn: &ConnectionInfo,
+    ) -> Result<Option<String>, AuthDecision> {
+        let client_ip = conn.remote_addr.ip();
+        let client_cert = conn.client_cert.as_deref();
+        match self.auth.check(context, client_ip, req.headers(), client_cert) {
+            AuthDecision::Allow => Ok(None),
+            AuthDecision::Authenticated(identity) => {
+                // Header credentials were for the tunnel, not the application;
+                // with a client certificate, Authorization belongs to the app
+                if client_cert.is_none() {
+                    req.headers_mut().remove(header::AUTHORIZATION);
+                }
+                Ok(Some(identity))
+            }
+            decision => {
+                warn!("Access denied for {} {} from {} ({:?})", req.method(), req.uri(), client_ip, decision);
+                Err(decision)
+            }
+        }
+    }
+
+    /// Apply rate limits and the concurrency cap; the permit is held while the request runs
+    fn admit(&self, context: AccessContext, client: &str) -> Result<Option<OwnedSemaphorePermit>, Throttle> {
+        let result = self.limits.check_rate(context, client).and_then(|_| self.limits.try_acquire());
+
+        match result {
+            Err(Throttle::RateLimited(_)) => {
+                self.stats.rate_limited_requests.fetch_add(1, Ordering::Relaxed);
+                warn!("Rate limited {} ({:?})", client, context);
+            }
+            Err(Throttle::Overloaded) => {
+                self.stats.overloaded_requests.fetch_add(1, Ordering::Relaxed);
+                warn!("Concurrency limit reached, refusing request from {}", client);
+            }
+            Ok(_) => {}
+        }
+
+        result
+    }
+
+    /// Route an admitted request based on its context
+    async fn dispatch(
+        &self,
+        mut req: Request<Body>,
+        context: AccessContext,
+        conn: &ConnectionInfo,
+        user_agent: Option<&str>,
+    ) -> Result<Response<Body>, Infallible> {
+        self.prepare_upstream_request(&mut req, context, conn);
+
+        // Detailed request logging
+        debug!("→ {} {} from {} (context: {:?}, UA: {:?})",
+              req.method(), req.uri(), conn.remote_addr, context,
+              user_agent.map(|s| if s.len() > 50 { &s[..50] } else { s }));
+
+        match context {
+            AccessContext::LocalBrowser => {
+                self.proxy_to_local_app(req).await
+            }
+            AccessContext::WebhookService => {
+                debug!("Webhook detected from {}", conn.remote_addr);
+                self.handle_webhook_request(req).await
+            }
+            AccessContext::APIClient | AccessContext::ExternalAccess => {
+                self.handle_external_request(req, context).await
+            }
+        }
+    }
+
+    /// Add forwarding headers and select the upstream for a request
+    fn prepare_upstream_request(&self, req: &mut Request<Body>, context: AccessContext, conn: &ConnectionInfo) {
+        // Tell the local application who the real client is
+        let original_host = req.headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .map(|s| s.to_string())
+            .or_else(|| req.uri().authority().map(|a| a.to_string()));
+        let mut header_set = self.forwarding.headers_for(context);
+
+        // Keep the tenant subdomain visible to the app when Host is rewritten
+        let is_subdomain = original_host.as_deref().and_then(|h| dns::subdomain_of(h, &self.domain)).is_some();
+        if is_subdomain && !self.forwarding.preserve_host {
+            header_set.x_forwarded_host = true;
+        }
+
+        forwarding::apply_forwarding_headers(
+            req.headers_mut(),
+            header_set,
+            conn.remote_addr.ip(),
+            conn.scheme(),
+            original_host.as_deref(),
+        );
+        mtls::apply_client_cert_headers(req.headers_mut(), conn.client_cert.as_deref());
+
+        // Select the upstream before any context-specific handling
+        if let Some(target) = self.routes.resolve(req) {
+            debug!("Routing {} to {}", req.uri(), target.upstream);
+            req.extensions_mut().insert(target);
+        }
+    }
+
+    /// Re-send a captured request to the upstream and capture the new exchange
+    pub async fn replay(
+        &self,
+        original: &CapturedExchange,
+        mut req: Request<Body>,
+        body: Vec<u8>,
+    ) -> Result<CapturedExchange, Box<dyn std::error::Error>> {
+        let store = self.captures.as_ref().ok_or("Request capture is disabled")?;
+        if is_upgrade_request(&req) {
+            return Err("Protocol upgrade requests cannot be replayed".into());
+        }
+
+        // Replay as the original client so context, forwarding and routing match
+        let conn = ConnectionInfo {
+            remote_addr: original.client_addr.parse().unwrap_or_else(|_| ([127, 0, 0, 1], 0).into()),
+            secure: original.scheme == "https",
+            client_cert: None,
+            via_onion: false,
+        };
+        let header_str = |name: header::HeaderName| req.headers().get(name).and_then(|h| h.to_str().ok());
+        let context = self.context_detector.detect_context(
+            header_str(header::USER_AGENT),
+            conn.remote_addr.ip(),
+            header_str(header::REFERER),
+        );
+
+        let capture = store.begin(&req, &conn, context);
+        capture.set_replay_of(original.id);
+        capture.set_request_body(&body);
+        let id = capture.id();
+
+        *req.body_mut() = Body::from(body);
+        self.prepare_upstream_request(&mut req, context, &conn);
+
+        // A replay must observe what the upstream answers now
+        req.extensions_mut().insert(BypassCache);
+
+        info!("Replaying captured request #{} as #{}", original.id, id);
+        let started = Instant::now();
+        let response = self.perform_local_proxy(req).await?;
+        let response = capture.finish(response, started.elapsed());
+
+        // Draining the body completes the capture
+        hyper::body::to_bytes(response.into_body()).await?;
+
+        store.get(id).ok_or_else(|| "Replayed request was evicted from the capture store".into())
+    }
+
     async fn proxy_to_local_app(&self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
         // This is a simplified proxy implementation
         // In production, you'd use a proper HTTP client
@@ -316,37 +803,249 @@ impl TunnelDaemon {
     }
 
     async fn perform_local_proxy(&self, mut req: Request<Body>) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-        // Create HTTP client
-        let client = hyper::Client::new();
+        // Claim the client side of a protocol upgrade (WebSocket, HMR) before forwarding
+        let client_upgrade = if is_upgrade_request(&req) {
+            Some(hyper::upgrade::on(&mut req))
+        } else {
+            None
+        };
 
-        // Modify the request URI to point to the target application
-        let target_uri = format!("http://127.0.0.1:{}{}", self.target_port, req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or(""));
+        // Pick the upstream chosen by the routing table, or the default target
+        let target = req.extensions_mut()
+            .remove::<RouteTarget>()
+            .unwrap_or_else(|| RouteTarget {
+                upstream: Upstream::local(self.target_port),
+                path_and_query: req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/").to_string(),
+            });
+        let target_uri: hyper::Uri = target.upstream.uri_for(&target.path_and_query).parse()?;
 
-        // Update the request URI
-        *req.uri_mut() = target_uri.parse()?;
+        // Only plain GET requests are eligible for the response cache; tenants get their own entries
+        let tenant = req.headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| dns::subdomain_of(h, &self.domain))
+            .map(|s| format!("{}@", s.to_lowercase()))
+            .unwrap_or_default();
+        let cache_key = match &self.cache {
+            Some(_) if req.method() == hyper::Method::GET && client_upgrade.is_none() => Some(ResponseCache::cache_key(
+                req.method().as_str(),
+                &format!("{}{}{}", tenant, target.upstream.authority(), target_uri.path()),
+                target_uri.query(),
+            )),
+            _ => None,
+        };
 
-        // Update Host header
-        req.headers_mut().insert(
-            header::HOST,
-            header::HeaderValue::from_str(&format!("127.0.0.1:{}", self.target_port))?,
-        );
+        // The request headers as sent decide what a response may be stored under
+        let request_headers = cache_key.as_ref().map(|_| req.headers().clone());
+
+        if let (Some(cache), Some(key)) = (&self.cache, &cache_key) {
+            // Honor client-side cache bypass (e.g. a hard reload in the browser)
+            let bypass = req.extensions().get::<BypassCache>().is_some()
+                || req.headers()
+                    .get(header::CACHE_CONTROL)
+                    .and_then(|h| h.to_str().ok())
+                    .map(|v| cache::parse_cache_control(v) == Some(Duration::ZERO))
+                    .unwrap_or(false);
+
+            if !bypass {
+                if let Some(entry) = cache.get(&cache.variant_key(key, req.headers()).await).await {
+                    return Ok(cached_response(entry));
+                }
+            }
+        }
+
+        let path = target_uri.path().to_string();
+
+        // Update the request URI to point to the target application; upstream
+        // connections are HTTP/1.1 whatever the client negotiated
+        *req.uri_mut() = target_uri;
+        *req.version_mut() = Version::HTTP_11;
+
+        // Update Host header unless the application wants the original one
+        if !self.forwarding.preserve_host || !req.headers().contains_key(header::HOST) {
+            req.headers_mut().insert(
+                header::HOST,
+                header::HeaderValue::from_str(target.upstream.authority())?,
+            );
+        }
 
         // Forward the request to the local application
-        match client.request(req).await {
-            Ok(response) => Ok(response),
+        self.stats.upstream_requests.fetch_add(1, Ordering::Relaxed);
+        let response = match self.client.request(req).await {
+            Ok(response) => response,
             Err(e) => {
                 error!("Failed to proxy request to local application: {}", e);
                 // Return a 502 Bad Gateway error
-                Ok(Response::builder()
+                return Ok(Response::builder()
                     .status(StatusCode::BAD_GATEWAY)
                     .header("content-type", "application/json")
                     .body(Body::from(r#"{"error": "Failed to connect to local application"}"#))
-                    .unwrap())
+                    .unwrap());
             }
+        };
+
+        if let Some(client_upgrade) = client_upgrade {
+            return Ok(self.splice_upgrade(client_upgrade, response));
         }
+
+        match (&self.cache, cache_key, request_headers) {
+            (Some(cache), Some(key), Some(request_headers)) => {
+                self.store_in_cache(cache, &key, &request_headers, &path, response).await
+            }
+            _ => Ok(response),
+        }
+    }
+
+    /// Splice an upgraded client connection to the upgraded upstream connection
+    fn splice_upgrade(&self, client_upgrade: hyper::upgrade::OnUpgrade, mut response: Response<Body>) -> Response<Body> {
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            debug!("Upstream declined protocol upgrade ({})", response.status());
+            return response;
+        }
+
+        let upstream_upgrade = hyper::upgrade::on(&mut response);
+        let stats = Arc::clone(&self.stats);
+
+        tokio::spawn(async move {
+            match tokio::try_join!(client_upgrade, upstream_upgrade) {
+                Ok((mut client, mut upstream)) => {
+                    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                        Ok((bytes_in, bytes_out)) => {
+                            stats.total_bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+                            stats.total_bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+                            debug!("Upgraded connection closed ({} in, {} out)", format_bytes(bytes_in), format_bytes(bytes_out));
+                        }
+                        Err(e) => debug!("Upgraded connection ended: {}", e),
+                    }
+                }
+                Err(e) => warn!("Protocol upgrade failed: {}", e),
+            }
+        });
+
+        // Hand the 101 back to the client; the upgraded body is driven by the task above
+        let (parts, _) = response.into_parts();
+        Response::from_parts(parts, Body::empty())
+    }
+
+    /// Buffer a cacheable upstream response into the cache and tag it as a miss
+    async fn store_in_cache(
+        &self,
+        cache: &ResponseCache,
+        key: &str,
+        request_headers: &header::HeaderMap,
+        path: &str,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+        let content_type = response.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .map(|s| s.to_string());
+
+        let ttl = response.headers()
+            .get(header::CACHE_CONTROL)
+            .and_then(|h| h.to_str().ok())
+            .and_then(cache::parse_cache_control);
+
+        // Any of these anywhere in Cache-Control keeps a response out of this
+        // shared cache, which onion visitors read from too
+        let uncacheable = response.headers()
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .any(|v| ["no-store", "no-cache", "private"].iter().any(|d| cache::has_directive(v, d)));
+
+        let cacheable = response.status() == StatusCode::OK
+            && !response.headers().contains_key(header::SET_COOKIE)
+            && !uncacheable
+            && ttl != Some(Duration::ZERO)
+            && cache.should_cache(path, content_type.as_deref());
+        let key = if cacheable {
+            cache.store_key(key, request_headers, response.headers()).await
+        } else {
+            None
+        };
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers.insert(CACHE_STATUS_HEADER, header::HeaderValue::from_static("MISS"));
+
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(Response::from_parts(parts, body)),
+        };
+
+        let body = hyper::body::to_bytes(body).await?;
+
+        let headers = parts.headers
+            .iter()
+            .filter(|(name, _)| {
+                let name = name.as_str();
+                name != CACHE_STATUS_HEADER
+                    && name != header::CONTENT_LENGTH.as_str()
+                    && !HOP_BY_HOP_HEADERS.contains(&name)
+            })
+            .filter_map(|(name, value)| {
+                value.to_str().ok().map(|v| (name.as_str().to_string(), v.to_string()))
+            })
+            .collect();
+
+        cache.put(
+            key,
+            body.to_vec(),
+            content_type.unwrap_or_default(),
+            parts.status.as_u16(),
+            headers,
+            ttl,
+        ).await;
+
+        Ok(Response::from_parts(parts, Body::from(body)))
     }
 }
 
+/// Check whether a request asks to switch protocols (e.g. WebSocket)
+fn is_upgrade_request(req: &Request<Body>) -> bool {
+    req.headers().contains_key(header::UPGRADE)
+        && req.headers()
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Build a response from a cache entry
+fn cached_response(entry: CacheEntry) -> Response<Body> {
+    let mut builder = Response::builder()
+        .status(entry.status_code);
+
+    for (name, value) in &entry.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+
+    if !entry.content_type.is_empty() && !entry.headers.iter().any(|(name, _)| name == "content-type") {
+        builder = builder.header(header::CONTENT_TYPE, entry.content_type.as_str());
+    }
+
+    builder
+        .header(header::CONTENT_LENGTH, entry.body.len())
+        .header(CACHE_STATUS_HEADER, "HIT")
+        .body(Body::from(entry.body))
+        .unwrap_or_else(|_| {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Internal Server Error"))
+                .unwrap()
+        })
+}
+
+/// Refusal for plain HTTP requests when every client needs a certificate
+fn client_cert_required() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{"error": "Client certificate required, connect over HTTPS"}"#))
+        .unwrap()
+}
+
 /// Format bytes in human-readable form
 fn format_bytes(bytes: u64) -> String {
     const KB: u64 = 1024;
@@ -366,3 +1065,157 @@ fn format_bytes(bytes: u64) -> String {
 
 
 
+
+/// Who a request counts against for rate limiting: authenticated clients by
+/// identity, anonymous ones by address. Tor delivers every onion visitor from
+/// loopback, so they share a key of their own instead of the local clients'.
+fn rate_limit_key(identity: Option<String>, conn: &ConnectionInfo) -> String {
+    match identity {
+        Some(identity) => identity,
+        None if conn.via_onion => "onion".to_string(),
+        None => conn.remote_addr.ip().to_canonical().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 Chrome/120.0 Safari/537.36";
+
+    fn browser_request(host: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .header(header::HOST, host)
+            .header(header::USER_AGENT, CHROME)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn loopback(via_onion: bool) -> ConnectionInfo {
+        ConnectionInfo { remote_addr: ([127, 0, 0, 1], 40000).into(), secure: false, client_cert: None, via_onion }
+    }
+
+    #[tokio::test]
+    async fn test_onion_visitors_are_never_local() {
+        // Nothing listens on port 1, so admitted requests end in a 502
+        let mut daemon = TunnelDaemon::new(0, 1, "myapp.local".to_string()).await.unwrap();
+        let mut auth = AuthConfig::new();
+        auth.add_token("secret").unwrap();
+        daemon.set_auth(auth);
+
+        // Tor delivers onion visitors from loopback; a spoofed local Host and
+        // browser User-Agent must not earn the local-browser bypass
+        let res = daemon.handle_request(browser_request("localhost"), loopback(true)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // The Host header carries no trust either way
+        let res = daemon.handle_request(browser_request("abcdef.onion"), loopback(false)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_onion_visitors_have_their_own_rate_limit() {
+        let mut daemon = TunnelDaemon::new(0, 1, "myapp.local".to_string()).await.unwrap();
+        let mut limits = RateLimiter::new();
+        limits.set_default_limit(Some("1/m".parse().unwrap()));
+        daemon.set_rate_limiter(Arc::new(limits));
+
+        let res = daemon.handle_request(browser_request("myapp.local"), loopback(true)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        let res = daemon.handle_request(browser_request("myapp.local"), loopback(true)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Onion visitors arrive from loopback too, but local clients keep their own bucket
+        let res = daemon.handle_request(browser_request("myapp.local"), loopback(false)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_required_client_cert_refuses_plain_http() {
+        let mut daemon = TunnelDaemon::new(0, 1, "myapp.local".to_string()).await.unwrap();
+        daemon.require_client_cert = true;
+
+        for via_onion in [false, true] {
+            let res = daemon.handle_request(browser_request("myapp.local"), loopback(via_onion)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+
+        // Plain HTTP still leads clients to the HTTPS listener when redirecting
+        daemon.set_https_redirect(HttpsRedirect::new(8443, 0, false));
+        let res = daemon.handle_request(browser_request("myapp.local"), loopback(false)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+    }
+
+    #[tokio::test]
+    async fn test_cache_control_directives_anywhere_prevent_storing() {
+        let daemon = TunnelDaemon::new(0, 1, "myapp.local".to_string()).await.unwrap();
+        let cache = ResponseCache::new(10, 60, true);
+
+        for (path, cache_control, stored) in [
+            ("/a.css", "max-age=60, no-store", false),
+            ("/b.css", "max-age=60, no-cache", false),
+            ("/c.css", "public, max-age=60, private", false),
+            ("/d.css", "max-age=60, private=\"Set-Cookie\"", false),
+            ("/e.css", "public, max-age=60", true),
+        ] {
+            let key = ResponseCache::cache_key("GET", path, None);
+            let response = Response::builder()
+                .header(header::CONTENT_TYPE, "text/css")
+                .header(header::CACHE_CONTROL, cache_control)
+                .body(Body::from("body {}"))
+                .unwrap();
+            daemon.store_in_cache(&cache, &key, &header::HeaderMap::new(), path, response).await.unwrap();
+            assert_eq!(cache.get(&key).await.is_some(), stored, "{}", cache_control);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_skips_the_response_cache() {
+        let hits = Arc::new(AtomicU64::new(0));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_port = listener.local_addr().unwrap().port();
+        let upstream_hits = Arc::clone(&hits);
+        let upstream = Server::from_tcp(listener).unwrap().serve(make_service_fn(move |_| {
+            let hits = Arc::clone(&upstream_hits);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                    let hit = hits.fetch_add(1, Ordering::Relaxed) + 1;
+                    async move {
+                        Ok::<_, Infallible>(Response::builder()
+                            .header(header::CONTENT_TYPE, "text/css")
+                            .header(header::CACHE_CONTROL, "max-age=60")
+                            .body(Body::from(format!("/* {} */", hit)))
+                            .unwrap())
+                    }
+                }))
+            }
+        }));
+        tokio::spawn(upstream);
+
+        let mut daemon = TunnelDaemon::new(0, upstream_port, "myapp.local".to_string()).await.unwrap();
+        daemon.set_cache(Arc::new(ResponseCache::new(10, 60, true)));
+        let store = Arc::new(CaptureStore::new(10, 1024));
+        daemon.set_inspector(Arc::clone(&store), 0);
+
+        let request = || {
+            Request::builder()
+                .uri("/app.css")
+                .header(header::HOST, "myapp.local")
+                .header(header::USER_AGENT, CHROME)
+                .body(Body::empty())
+                .unwrap()
+        };
+        for _ in 0..2 {
+            let res = daemon.handle_request(request(), loopback(false)).await.unwrap();
+            hyper::body::to_bytes(res.into_body()).await.unwrap();
+        }
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+
+        let original = store.list().into_iter().find(|e| e.status == 200).unwrap();
+        let (req, body) = crate::replay::build_request(&original, &Default::default()).unwrap();
+        let replayed = daemon.replay(&original, req, body).await.unwrap();
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+        assert_eq!(replayed.response_body.bytes().unwrap(), b"/* 2 */");
+    }
+}